@@ -0,0 +1,149 @@
+//! Minimal bridge-side program showing how to verify a Bitcoin transaction through the relayer.
+//!
+//! Anchor serializes the `Result<bool>` of `relayer::verify_tx` with `set_return_data`, so after
+//! the CPI the caller reads it back with `get_return_data`, checking that the data was set by
+//! the relayer and not by a program it invoked in turn. `check_tx_unsigned` goes through
+//! `verify_tx_cpi` instead, which needs neither a user signer nor any account beyond the PDAs.
+//!
+//! In the other direction, `record_callback` is the target of `relayer::verify_and_invoke`: the
+//! relay calls it after a successful verification, signed by the `callback` PDA of the relay
+//! state it verified against.
+//!
+//! Anyone can initialize a relay instance under a new epoch from a made-up chain, so every
+//! instruction pins the relay state of `TRUSTED_EPOCH` instead of taking any `RelayState`.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::get_return_data;
+use relayer::cpi::accounts::{VerifyTx, VerifyTxCpi};
+use relayer::program::BtcRelay;
+use relayer::state::RelayState;
+
+declare_id!("8mbziKMrcY1K4g5gPa69W8hN7kySi7VUMiK3qaVUDvFq");
+
+/// Epoch of the relay instance this program trusts.
+pub const TRUSTED_EPOCH: u64 = 0;
+
+#[program]
+pub mod relay_consumer {
+    use super::*;
+
+    /// Verifies `txid` at `index` of the main-chain block at `height` by calling
+    /// `relayer::verify_tx` and returns the result read from the relayer's return data.
+    ///
+    /// # Errors
+    ///
+    /// - Any error of `verify_tx`, which aborts the CPI
+    /// - `MissingReturnData` if the relayer did not set return data
+    #[allow(clippy::too_many_arguments)]
+    pub fn check_tx(
+        ctx: Context<CheckTx>,
+        height: u32,
+        index: u32,
+        txid: [u8; 32],
+        header: [u8; 80],
+        proof: Vec<u8>,
+        confirmations: u64,
+    ) -> Result<bool> {
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.relayer_program.to_account_info(),
+            VerifyTx {
+                relay_state: ctx.accounts.relay_state.to_account_info(),
+                chain: ctx.accounts.chain.to_account_info(),
+                user: ctx.accounts.user.to_account_info(),
+            },
+        );
+        relayer::cpi::verify_tx(cpi_ctx, height, index, txid, header, proof, confirmations, false)?;
+        relayer_result()
+    }
+
+    /// `check_tx` without a user signer, calling `relayer::verify_tx_cpi`.
+    ///
+    /// # Errors
+    ///
+    /// - Any error of `verify_tx_cpi`, which aborts the CPI
+    /// - `MissingReturnData` if the relayer did not set return data
+    pub fn check_tx_unsigned(
+        ctx: Context<CheckTxUnsigned>,
+        height: u32,
+        index: u32,
+        txid: [u8; 32],
+        header: [u8; 80],
+        proof: Vec<u8>,
+        confirmations: u64,
+    ) -> Result<bool> {
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.relayer_program.to_account_info(),
+            VerifyTxCpi {
+                relay_state: ctx.accounts.relay_state.to_account_info(),
+                chain: ctx.accounts.chain.to_account_info(),
+            },
+        );
+        relayer::cpi::verify_tx_cpi(cpi_ctx, height, index, txid, header, proof, confirmations)?;
+        relayer_result()
+    }
+
+    /// Records a callback of `relayer::verify_and_invoke`, which appends `verified` to the
+    /// `tag` chosen by the caller.
+    pub fn record_callback(ctx: Context<RecordCallback>, tag: u64, verified: bool) -> Result<()> {
+        let record = &mut ctx.accounts.record;
+        record.tag = tag;
+        record.verified = verified;
+        Ok(())
+    }
+}
+
+/// Reads the `bool` the relayer left in the return data of the last CPI.
+fn relayer_result() -> Result<bool> {
+    let (program_id, data) = get_return_data().ok_or(ConsumerError::MissingReturnData)?;
+    require_keys_eq!(program_id, relayer::ID, ConsumerError::MissingReturnData);
+    Ok(bool::try_from_slice(&data)?)
+}
+
+#[derive(Accounts)]
+pub struct CheckTx<'info> {
+    // any epoch's relay state would verify against its own chain, see `TRUSTED_EPOCH`
+    #[account(seeds = [b"relay_state", TRUSTED_EPOCH.to_le_bytes().as_ref()], bump, seeds::program = relayer::ID)]
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: the `BlockHash` PDA at the block height, validated by the relayer
+    pub chain: UncheckedAccount<'info>,
+    pub user: Signer<'info>,
+    pub relayer_program: Program<'info, BtcRelay>,
+}
+
+#[derive(Accounts)]
+pub struct CheckTxUnsigned<'info> {
+    // any epoch's relay state would verify against its own chain, see `TRUSTED_EPOCH`
+    #[account(seeds = [b"relay_state", TRUSTED_EPOCH.to_le_bytes().as_ref()], bump, seeds::program = relayer::ID)]
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: the `BlockHash` PDA at the block height, validated by the relayer
+    pub chain: UncheckedAccount<'info>,
+    pub relayer_program: Program<'info, BtcRelay>,
+}
+
+#[derive(Accounts)]
+#[instruction(tag: u64)]
+pub struct RecordCallback<'info> {
+    // only the relay can sign for the callback PDA of a relay state
+    #[account(seeds = [b"callback", relay_state.key().as_ref()], bump, seeds::program = relayer::ID)]
+    pub relay_authority: Signer<'info>,
+    #[account(seeds = [b"relay_state", TRUSTED_EPOCH.to_le_bytes().as_ref()], bump, seeds::program = relayer::ID)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(init, payer = payer, space = 8 + CallbackRecord::INIT_SPACE, seeds = [b"record", tag.to_le_bytes().as_ref()], bump)]
+    pub record: Account<'info, CallbackRecord>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct CallbackRecord {
+    pub tag: u64,
+    pub verified: bool,
+}
+
+#[error_code]
+pub enum ConsumerError {
+    #[msg("Relayer did not return a verification result")]
+    MissingReturnData,
+}
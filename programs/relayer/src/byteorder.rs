@@ -0,0 +1,186 @@
+//! Byte-order conventions of the relay, in one place.
+//!
+//! Bitcoin serializes integers little-endian and the relay keeps hashes in that internal
+//! order (the reverse of how explorers display them). Hashes and stored 256-bit integers such
+//! as chain work are therefore read as little-endian; targets travel in headers in the compact
+//! `nBits` form and are stored in `RelayState` as decimal strings.
+//!
+//! Every hash an instruction takes or returns is in internal order: block hashes
+//! (`block_hash`, `genesis_block_hash`, `best_block`, `expected_hash`), `txid`s, merkle roots
+//! and proof siblings alike, as are the hashes inside raw headers. Convert a hash copied from
+//! an explorer with `display_to_internal` before passing it, and a returned one with
+//! `internal_to_display` before showing it.
+
+use anchor_lang::prelude::*;
+use spl_math::uint::U256;
+
+use crate::errors::RelayError;
+
+/// Interprets a hash in internal byte order as the little-endian integer compared against
+/// targets in proof-of-work checks.
+pub fn hash_to_u256_le(hash: &[u8; 32]) -> U256 {
+    u256_from_le_bytes(hash)
+}
+
+/// Reads a 256-bit integer stored as 32 little-endian bytes.
+pub fn u256_from_le_bytes(bytes: &[u8; 32]) -> U256 {
+    U256::from_little_endian(bytes)
+}
+
+/// Writes a 256-bit integer as 32 little-endian bytes, the inverse of `u256_from_le_bytes`.
+pub fn u256_to_le_bytes(value: U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    value.to_little_endian(&mut bytes);
+    bytes
+}
+
+/// Reads the little-endian `u32` at `offset`, as header fields are serialized.
+pub fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Decodes a compact `nBits` value into the target it encodes, following Bitcoin Core's
+/// `SetCompact`.
+///
+/// # Errors
+///
+/// `IncorrectDifficultyTarget` if the sign bit is set or the target does not fit in 256 bits
+pub fn compact_to_u256(bits: u32) -> Result<U256> {
+    let exponent = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+    require!(mantissa == 0 || bits & 0x0080_0000 == 0, RelayError::IncorrectDifficultyTarget);
+
+    if exponent <= 3 {
+        return Ok(U256::from(mantissa >> (8 * (3 - exponent))));
+    }
+    // the exponent comes straight from the header: bound it so the shifted mantissa fits in
+    // 256 bits, an overflowing target must not wrap or fall back to zero
+    let shift = 8 * (exponent - 3);
+    require!(mantissa == 0 || shift + 32 - mantissa.leading_zeros() <= 256, RelayError::IncorrectDifficultyTarget);
+    Ok(U256::from(mantissa) << shift)
+}
+
+/// Encodes a target in compact `nBits` form, following Bitcoin Core's `GetCompact`; precision
+/// beyond the 3-byte mantissa is truncated.
+pub fn u256_to_compact(target: U256) -> u32 {
+    let mut size = (target.bits() as u32).div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+    // the mantissa's top bit is the sign, shift it into the next byte
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | size << 24
+}
+
+/// Converts a hash from the big-endian order explorers display to the internal order the
+/// relay stores and hashes, by reversing its bytes.
+pub fn display_to_internal(hash: [u8; 32]) -> [u8; 32] {
+    let mut internal = hash;
+    internal.reverse();
+    internal
+}
+
+/// Converts a hash from internal order to the order explorers display, the inverse of
+/// `display_to_internal`.
+pub fn internal_to_display(hash: [u8; 32]) -> [u8; 32] {
+    display_to_internal(hash)
+}
+
+/// Formats a target as the decimal string stored in `RelayState`.
+pub fn u256_to_dec_string(value: U256) -> String {
+    value.to_string()
+}
+
+/// Parses a decimal string written by `u256_to_dec_string`.
+///
+/// # Errors
+///
+/// `IncorrectDifficultyTarget` if the string is empty or not a decimal 256-bit integer
+pub fn dec_string_to_u256(value: &str) -> Result<U256> {
+    U256::from_dec_str(value).map_err(|_| error!(RelayError::IncorrectDifficultyTarget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_round_trips_little_endian() {
+        let mut hash = [0u8; 32];
+        hash[0] = 0x01;
+        hash[31] = 0x80;
+        let value = hash_to_u256_le(&hash);
+        assert_eq!(value, (U256::one() << 255u32) + U256::one());
+        assert_eq!(u256_to_le_bytes(value), hash);
+        assert_eq!(u256_from_le_bytes(&u256_to_le_bytes(value)), value);
+    }
+
+    #[test]
+    fn reads_header_fields_little_endian() {
+        let data = [0xff, 0x01, 0x02, 0x03, 0x04, 0xff];
+        assert_eq!(read_u32_le(&data, 1), 0x04030201);
+    }
+
+    #[test]
+    fn compact_round_trips() {
+        for bits in [0x1d00ffff, 0x1b04864c, 0x207fffff, 0x1c05a3f4, 0x03123456, 0x02008000] {
+            assert_eq!(u256_to_compact(compact_to_u256(bits).unwrap()), bits);
+        }
+        let target = U256::from(0xffffu32) << 208u32;
+        assert_eq!(compact_to_u256(u256_to_compact(target)).unwrap(), target);
+    }
+
+    #[test]
+    fn compact_decoding_bounds_every_exponent() {
+        for exponent in 0..=255u32 {
+            for mantissa in [0, 0x01, 0xffff, 0x7fffff] {
+                let result = compact_to_u256(exponent << 24 | mantissa);
+                let fits = mantissa == 0 || exponent <= 3 || 8 * (exponent - 3) + 32 - mantissa.leading_zeros() <= 256;
+                match result {
+                    Ok(target) if exponent > 3 => assert_eq!(target.is_zero(), mantissa == 0),
+                    Ok(_) => {}
+                    Err(err) => assert_eq!(err, RelayError::IncorrectDifficultyTarget.into()),
+                }
+                assert_eq!(compact_to_u256(exponent << 24 | mantissa).is_ok(), fits, "bits {:#010x}", exponent << 24 | mantissa);
+            }
+        }
+        // the largest mantissa fits up to exponent 32, a single bit up to 34
+        assert!(compact_to_u256(0x207fffff).is_ok());
+        assert!(compact_to_u256(0x217fffff).is_err());
+        assert!(compact_to_u256(0x22000001).is_ok());
+        assert!(compact_to_u256(0x23000001).is_err());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn converts_the_genesis_block_hash_between_orders() {
+        use crate::hash256;
+
+        let genesis_header = hex(
+            "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        );
+        let displayed: [u8; 32] = hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").try_into().unwrap();
+
+        let internal = display_to_internal(displayed);
+        assert_eq!(internal, hash256(&genesis_header));
+        assert_eq!(internal[0], 0x6f);
+        assert_eq!(internal_to_display(internal), displayed);
+        // the leading zeros of the displayed hash are the high bytes of the internal integer
+        assert!(hash_to_u256_le(&internal) < U256::one() << 216u32);
+    }
+
+    #[test]
+    fn dec_string_round_trips() {
+        let target = compact_to_u256(0x1d00ffff).unwrap();
+        assert_eq!(dec_string_to_u256(&u256_to_dec_string(target)).unwrap(), target);
+        assert!(dec_string_to_u256("12a").is_err());
+    }
+}
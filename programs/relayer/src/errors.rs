@@ -2,6 +2,7 @@ use anchor_lang::prelude::*;
 
 #[error_code]
 pub enum RelayError {
+    // headers are `[u8; 80]` and cannot have another size, kept so error codes stay stable
     #[msg("Invalid block header size")]
     InvalidHeaderSize,
 
@@ -56,6 +57,75 @@ pub enum RelayError {
     #[msg("Invalid chain id")]
     InvalidChainId,
 
-    #[msg("Fork not found")]
+    #[msg("Fork not found for the given chain id")]
     ForkNotFound,
+
+    #[msg("Invalid coinbase transaction")]
+    InvalidCoinbase,
+
+    #[msg("Invalid BIP9 version bit")]
+    InvalidVersionBit,
+
+    #[msg("Signer is not the relay authority")]
+    Unauthorized,
+
+    #[msg("Relay is paused")]
+    RelayPaused,
+
+    #[msg("Fork carries no bond")]
+    NoForkBond,
+
+    #[msg("Fork bond cannot be forfeited yet")]
+    ForkBondLocked,
+
+    #[msg("Header version outside the accepted range")]
+    InvalidVersion,
+
+    #[msg("Oracle mode requires a published oracle target")]
+    OracleNotConfigured,
+
+    #[msg("Block cannot be its own previous block")]
+    SelfReferentialBlock,
+
+    #[msg("Block would rewrite history at or below the finalized height")]
+    BelowFinality,
+
+    #[msg("Header predates the genesis block of the relay")]
+    PredatesGenesis,
+
+    #[msg("Best block has no matching main-chain header")]
+    DanglingTip,
+
+    #[msg("Fork tip is not below the finalized height")]
+    ForkNotFinal,
+
+    #[msg("Callback program does not match the consumer program account")]
+    InvalidCallbackProgram,
+
+    #[msg("Forks can only branch off the main chain")]
+    NestedFork,
+
+    #[msg("Stored main-chain header is missing from the height index, audit it with verify_consistency")]
+    InconsistentState,
+
+    #[msg("Retarget moves the target by more than the allowed factor")]
+    RetargetOutOfBounds,
+
+    #[msg("Filter header does not follow the stored filter-header chain")]
+    FilterHeaderGap,
+
+    #[msg("Relay already tracks the maximum number of active forks")]
+    TooManyForks,
+
+    #[msg("Queried range exceeds the maximum length")]
+    RangeTooLarge,
+
+    #[msg("Stored block hash does not match the checkpoint")]
+    CheckpointMismatch,
+
+    #[msg("Block changes the target within a difficulty period")]
+    UnexpectedTargetChange,
+
+    #[msg("Block at the assumevalid height is not the assumevalid block")]
+    NotAssumevalidBlock,
 }
\ No newline at end of file
@@ -1,11 +1,23 @@
+pub mod byteorder;
 pub mod errors;
 pub mod state;
 
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::compute_units::sol_remaining_compute_units;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::{invoke_signed, set_return_data};
+use anchor_lang::system_program;
 use state::*;
-use sha2::{Digest, Sha256};
 use errors::RelayError;
+use sha2::{Digest, Sha256};
 use spl_math::uint::U256;
+use std::iter::StepBy;
+use std::mem::size_of;
+use std::ops::Range;
+use byteorder::{
+    compact_to_u256, dec_string_to_u256, hash_to_u256_le, read_u32_le, u256_from_le_bytes, u256_to_compact,
+    u256_to_dec_string, u256_to_le_bytes,
+};
 
 declare_id!("7iY5TvGUTxfPX2vD71k6xkHCTDKDquruKLtikL9Pmtk7");
 
@@ -16,7 +28,6 @@ pub mod btc_relay {
     ///
     /// This function sets up the initial state of the BTC relay by storing the genesis block
     /// information. It performs several checks to ensure the validity of the provided data:
-    /// - Verifies the header size is correct (80 bytes)
     /// - Ensures the genesis height is greater than 0
     /// - Validates that the provided block hash matches the hash of the genesis header
     ///
@@ -27,315 +38,3530 @@ pub mod btc_relay {
     /// # Arguments
     ///
     /// * `ctx` - The context for the instruction
-    /// * `genesis_header` - The 80-byte Bitcoin genesis block header
+    /// * `genesis_header` - The 80-byte Bitcoin genesis block header, fixed-size so a header of
+    ///   another length fails to deserialize before the handler runs
     /// * `genesis_height` - The height of the genesis block
     /// * `genesis_block_hash` - The hash of the genesis block
+    /// * `config` - Options fixed for the lifetime of the relay
     ///
     /// # Errors
     ///
     /// This function will return an error if:
-    /// - The header size is invalid
     /// - The genesis height is 0 or negative
-    /// - The provided block hash doesn't match the hash of the genesis header
-    pub fn initialize(ctx: Context<Initialize>, genesis_header: [u8; 80], genesis_height: u32, genesis_block_hash: [u8; 32]) -> Result<()> {
-        require!(genesis_header.len() == 80, RelayError::InvalidHeaderSize);
-        require!(genesis_height > 0, RelayError::InvalidGenesisHeight);
+    /// - The provided block hash is zero or doesn't match the hash of the genesis header
+    pub fn initialize(
+        ctx: Context<Initialize>,
+        genesis_header: [u8; 80],
+        genesis_height: u32,
+        genesis_block_hash: [u8; 32],
+        config: RelayConfig,
+    ) -> Result<()> {
+        _initialize(ctx.accounts, genesis_header, genesis_height, genesis_block_hash, config)
+    }
 
-        let digest = hash256(&genesis_header);
-        require!(digest == genesis_block_hash, RelayError::InvalidBlockHash);
+    /// `initialize` followed by relaying `follow_up`, the headers right after the genesis
+    /// block, in the same transaction, saving the bootstrap round-trips.
+    ///
+    /// The follow-ups are validated and stored like in `submit_block_header_batch`, with their
+    /// `Header` and `BlockHash` PDAs passed the same way as remaining accounts. They stop early
+    /// as well when the compute budget runs low, the relay's best height tells how far they got.
+    ///
+    /// # Errors
+    ///
+    /// - Any error of `initialize`
+    /// - Any error of `submit_block_header_batch`, except that no follow-up is allowed
+    /// - `OracleNotConfigured` if the relay is in oracle mode and there are follow-ups, no
+    ///   oracle having published a target yet
+    pub fn initialize_with_headers<'info>(
+        ctx: Context<'_, '_, '_, 'info, Initialize<'info>>,
+        genesis_header: [u8; 80],
+        genesis_height: u32,
+        genesis_block_hash: [u8; 32],
+        config: RelayConfig,
+        follow_up: Vec<[u8; 80]>,
+    ) -> Result<()> {
+        check_batch_size(follow_up.len())?;
+        _initialize(ctx.accounts, genesis_header, genesis_height, genesis_block_hash, config)?;
+        if follow_up.is_empty() {
+            return Ok(());
+        }
 
-        let target = extract_target_at(&genesis_header, 0);
-        let timestamp = extract_timestamp(&genesis_header);
+        // no oracle can be set yet, oracle mode rejects the follow-up headers
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &None)?;
+        let accounts = &mut *ctx.accounts;
+        let genesis = (*accounts.header).clone();
+        _store_main_chain_run(
+            &mut accounts.relay_state,
+            &mut accounts.fork,
+            &genesis,
+            &follow_up,
+            ctx.remaining_accounts,
+            oracle_target,
+            &accounts.user.to_account_info(),
+            &accounts.system_program.to_account_info(),
+        )?;
+        Ok(())
+    }
 
-        // store bitcoin header 
-        let relay_state = &mut ctx.accounts.relay_state;
-        relay_state.best_block = digest;
-        relay_state.best_height = genesis_height;
-        relay_state.epoch_start_target = target.to_string();
-        relay_state.epoch_end_target = target.to_string();
-        relay_state.epoch_start_time = timestamp;
-        relay_state.epoch_end_time = timestamp;
+    /// Initializes the BTC relay from a post-BIP34 checkpoint block, cross-checking the
+    /// claimed height against the height committed in the block's coinbase.
+    ///
+    /// Besides the checks of `initialize`, this verifies that `coinbase_tx` is the first
+    /// transaction of the block via its merkle proof and that the height pushed at the start
+    /// of its scriptSig equals `genesis_height`.
+    ///
+    /// # Arguments
+    ///
+    /// * `coinbase_tx` - The coinbase transaction in its non-witness serialization (the txid preimage)
+    /// * `coinbase_proof` - The merkle branch of the coinbase, concatenated 32-byte siblings
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - Any check of `initialize` fails
+    /// - The coinbase is malformed, 64 bytes long (it could pass for an inner merkle node) or
+    ///   its merkle proof doesn't match the header's root
+    /// - The BIP34 height in the coinbase differs from `genesis_height`
+    pub fn initialize_checkpoint(
+        ctx: Context<Initialize>,
+        genesis_header: [u8; 80],
+        genesis_height: u32,
+        genesis_block_hash: [u8; 32],
+        config: RelayConfig,
+        coinbase_tx: Vec<u8>,
+        coinbase_proof: Vec<u8>,
+    ) -> Result<()> {
+        require!(coinbase_tx.len() != 64, RelayError::InvalidCoinbase);
+        let coinbase_txid = hash256(&coinbase_tx);
+        let merkle_root = compute_merkle_root(coinbase_txid, 0, &coinbase_proof)?;
+        require!(merkle_root == extract_merkle_root(&genesis_header), RelayError::IncorrectMerkleProof);
 
-        let fork = &mut ctx.accounts.fork;
-        fork.height = genesis_height;
+        let height = parse_bip34_height(coinbase_script(&coinbase_tx)?)?;
+        require!(height == genesis_height, RelayError::InvalidGenesisHeight);
+
+        initialize(ctx, genesis_header, genesis_height, genesis_block_hash, config)
+    }
+
+    /// Initializes a throwaway relay instance under `config.epoch` from the tip of a live relay,
+    /// for integration testing without replaying history. Only callable by the authority of
+    /// `source_relay_state`, who becomes the authority of the clone.
+    ///
+    /// The clone starts like `initialize` at `best_height`, with `tip_header` as its genesis,
+    /// but takes the difficulty-period `anchors` as given so the next retarget is validated as
+    /// on the source. Nothing is checked against the source relay: the tip and anchors are a
+    /// snapshot trusted as is, and the clone's work counts from its tip only. Bridges must
+    /// never verify against a cloned instance.
+    ///
+    /// # Errors
+    ///
+    /// - `Unauthorized` if the signer is not the authority of `source_relay_state`
+    /// - Any error of `initialize`
+    /// - `InvalidDifficultyPeriod` if `anchors.start_target` is not the target of `tip_header`
+    pub fn clone_tip_to(
+        ctx: Context<CloneTipTo>,
+        tip_header: [u8; 80],
+        best_height: u32,
+        best_block: [u8; 32],
+        config: RelayConfig,
+        anchors: EpochAnchors,
+    ) -> Result<()> {
+        let accounts = &mut *ctx.accounts;
+        _initialize_relay(
+            &mut accounts.relay_state,
+            &mut accounts.fork,
+            &mut accounts.header,
+            &mut accounts.chain,
+            accounts.authority.key(),
+            tip_header,
+            best_height,
+            best_block,
+            config,
+        )?;
 
-        _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, digest, genesis_height, CHAIN_ID)?;
+        // the target is constant within a period
+        let start_target = u256_from_le_bytes(&anchors.start_target);
+        require!(start_target == extract_target_at(&tip_header, 0)?, RelayError::InvalidDifficultyPeriod);
+        let end_target = u256_from_le_bytes(&anchors.end_target);
+        let relay_state = &mut accounts.relay_state;
+        relay_state.epoch_start_target = u256_to_dec_string(start_target);
+        relay_state.epoch_start_time = anchors.start_time;
+        relay_state.epoch_end_target = if end_target.is_zero() { String::new() } else { u256_to_dec_string(end_target) };
+        relay_state.epoch_end_time = anchors.end_time;
+        relay_state.epoch_anchored = anchors.anchored;
         Ok(())
     }
 
     /// This function submits a new block header to the relay.
     /// 
     /// It performs several checks to ensure the validity of the submitted header:
-    /// - Verifies the header size is correct (80 bytes)
     /// - Ensures the chain counter is incremented correctly
+    /// - Checks that the fork of the previous block exists
     /// - Validates that the provided block hash matches the hash of the header
-    /// - Checks that the block hasn't been submitted before
+    /// - Checks that the block hasn't been submitted before and agrees with the height index
     /// - Verifies the previous block exists and is at the correct height
-    /// - Ensures the chain ID is valid
+    /// - Ensures the chain ID is valid and that a new fork branches off the main chain
     /// - Checks that the block meets the required difficulty target
     /// 
     /// If the block is at the start of a new difficulty adjustment period, it also verifies
     /// the new difficulty target. At the end of a period, it updates the epoch end information.
     /// 
-    /// The function handles creating new forks when necessary and updates the relay state
-    /// with the new best block if applicable.
+    /// A block whose parent is the tip of its chain extends that chain, growing the
+    /// `prev_fork` PDA (reallocated once full, `user` paying the extra rent); otherwise it
+    /// starts the `next_counter` fork. The function updates the
+    /// relay state with the new best block if applicable. When the submission makes a fork overtake the
+    /// main chain, the accounts listed on `reorg_chain` must be passed as remaining accounts.
+    /// 
+    /// Ties go to the first-seen chain, as in Bitcoin: a fork reaching the main chain's height
+    /// or work only extends its own branch. It takes over once it leads by the work of
+    /// `CONFIRMATIONS` blocks (see `fork_overtakes`), at a constant difficulty when its tip
+    /// reaches `best_height + CONFIRMATIONS`, mined at a higher difficulty possibly at or below
+    /// `best_height`.
+    /// 
+    /// The parent is never passed explicitly: `prev_header` is the `Header` PDA seeded by the
+    /// header's own prevhash field, and `prev_fork` the `Fork` PDA of the parent's chain id.
     /// 
     /// # Arguments
     /// 
     /// * `ctx` - The context for the instruction
-    /// * `header` - The 80-byte Bitcoin block header
+    /// * `header` - The 80-byte Bitcoin block header, fixed-size like `genesis_header`
     /// * `block_hash` - The hash of the block
-    /// * `prev_block_hash` - The hash of the previous block
-    /// * `prev_block_hash_chain_id` - The chain ID of the previous block
     /// * `block_height` - The height of the new block
     /// * `next_counter` - The next chain counter value
     /// 
     /// # Errors
     /// 
     /// This function will return an error if any of the validity checks fail.
-    pub fn submit_block_header(
-        ctx: Context<SubmitBlockHeader>, 
+    pub fn submit_block_header<'info>(
+        ctx: Context<'_, '_, '_, 'info, SubmitBlockHeader<'info>>, 
         header: [u8; 80], 
         block_hash: [u8; 32], 
-        _prev_block_hash: [u8; 32], 
-        prev_block_hash_chain_id: u32, 
         block_height: u32,
         next_counter: u32
     ) -> Result<()> {
-        require!(header.len() == 80, RelayError::InvalidHeaderSize);
+        let prev_block_hash = extract_prev_block_hash(&header);
+        let prev_block_hash_chain_id = ctx.accounts.prev_header.chain_id;
+        require!(prev_block_hash != block_hash, RelayError::SelfReferentialBlock);
         require!(ctx.accounts.relay_state.chain_counter + 1 == next_counter, RelayError::InvalidCounter);
+        let mut prev_fork = load_fork(&ctx.accounts.prev_fork)?;
 
-        let hash_curr_block: [u8; 32] = hash256(&header);
-        require!(hash_curr_block == block_hash, RelayError::InvalidBlockHash);       
-        require!(ctx.accounts.header.chain_id == 0, RelayError::DuplicateBlock);
-        let prv_height = ctx.accounts.prev_header.height;
-        require!(prv_height > 0 && prv_height == block_height - 1, RelayError::PreviousBlockNotFound);
-        require!(ctx.accounts.prev_header.chain_id == prev_block_hash_chain_id, RelayError::InvalidChainId);
-
-        let target = extract_target_at(&header, 0);
-        require!(U256::from_little_endian(&hash_curr_block) <= target, RelayError::LowDifficulty);
-
-        let prv_target = U256::from_dec_str(&ctx.accounts.relay_state.epoch_start_target).unwrap();
-        let prv_end_target = U256::from_dec_str(&ctx.accounts.relay_state.epoch_end_target).unwrap();
-        
-        if is_period_start(block_height) {
-            require!(
-                is_correct_difficulty_target(
-                    prv_target,
-                    ctx.accounts.relay_state.epoch_start_time,
-                    prv_end_target,
-                    ctx.accounts.relay_state.epoch_end_time,
-                    target,
-                ).unwrap_or_default(),
-                RelayError::IncorrectDifficultyTarget
-            );
+        check_not_stored(&ctx.accounts.header, &ctx.accounts.chain, block_hash, block_height)?;
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?;
+        let target = validate_block_header(
+            &ctx.accounts.relay_state,
+            &ctx.accounts.prev_header,
+            &header,
+            block_hash,
+            prev_block_hash_chain_id,
+            block_height,
+            oracle_target,
+        )?;
+        let hash_curr_block = block_hash;
 
-            ctx.accounts.relay_state.epoch_start_target = target.to_string();
-            ctx.accounts.relay_state.epoch_start_time = extract_timestamp(&header);
-            ctx.accounts.relay_state.epoch_end_target = String::new();
-            ctx.accounts.relay_state.epoch_end_time = 0;
-        } else if is_period_end(block_height) {
-            ctx.accounts.relay_state.epoch_end_target = target.to_string();
-            ctx.accounts.relay_state.epoch_end_time = extract_timestamp(&header);
-        }
+        let chain_work = u256_from_le_bytes(&ctx.accounts.prev_header.chain_work)
+            .checked_add(calculate_work(target)?)
+            .ok_or(RelayError::ArithmeticError)?;
+
+        ctx.accounts.relay_state.total_headers += 1;
 
-        let is_new_fork = ctx.accounts.prev_fork.height != ctx.accounts.prev_header.height;
+        // a block extends the chain of its parent when the parent is that chain's tip, and
+        // branches into the `next_counter` fork otherwise
+        let is_new_fork = prev_fork.height != ctx.accounts.prev_header.height;
+        check_fork_allowed(&ctx.accounts.relay_state, !is_new_fork && prev_block_hash_chain_id == MAIN_CHAIN_ID)?;
         if is_new_fork {
+            // reorgs and median time past walk a fork back to the main chain in one step
+            require!(prev_block_hash_chain_id == MAIN_CHAIN_ID, RelayError::NestedFork);
+            require!(block_height > ctx.accounts.relay_state.finalized_height, RelayError::BelowFinality);
+            check_fork_capacity(&ctx.accounts.relay_state)?;
             ctx.accounts.relay_state.chain_counter = next_counter;
-            _initialize_fork(&mut ctx.accounts.fork, hash_curr_block, ctx.accounts.relay_state.best_block, next_counter, block_height)?;
-            _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, hash_curr_block, block_height, next_counter)?;
+            ctx.accounts.relay_state.active_fork_count += 1;
+            ctx.accounts.relay_state.active_fork_ids.push(next_counter);
+            if let Some(storm) = record_fork_creation(&mut ctx.accounts.relay_state, next_counter, block_height) {
+                emit!(storm);
+            }
+            _initialize_fork(&mut ctx.accounts.fork, hash_curr_block, prev_block_hash, next_counter, block_height)?;
+
+            // anti-spam bond, refunded when the fork is absorbed into the main chain
+            let fork_bond = ctx.accounts.relay_state.fork_bond;
+            if fork_bond > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.user.to_account_info(),
+                            to: ctx.accounts.fork.to_account_info(),
+                        },
+                    ),
+                    fork_bond,
+                )?;
+                ctx.accounts.fork.bond = fork_bond;
+                ctx.accounts.fork.bond_owner = ctx.accounts.user.key();
+            }
+            _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, &header, hash_curr_block, block_height, next_counter, chain_work, ctx.accounts.user.key())?;
         } else {
-            _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, hash_curr_block, block_height, prev_block_hash_chain_id)?;
+            _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, &header, hash_curr_block, block_height, prev_block_hash_chain_id, chain_work, ctx.accounts.user.key())?;
             if prev_block_hash_chain_id == MAIN_CHAIN_ID {
-                ctx.accounts.relay_state.best_block = hash_curr_block;
-                ctx.accounts.relay_state.best_height = block_height;
-                ctx.accounts.fork.height = block_height;
-                ctx.accounts.fork.descendants.push(hash_curr_block);
-            } else if block_height >= ctx.accounts.relay_state.best_height + CONFIRMATIONS {
-                reorg_chain(ctx, prev_block_hash_chain_id, block_height, hash_curr_block)?;
+                // epoch anchors follow the main chain only, a reorg recomputes them
+                update_epoch(&mut ctx.accounts.relay_state, block_height, target, extract_timestamp(&header));
+                record_main_timestamp(&mut ctx.accounts.relay_state, block_height, extract_timestamp(&header));
+                _extend_main_chain(&mut ctx.accounts.relay_state, &mut prev_fork, hash_curr_block, block_height, extract_timestamp(&header), chain_work);
+                store_account(&ctx.accounts.prev_fork, &prev_fork)?;
+                check_tip_invariant(&ctx.accounts.relay_state, hash_curr_block, &ctx.accounts.header)?;
             } else {
-                ctx.accounts.fork.height = block_height;
-                ctx.accounts.fork.descendants.push(hash_curr_block);
+                let rewind_from = block_height - prev_fork.descendants.len() as u32;
+                let overtakes = fork_overtakes(&ctx.accounts.relay_state, block_height, rewind_from, chain_work)?;
+                grow_fork(
+                    &ctx.accounts.prev_fork,
+                    &prev_fork,
+                    prev_fork.descendants.len() + 1,
+                    &ctx.accounts.user.to_account_info(),
+                    &ctx.accounts.system_program.to_account_info(),
+                )?;
+                prev_fork.height = block_height;
+                prev_fork.descendants.push(hash_curr_block);
+                store_account(&ctx.accounts.prev_fork, &prev_fork)?;
+                if overtakes {
+                    let accounts = &mut *ctx.accounts;
+                    reorg_chain(
+                        &mut accounts.relay_state,
+                        &accounts.prev_fork,
+                        &mut accounts.header,
+                        &mut accounts.chain,
+                        &mut accounts.fork,
+                        &accounts.user.to_account_info(),
+                        &accounts.system_program.to_account_info(),
+                        ctx.remaining_accounts,
+                        prev_fork,
+                        prev_block_hash_chain_id,
+                        block_height,
+                        hash_curr_block,
+                    )?;
+                }
             }
         }
 
         Ok(())
     }
 
-    pub fn submit_block_header_batch(ctx: Context<SubmitBlockHeaderBatch>, headers: Vec<[u8; 80]>) -> Result<()> {
-        // Implement batch submission logic
+    /// Runs every check `submit_block_header` performs (counter, fork, hash, linkage and
+    /// difficulty) without storing anything, so relayers can simulate a submission and read
+    /// its compute units before sending it.
+    ///
+    /// Returns `Ok` if the header would be accepted and the corresponding error otherwise.
+    pub fn simulate_submit(
+        ctx: Context<SimulateSubmit>,
+        header: [u8; 80],
+        block_hash: [u8; 32],
+        block_height: u32,
+        next_counter: u32
+    ) -> Result<()> {
+        require!(extract_prev_block_hash(&header) != block_hash, RelayError::SelfReferentialBlock);
+        require!(ctx.accounts.relay_state.chain_counter + 1 == next_counter, RelayError::InvalidCounter);
+        load_fork(&ctx.accounts.prev_fork)?;
+
+        require!(!is_header_stored(&ctx.accounts.header), RelayError::DuplicateBlock);
+
+        validate_block_header(
+            &ctx.accounts.relay_state,
+            &ctx.accounts.prev_header,
+            &header,
+            block_hash,
+            ctx.accounts.prev_header.chain_id,
+            block_height,
+            oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?,
+        )?;
         Ok(())
     }
 
-    pub fn verify_tx(ctx: Context<VerifyTx>, height: u32, index: u64, txid: [u8; 32], header: [u8; 80], proof: Vec<u8>, confirmations: u64, insecure: bool) -> Result<bool> {
-        // Implement transaction verification logic
-        Ok(false)
+    /// Extends the main chain with a run of consecutive headers, checking proof-of-work only on
+    /// some of them.
+    ///
+    /// Every header must link to the previous one (the first to the current best block), but
+    /// proof-of-work (`hash <= target`) is only checked on checkpoint heights (multiples of
+    /// `checkpoint_interval`), on difficulty period boundaries and on the final header; the
+    /// target itself is validated on every header, retargets included. In oracle mode every
+    /// header must meet both its own target and the oracle's, whatever `checkpoint_interval`.
+    ///
+    /// This trades security for cost: a header between checkpoints can claim work it never
+    /// did, so an attacker has to mine only the checkpoints to fabricate a run. Each header is
+    /// still credited the full work of its target in `chain_work`, checked or not, and that work
+    /// counts when `submit_block_header` weighs a fork against the main chain: a fork mined in
+    /// full has to outwork the claimed work of the run, not the work actually done. Choose a
+    /// small `checkpoint_interval` unless the run is anchored by other means.
+    ///
+    /// Every header is stored like a `submit_block_header` one, so the height index stays whole
+    /// and later instructions (proofs, reorgs, audits) can reference any of them. The last
+    /// header's PDAs are `chain` and `header`; the others are passed as remaining accounts, for
+    /// each in order its `Header` PDA and its `BlockHash` PDA. Runs close to
+    /// `MAX_HEADER_CHAIN_LENGTH` need an address lookup table for them.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidHeaderBatch` if the run is empty, too long, or does not link
+    /// - `BlockNotFound` if the remaining accounts are not the PDAs of the run
+    /// - `DuplicateBlock` if a header of the run is already stored
+    /// - `LowDifficulty` if a checked header does not meet its target
+    /// - `IncorrectDifficultyTarget` if a retarget is wrong
+    /// - `UnexpectedTargetChange` if a header changes the target mid-period
+    /// - `NotAssumevalidBlock` if a header at the assumevalid height is not that block
+    pub fn submit_header_chain<'info>(
+        ctx: Context<'_, '_, '_, 'info, SubmitHeaderChain<'info>>,
+        headers: Vec<[u8; 80]>,
+        checkpoint_interval: u32,
+    ) -> Result<()> {
+        require!(!headers.is_empty() && headers.len() <= MAX_HEADER_CHAIN_LENGTH, RelayError::InvalidHeaderBatch);
+        require!(checkpoint_interval > 0, RelayError::InvalidHeaderBatch);
+        require!(ctx.remaining_accounts.len() == 2 * (headers.len() - 1), RelayError::BlockNotFound);
+
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?;
+        let relay_state = &mut ctx.accounts.relay_state;
+        let payer = ctx.accounts.user.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+        let mut prev_hash = relay_state.best_block;
+        let mut height = relay_state.best_height;
+        let mut chain_work = u256_from_le_bytes(&ctx.accounts.prev_header.chain_work);
+
+        for (i, header) in headers.iter().enumerate() {
+            require!(header[4..36] == prev_hash, RelayError::InvalidHeaderBatch);
+            let hash = hash256(header);
+            height += 1;
+
+            check_after_genesis(relay_state, height)?;
+            check_version(relay_state, header)?;
+            let target = extract_target_at(header, 0)?;
+            // the oracle only vouches for the difficulty of headers shown to meet it
+            let is_checkpoint = oracle_target.is_some() || height.is_multiple_of(checkpoint_interval) || i == headers.len() - 1;
+            if is_checkpoint || is_period_start(height) || is_period_end(height) {
+                require!(hash_to_u256_le(&hash) <= target, RelayError::LowDifficulty);
+            }
+            check_assumevalid(relay_state, height, hash)?;
+            match oracle_target {
+                Some(oracle_target) => require!(hash_to_u256_le(&hash) <= oracle_target, RelayError::LowDifficulty),
+                None if is_assumed_valid(relay_state, height) => {}
+                None => check_target(relay_state, height, target)?,
+            }
+            update_epoch(relay_state, height, target, extract_timestamp(header));
+            record_main_timestamp(relay_state, height, extract_timestamp(header));
+            relay_state.total_headers += 1;
+
+            chain_work = chain_work
+                .checked_add(calculate_work(target)?)
+                .ok_or(RelayError::ArithmeticError)?;
+            prev_hash = hash;
+
+            // the last header goes to the `chain` and `header` accounts below
+            if let [header_info, chain_info] = ctx.remaining_accounts.get(2 * i..2 * i + 2).unwrap_or_default() {
+                require!(!is_header_stored(header_info), RelayError::DuplicateBlock);
+                let epoch = relay_state.epoch.to_le_bytes();
+                create_pda(header_info, &[b"header", epoch.as_ref(), hash.as_ref()], size_of::<Header>() + 8, &payer, &system_program)?;
+                create_pda(chain_info, &[b"chain", epoch.as_ref(), height.to_le_bytes().as_ref()], size_of::<BlockHash>() + 8, &payer, &system_program)?;
+                store_account(header_info, &header_record(header, height, MAIN_CHAIN_ID, chain_work, payer.key()))?;
+                store_account(chain_info, &BlockHash { block_hash: hash })?;
+            }
+        }
+
+        let tip = headers.last().unwrap();
+        _extend_main_chain(relay_state, &mut ctx.accounts.main_fork, prev_hash, height, extract_timestamp(tip), chain_work);
+        _store_block_header(&mut ctx.accounts.header, &mut ctx.accounts.chain, tip, prev_hash, height, MAIN_CHAIN_ID, chain_work, ctx.accounts.user.key())?;
+        check_tip_invariant(&ctx.accounts.relay_state, prev_hash, &ctx.accounts.header)
     }
 
-    // Add other functions as needed
-}
+    /// Returns the stored metadata of the header with `block_hash`, on the main chain or a
+    /// fork, including who submitted it.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if no header with `block_hash` is stored
+    pub fn get_block_header(ctx: Context<GetBlockHeader>, _block_hash: [u8; 32]) -> Result<Header> {
+        load_header(&ctx.accounts.header)
+    }
 
-fn reorg_chain(
-    ctx: Context<SubmitBlockHeader>, 
-    chain_id: u32,
-    height: u32,
-    hash_curr_block: [u8; 32],
-) -> Result<()> {
-    let relay = &mut ctx.accounts.relay_state;
-    relay.chain_counter += 1;
-
-    // reorg fork to main
-    let mut ancestor_id = chain_id;
-    let fork_id = relay.chain_counter;
-    let mut fork_height = height - 1;
-
-    // TODO: add new fork struct for old main
-
-    // while ancestor_id != relay.MAIN_CHAIN_ID {
-    //     let fork = relay.forks.get(&ancestor_id).ok_or(RelayError::ForkNotFound)?;
-        
-    //     for i in (0..fork.descendants.len()).rev() {
-    //         let descendant = fork.descendants[i];
-            
-    //         // promote header to main chain
-    //         relay.headers.get_mut(&descendant).unwrap().chain_id = relay.MAIN_CHAIN_ID;
-            
-    //         // demote old header to new fork
-    //         relay.headers.get_mut(&relay.chain[height as usize]).unwrap().chain_id = fork_id;
-            
-    //         // swap header at height
-    //         relay.chain[height as usize] = descendant;
-    //         fork_height -= 1;
-    //     }
-
-    //     let ancestor = fork.ancestor;
-    //     ancestor_id = relay.headers.get(&ancestor).unwrap().chain_id;
-    // }
-
-    // emit!(ChainReorg {
-    //     from: relay.best_block,
-    //     to: hash_curr_block,
-    //     chain_id,
-    // });
-
-    // relay.best_block = hash_curr_block;
-    // relay.best_height = height;
-
-    // relay.forks.remove(&chain_id);
-
-    // // extend to current head
-    // relay.chain[relay.best_height as usize] = relay.best_block;
-    // relay.headers.get_mut(&relay.best_block).unwrap().chain_id = relay.MAIN_CHAIN_ID;
+    /// Returns whether a header with `block_hash` is already stored, on the main chain or a
+    /// fork, so relayers can skip redundant submissions.
+    pub fn header_exists(ctx: Context<HeaderExists>, _block_hash: [u8; 32]) -> Result<bool> {
+        Ok(is_header_stored(&ctx.accounts.header))
+    }
 
-    Ok(())
-}
+    /// Stores the BIP158 filter header of the main-chain block at `height`, for neutrino-style
+    /// clients to check the filters they download: a filter with hash `h` at `height` matches
+    /// when `hash256(h || filter header at height - 1)` is the stored filter header.
+    ///
+    /// Blocks do not commit to their filters, so only the relay authority submits filter
+    /// headers. They form a chain keyed by height, starting at the genesis height, and each
+    /// must follow a stored one. A reorg leaves them stale until the authority overwrites them.
+    ///
+    /// # Errors
+    ///
+    /// - `Unauthorized` if not signed by the relay authority
+    /// - `BlockNotFound` if no main-chain block is stored at `height`
+    /// - `FilterHeaderGap` if `height` is above the genesis height and the filter header below
+    ///   it is not passed as `prev_filter_header`
+    pub fn submit_filter_header(ctx: Context<SubmitFilterHeader>, height: u32, filter_header: [u8; 32]) -> Result<()> {
+        let relay_state = &ctx.accounts.relay_state;
+        require!(
+            (relay_state.genesis_height..=relay_state.best_height).contains(&height),
+            RelayError::BlockNotFound
+        );
+        require!(
+            height == relay_state.genesis_height || ctx.accounts.prev_filter_header.is_some(),
+            RelayError::FilterHeaderGap
+        );
+        ctx.accounts.filter_header.filter_header = filter_header;
+        Ok(())
+    }
 
-pub fn is_correct_difficulty_target(
-    prev_start_target: U256,
-    prev_start_time: u32,
-    prev_end_target: U256,
-    prev_end_time: u32,
-    next_target: U256,
-) -> Result<bool> {
-    // Check if the difficulty at the start and end of the previous period is the same
-    require!(calculate_difficulty(prev_start_target) != calculate_difficulty(prev_end_target), RelayError::InvalidDifficultyPeriod);
-    let expected_target = retarget_algorithm(prev_start_target, prev_start_time, prev_end_time).unwrap();
+    /// Returns the BIP158 filter header stored for `height`, see `submit_filter_header`.
+    pub fn get_filter_header(ctx: Context<GetFilterHeader>, _height: u32) -> Result<[u8; 32]> {
+        Ok(ctx.accounts.filter_header.filter_header)
+    }
 
-    Ok((next_target & expected_target) == next_target)
-}
+    /// Extends the main chain by one header without any caller-supplied linkage data.
+    ///
+    /// The previous block is read from the header's own prevhash field and must be the current
+    /// best block; the height is derived as its height + 1. Forks are not handled here, use
+    /// `submit_block_header` for those.
+    ///
+    /// # Errors
+    ///
+    /// - `NotChainExtension` if the previous block is not the main chain tip
+    /// - Any validity error of `submit_block_header`
+    pub fn extend_chain(ctx: Context<ExtendChain>, header: [u8; 80]) -> Result<()> {
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?;
+        _connect_to_tip(
+            &mut ctx.accounts.relay_state,
+            &mut ctx.accounts.main_fork,
+            &ctx.accounts.prev_header,
+            &mut ctx.accounts.chain,
+            &mut ctx.accounts.header,
+            &header,
+            ctx.accounts.user.key(),
+            oracle_target,
+        )
+    }
 
-// Helper functions (you'll need to implement these)
-fn calculate_difficulty(target: U256) -> U256 {
-    let diff1_target = U256::from_str_radix(DIFF1_TARGET, 16).unwrap();
-    diff1_target.checked_div(target).unwrap_or_default()
-}
+    /// Parks a header whose parent has not been relayed yet in an `Orphan` PDA keyed by that
+    /// parent's hash, so out-of-order deliveries need not be dropped. Only its own
+    /// proof-of-work is checked here; everything else is validated by `connect_orphans`.
+    ///
+    /// A single orphan can wait on a given parent at a time.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidBlockHash` if `block_hash` is not the hash of `header`
+    /// - `LowDifficulty` if the header does not meet its own target
+    pub fn submit_orphan(ctx: Context<SubmitOrphan>, header: [u8; 80], block_hash: [u8; 32]) -> Result<()> {
+        require!(hash256(&header) == block_hash, RelayError::InvalidBlockHash);
+        let target = extract_target_at(&header, 0)?;
+        require!(hash_to_u256_le(&block_hash) <= target, RelayError::LowDifficulty);
 
-pub fn retarget_algorithm(
-    previous_target: U256,
-    first_timestamp: u32,
-    second_timestamp: u32
-) -> Result<U256> {
-    let mut elapsed_time = second_timestamp.checked_sub(first_timestamp)
-        .ok_or(RelayError::ArithmeticError)?;
+        let orphan = &mut ctx.accounts.orphan;
+        orphan.header = header;
+        orphan.submitter = ctx.accounts.user.key();
+        Ok(())
+    }
 
-    // Normalize ratio to factor of 4 if very long or very short
-    if elapsed_time < RETARGET_PERIOD / 4 {
-        elapsed_time = RETARGET_PERIOD / 4;
+    /// Connects the orphan waiting on `parent_hash` once that parent is the main chain tip,
+    /// as `extend_chain` would, and closes its PDA refunding the rent to whoever parked it.
+    ///
+    /// Calling it again with the connected orphan's hash connects the next orphan in line.
+    ///
+    /// # Errors
+    ///
+    /// - `NotChainExtension` if the parent is not the main chain tip
+    /// - Any validity error of `submit_block_header`
+    pub fn connect_orphans(ctx: Context<ConnectOrphans>, _parent_hash: [u8; 32]) -> Result<()> {
+        let header = ctx.accounts.orphan.header;
+        // the reward goes to whoever relayed the header, not to whoever connected it
+        let submitter = ctx.accounts.orphan.submitter;
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?;
+        _connect_to_tip(
+            &mut ctx.accounts.relay_state,
+            &mut ctx.accounts.main_fork,
+            &ctx.accounts.prev_header,
+            &mut ctx.accounts.chain,
+            &mut ctx.accounts.header,
+            &header,
+            submitter,
+            oracle_target,
+        )
     }
-    if elapsed_time > RETARGET_PERIOD * 4 {
-        elapsed_time = RETARGET_PERIOD * 4;
+
+    /// Returns the target of the current difficulty period as a little-endian 256-bit integer.
+    ///
+    /// # Errors
+    ///
+    /// `InvalidDifficultyPeriod` if the period target is not recorded
+    pub fn get_current_target(ctx: Context<QueryRelayState>) -> Result<[u8; 32]> {
+        Ok(u256_to_le_bytes(current_target(&ctx.accounts.relay_state)?))
     }
 
-    // Divide by 256^2 to prevent overflow, will multiply back later
-    let adjusted = previous_target
-        .checked_div(65536.into())
-        .ok_or(RelayError::ArithmeticError)?
-        .checked_mul(elapsed_time.into())
-        .ok_or(RelayError::ArithmeticError)?;
+    /// Returns the difficulty (see `target_to_difficulty`) of the current difficulty period as
+    /// a little-endian 256-bit integer.
+    ///
+    /// # Errors
+    ///
+    /// `InvalidDifficultyPeriod` if the period target is not recorded
+    pub fn get_difficulty(ctx: Context<QueryRelayState>) -> Result<[u8; 32]> {
+        Ok(u256_to_le_bytes(target_to_difficulty(current_target(&ctx.accounts.relay_state)?)))
+    }
 
-    let result = adjusted
-        .checked_div(RETARGET_PERIOD.into())
-        .ok_or(RelayError::ArithmeticError)?
-        .checked_mul(65536.into())
-        .ok_or(RelayError::ArithmeticError)?;
+    /// Returns the difficulty (see `target_to_difficulty`) of every period start in
+    /// `[start_height, start_height + count)`, as little-endian 256-bit integers in ascending
+    /// height order, showing how the relay's accepted difficulty evolved.
+    ///
+    /// The main-chain `Header` accounts of those period starts must be supplied as remaining
+    /// accounts, in the same order.
+    ///
+    /// # Errors
+    ///
+    /// - `RangeTooLarge` if the range holds more than `MAX_DIFFICULTY_SERIES` period starts
+    /// - `BlockNotFound` if a header is missing or not the main-chain block at its period start
+    pub fn get_difficulty_series(ctx: Context<QueryRelayState>, start_height: u32, count: u32) -> Result<Vec<[u8; 32]>> {
+        let heights = period_starts(start_height, count);
+        require!(heights.len() <= MAX_DIFFICULTY_SERIES, RelayError::RangeTooLarge);
+        require!(ctx.remaining_accounts.len() == heights.len(), RelayError::BlockNotFound);
 
-    Ok(result)
-}
+        heights
+            .zip(ctx.remaining_accounts)
+            .map(|(height, info)| {
+                let bits = load_main_header_at(info, height)?.bits;
+                Ok(u256_to_le_bytes(target_to_difficulty(bits_to_target(bits)?)))
+            })
+            .collect()
+    }
 
-fn is_period_start(height: u32) -> bool {
-    height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0
-}
+    /// Counts how many main-chain headers in `[start_height, start_height + count)` signal the
+    /// BIP9 version `bit`, i.e. use the `001` top version bits and have `bit` set.
+    ///
+    /// The `Header` accounts of the window must be supplied as remaining accounts in ascending
+    /// height order.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidVersionBit` if `bit` is not one of the 29 BIP9 bits
+    /// - `BlockNotFound` if a header of the window is missing or not on the main chain
+    pub fn count_version_bits(ctx: Context<QueryRelayState>, start_height: u32, count: u32, bit: u8) -> Result<u32> {
+        require!(bit < VERSIONBITS_NUM_BITS, RelayError::InvalidVersionBit);
+        require!(ctx.remaining_accounts.len() == count as usize, RelayError::BlockNotFound);
 
-fn is_period_end(height: u32) -> bool {
-    height % DIFFICULTY_ADJUSTMENT_INTERVAL == 2015
-}
+        let mut signalling = 0;
+        for (i, info) in ctx.remaining_accounts.iter().enumerate() {
+            let height = start_height.checked_add(i as u32).ok_or(RelayError::BlockNotFound)?;
+            let version = load_main_header_at(info, height)?.version;
+            if version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS && version & (1 << bit) != 0 {
+                signalling += 1;
+            }
+        }
+        Ok(signalling)
+    }
 
-fn _initialize_fork(fork: &mut Account<'_, Fork>, hash_curr_block: [u8; 32], hash_prev_block: [u8; 32], new_chain_id: u32, height: u32) -> Result<()> {
-    fork.height = height;
-    fork.ancestor = hash_prev_block;
-    fork.descendants = vec![hash_curr_block];
-    Ok(())
-}
+    /// Checks that `headers` form a valid proof-of-work chain without reading or storing any
+    /// relay state: each header links to the previous one, meets its own target, keeps the
+    /// target within a period and retargets correctly at period starts.
+    ///
+    /// `headers[0]` is at `start_height`, and `start_target` (little-endian) and `start_time`
+    /// are the target and timestamp of the first block of its difficulty period. The height
+    /// is needed to tell where periods begin. Returns `false` for an invalid segment.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidHeaderBatch` if the segment is empty or longer than `MAX_HEADER_CHAIN_LENGTH`
+    pub fn validate_segment(
+        _ctx: Context<ValidateSegment>,
+        headers: Vec<[u8; 80]>,
+        start_height: u32,
+        start_target: [u8; 32],
+        start_time: u32,
+    ) -> Result<bool> {
+        require!(!headers.is_empty() && headers.len() <= MAX_HEADER_CHAIN_LENGTH, RelayError::InvalidHeaderBatch);
+        Ok(is_valid_segment(&headers, start_height, u256_from_le_bytes(&start_target), start_time))
+    }
 
-fn _store_block_header(
-    header: &mut Account<'_, Header>,
-    chain: &mut Account<'_, BlockHash>,
-    digest: [u8; 32],
-    height: u32,
-    chain_id: u32,
-) -> Result<()> {
-    header.chain_id = chain_id;
-    header.height = height;
-    chain.block_hash = digest;
-    
-    Ok(())
-}
+    /// Checks the merkle branch `proof` (concatenated 32-byte siblings) of `txid` at `index`
+    /// against `expected_root` instead of a stored header, folding it like `verify_tx` does.
+    /// Reads no relay state, so it also serves proofs against headers not relayed yet and
+    /// client-side test vectors. Returns `false` for a malformed or non-matching proof.
+    pub fn verify_merkle(
+        _ctx: Context<VerifyMerkle>,
+        txid: [u8; 32],
+        index: u32,
+        proof: Vec<u8>,
+        expected_root: [u8; 32],
+    ) -> Result<bool> {
+        Ok(proves_inclusion(txid, index, &proof, expected_root))
+    }
 
-fn hash256(b: &[u8]) -> [u8; 32] {
-    let mut hasher = Sha256::new();
-    hasher.update(Sha256::digest(b));
-    hasher.finalize().into()
-}
+    /// Checks the BIP141 witness commitment of a segwit block: returns `true` if `coinbase_tx`,
+    /// in its witness serialization, commits to `witness_root`, the merkle root of the
+    /// block's wtxids (the coinbase's counting as zero).
+    ///
+    /// The commitment is the last output whose script starts with `WITNESS_COMMITMENT_HEADER`
+    /// and must equal the double SHA256 of `witness_root` followed by the witness reserved
+    /// value, the single 32-byte item of the coinbase's witness. Returns `false` when the
+    /// coinbase carries no commitment. Like `verify_merkle` this reads no relay state, proving
+    /// the coinbase part of a relayed block is up to the caller.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidCoinbase` if `coinbase_tx` is not a coinbase in witness serialization with a
+    ///   32-byte witness reserved value
+    pub fn verify_witness_commitment(_ctx: Context<VerifyMerkle>, coinbase_tx: Vec<u8>, witness_root: [u8; 32]) -> Result<bool> {
+        let (commitment, reserved_value) = parse_witness_commitment(&coinbase_tx)?;
+        Ok(commitment.is_some_and(|commitment| hash256(&[witness_root, reserved_value].concat()) == commitment))
+    }
 
-fn extract_target_at(header: &[u8], at: usize) -> U256 {
-    let m: u32 = u32::from_le_bytes([header[72 + at], header[73 + at], header[74 + at], 0]);
-    let e = header[75 + at];
-    let mantissa = U256::from(reverse_uint24(m) as u64);
-    let exponent = U256::from(e.saturating_sub(3) as u32);
-    
-    // Use checked arithmetic to prevent overflow
-    mantissa.checked_mul(U256::from(256).checked_pow(exponent).unwrap_or(U256::from(0))).unwrap_or(U256::from(0))
-}
+    /// Returns the `Header`, `BlockHash` and `Fork` PDAs of a block in the relay instance of
+    /// `epoch`, derived with the exact seeds the program uses, so clients need not re-implement
+    /// the seed encoding.
+    pub fn derive_pdas(
+        _ctx: Context<DerivePdas>,
+        block_hash: [u8; 32],
+        block_height: u32,
+        chain_id: u32,
+        epoch: u64,
+    ) -> Result<BlockPdas> {
+        Ok(BlockPdas {
+            header: header_address(epoch, &block_hash),
+            chain: chain_address(epoch, block_height),
+            fork: fork_address(epoch, chain_id),
+        })
+    }
 
-fn reverse_uint24(b: u32) -> u32 {
-    (b  << 16) | (b & 0x00FF00) | (b  >> 16)
-}
+    /// Counts the active forks holding a block at `height`, showing how wide a chain split is.
+    ///
+    /// The `Fork` PDA of every id in `active_fork_ids` must be supplied as remaining accounts,
+    /// in the same order.
+    pub fn forks_at_height(ctx: Context<QueryRelayState>, height: u32) -> Result<u32> {
+        let fork_ids = &ctx.accounts.relay_state.active_fork_ids;
+        require!(ctx.remaining_accounts.len() == fork_ids.len(), RelayError::ForkNotFound);
 
-fn extract_timestamp_le(data: &[u8]) -> [u8; 4] {
-    data[68..72].try_into().unwrap()
-}
+        let mut count = 0;
+        for (&chain_id, info) in fork_ids.iter().zip(ctx.remaining_accounts) {
+            require_keys_eq!(info.key(), fork_address(ctx.accounts.relay_state.epoch, chain_id), RelayError::ForkNotFound);
+            let fork = load_fork(info)?;
+            let first_height = fork.height + 1 - fork.descendants.len() as u32;
+            if (first_height..=fork.height).contains(&height) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
 
-fn extract_timestamp(data: &[u8]) -> u32 {
-    let timestamp_le = extract_timestamp_le(data);
-    u32::from_le_bytes(timestamp_le)
-}
\ No newline at end of file
+    /// Returns a snapshot of the relay's status for monitoring, in a single call.
+    pub fn get_health(ctx: Context<QueryRelayState>) -> Result<RelayHealth> {
+        let relay_state = &ctx.accounts.relay_state;
+        Ok(RelayHealth {
+            best_height: relay_state.best_height,
+            best_timestamp: relay_state.best_timestamp,
+            chain_counter: relay_state.chain_counter,
+            total_headers: relay_state.total_headers,
+            active_fork_count: relay_state.active_fork_count,
+            paused: relay_state.paused,
+            timestamp_anomaly_count: relay_state.timestamp_anomaly_count,
+            recent_fork_creations: relay_state.recent_fork_creations,
+        })
+    }
+
+    /// Returns how far the main-chain tip is into the current difficulty period, for rendering
+    /// progress toward the next retarget, and when the period started.
+    pub fn get_epoch_progress(ctx: Context<QueryRelayState>) -> Result<EpochProgress> {
+        Ok(epoch_progress(&ctx.accounts.relay_state))
+    }
+
+    /// Returns how many more main-chain blocks the block at `height` needs to become final, out
+    /// of reach of any reorg (see `finalized_height`), or 0 if it already is. Computed from the
+    /// tip's height alone, for showing users how long until a deposit is irreversible.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if `height` is above the main-chain tip
+    pub fn blocks_until_final(ctx: Context<QueryRelayState>, height: u32) -> Result<u32> {
+        Ok(confirmations_until_final(ctx.accounts.relay_state.best_height, height).ok_or(RelayError::BlockNotFound)?)
+    }
+
+    /// Returns the whole relay state in one borsh payload, for off-chain snapshots. See
+    /// `StateSnapshot` for how to collect the headers and forks.
+    pub fn get_state_snapshot(ctx: Context<QueryRelayState>) -> Result<StateSnapshot> {
+        Ok(StateSnapshot::from(&*ctx.accounts.relay_state))
+    }
+
+    /// Pauses or resumes header submission. Only callable by the relay authority.
+    pub fn set_paused(ctx: Context<SetConfig>, paused: bool) -> Result<()> {
+        ctx.accounts.relay_state.paused = paused;
+        emit_admin_action(ADMIN_SET_PAUSED, ctx.accounts.authority.key())
+    }
+
+    /// Sets the lamport bond deposited by the submitter of every new fork. Only callable by
+    /// the relay authority; 0 disables the bond.
+    pub fn set_fork_bond(ctx: Context<SetConfig>, fork_bond: u64) -> Result<()> {
+        ctx.accounts.relay_state.fork_bond = fork_bond;
+        emit_admin_action(ADMIN_SET_FORK_BOND, ctx.accounts.authority.key())
+    }
+
+    /// Restricts accepted header versions to `[min_version, max_version]`, a cheap guard against
+    /// relaying headers of the wrong network. Only callable by the relay authority; the range
+    /// is unrestricted after `initialize`.
+    pub fn set_version_range(ctx: Context<SetConfig>, min_version: u32, max_version: u32) -> Result<()> {
+        require!(min_version <= max_version, RelayError::InvalidVersion);
+        let relay_state = &mut ctx.accounts.relay_state;
+        relay_state.min_version = min_version;
+        relay_state.max_version = max_version;
+        emit_admin_action(ADMIN_SET_VERSION_RANGE, ctx.accounts.authority.key())
+    }
+
+    /// Sets the oracle allowed to publish the required target for this relay instance, used when
+    /// it was initialized in oracle mode. Each epoch has its own oracle. Only callable by the relay
+    /// authority.
+    pub fn set_oracle(ctx: Context<SetOracle>, oracle: Pubkey) -> Result<()> {
+        ctx.accounts.oracle_config.oracle = oracle;
+        emit_admin_action(ADMIN_SET_ORACLE, ctx.accounts.authority.key())
+    }
+
+    /// Trusts the targets of every block up to `height`, the height of the `assumevalid` block,
+    /// like Bitcoin Core's `-assumevalid`. Only callable by the relay authority; a zero
+    /// `assumevalid` turns it off.
+    ///
+    /// Headers at or below `height` skip the retarget validation of `check_target` (the
+    /// period's target, or the recomputed retarget at a period start) but keep every other
+    /// check, linkage and their hash meeting the target in their `bits` included. This saves
+    /// compute when syncing a long history, at the cost of trusting the authority that no
+    /// header below the block claims a target the network never required. A header claiming an
+    /// easier target only carries the work of that target, so it cannot overtake a chain
+    /// mined at the real difficulty, but proofs against it are only as good as the authority.
+    ///
+    /// Every header submitted at `height`, on the main chain or a fork, must be the
+    /// `assumevalid` block, so no chain passes that height on trusted targets other than the
+    /// one the authority vouched for. Forks below `height` that have not reached it yet are
+    /// trusted too. Set it above the tip: a main chain already past `height` is not re-checked.
+    pub fn set_assumevalid(ctx: Context<SetConfig>, assumevalid: [u8; 32], height: u32) -> Result<()> {
+        let relay_state = &mut ctx.accounts.relay_state;
+        relay_state.assumevalid = assumevalid;
+        relay_state.assumevalid_height = height;
+        emit_admin_action(ADMIN_SET_ASSUMEVALID, ctx.accounts.authority.key())
+    }
+
+    /// Promotes the fork `chain_id`, whose tip is `tip_hash` at `tip_height`, to the main chain
+    /// without comparing its work against the main chain. Only callable by the relay authority.
+    ///
+    /// A break-glass tool for a relay stuck on the wrong chain: the relay trusts the authority
+    /// that the fork is the heaviest Bitcoin chain, the headers having been validated on
+    /// submission only. The reorg otherwise runs as in `submit_block_header`, with the same
+    /// remaining accounts (see `reorg_chain`), and emits `ChainReorg`.
+    ///
+    /// # Errors
+    ///
+    /// - `Unauthorized` if not signed by the relay authority
+    /// - `ForkNotFound` if `chain_id` is the main chain or has no fork PDA
+    /// - `BlockNotFound` if `tip_hash` at `tip_height` is not the fork's tip
+    /// - `NotChainExtension` if the main chain started a period above the fork tip and the fork
+    ///   does not reach back to the start of its tip's period, see `fork_overtakes`
+    /// - `BelowFinality` if the fork branches at or below the finalized height
+    pub fn force_set_main<'info>(
+        ctx: Context<'_, '_, '_, 'info, ForceSetMain<'info>>,
+        chain_id: u32,
+        tip_hash: [u8; 32],
+        tip_height: u32,
+    ) -> Result<()> {
+        require!(chain_id != MAIN_CHAIN_ID, RelayError::ForkNotFound);
+        let fork = load_fork(&ctx.accounts.fork)?;
+        require!(
+            fork.height == tip_height && fork.descendants.last() == Some(&tip_hash),
+            RelayError::BlockNotFound
+        );
+        let rewind_from = tip_height + 1 - fork.descendants.len() as u32;
+        require!(
+            replays_anchors(&ctx.accounts.relay_state, tip_height, rewind_from),
+            RelayError::NotChainExtension
+        );
+
+        let accounts = &mut *ctx.accounts;
+        reorg_chain(
+            &mut accounts.relay_state,
+            &accounts.fork,
+            &mut accounts.header,
+            &mut accounts.chain,
+            &mut accounts.new_fork,
+            &accounts.authority.to_account_info(),
+            &accounts.system_program.to_account_info(),
+            ctx.remaining_accounts,
+            fork,
+            chain_id,
+            tip_height,
+            tip_hash,
+        )?;
+        emit_admin_action(ADMIN_FORCE_SET_MAIN, ctx.accounts.authority.key())
+    }
+
+    /// Publishes the target (little-endian) that oracle-mode submissions must meet. Only
+    /// callable by the configured oracle.
+    pub fn publish_target(ctx: Context<PublishTarget>, target: [u8; 32]) -> Result<()> {
+        let oracle_config = &mut ctx.accounts.oracle_config;
+        oracle_config.target = target;
+        oracle_config.updated_slot = Clock::get()?.slot;
+        Ok(())
+    }
+
+    /// Forfeits the bond of a fork that has not been extended for `FORK_BOND_TIMEOUT` main-chain
+    /// blocks, transferring it to the relay authority. Callable by anyone.
+    ///
+    /// # Errors
+    ///
+    /// - `NoForkBond` if the fork carries no bond (or is the main chain)
+    /// - `ForkBondLocked` if the timeout has not passed yet
+    pub fn forfeit_fork_bond(ctx: Context<ForfeitForkBond>, chain_id: u32) -> Result<()> {
+        let fork = &mut ctx.accounts.fork;
+        require!(chain_id != MAIN_CHAIN_ID && fork.bond > 0, RelayError::NoForkBond);
+        require!(
+            ctx.accounts.relay_state.best_height >= fork.height.saturating_add(FORK_BOND_TIMEOUT),
+            RelayError::ForkBondLocked
+        );
+
+        let fork_info = fork.to_account_info();
+        release_fork_bond(&fork_info, &ctx.accounts.authority, fork)
+    }
+
+    /// Closes a fork that can no longer be promoted, its tip being below `finalized_height`,
+    /// together with the `Header` PDAs of its blocks, and drops it from `active_fork_ids`. The
+    /// rent goes to the relay authority, like a forfeited bond. Callable by anyone.
+    ///
+    /// The fork's `Header` PDAs are passed as remaining accounts in the order of its
+    /// descendants. `BlockHash` PDAs are left alone, they index the main chain.
+    ///
+    /// # Errors
+    ///
+    /// - `ForkNotFound` if `chain_id` is the main chain or not an active fork
+    /// - `ForkNotFinal` if the fork's tip is not below the finalized height
+    /// - `ForkBondLocked` if the fork still carries a bond that cannot be forfeited yet
+    /// - `BlockNotFound` if the headers do not match the fork's blocks
+    pub fn purge_fork(ctx: Context<PurgeFork>, chain_id: u32) -> Result<()> {
+        let relay_state = &mut ctx.accounts.relay_state;
+        let fork = &ctx.accounts.fork;
+        require!(
+            chain_id != MAIN_CHAIN_ID && relay_state.active_fork_ids.contains(&chain_id),
+            RelayError::ForkNotFound
+        );
+        require!(fork.height < relay_state.finalized_height, RelayError::ForkNotFinal);
+        require!(
+            fork.bond == 0 || relay_state.best_height >= fork.height.saturating_add(FORK_BOND_TIMEOUT),
+            RelayError::ForkBondLocked
+        );
+
+        require!(ctx.remaining_accounts.len() == fork.descendants.len(), RelayError::BlockNotFound);
+        for (descendant, header_info) in fork.descendants.iter().zip(ctx.remaining_accounts) {
+            require_keys_eq!(header_info.key(), header_address(relay_state.epoch, descendant), RelayError::BlockNotFound);
+            require!(load_header(header_info)?.chain_id == chain_id, RelayError::BlockNotFound);
+            close_account(header_info, &ctx.accounts.authority)?;
+        }
+
+        relay_state.active_fork_ids.retain(|&id| id != chain_id);
+        relay_state.active_fork_count -= 1;
+        Ok(())
+    }
+
+    /// Extends the main chain with a run of consecutive headers, storing and fully validating
+    /// every one of them, unlike `submit_header_chain`.
+    ///
+    /// Heights follow from linkage: the first header must link to the best block and each
+    /// following one to its predecessor, so a batch out of order or with a gap is rejected
+    /// before anything is stored, leaving the index of the first bad header in the return data
+    /// as a little-endian `u32`.
+    ///
+    /// For each header, its `Header` PDA and the `BlockHash` PDA at its height are passed as
+    /// remaining accounts, in order; they are created as needed and paid for by `user`. The
+    /// transaction account limit caps a batch at `MAX_BATCH_SIZE` headers, and batches of more
+    /// than a few headers only fit a transaction with their PDAs in an address lookup table.
+    ///
+    /// Running out of compute units would abort the whole batch, so before every header but
+    /// the first the remaining budget is checked against `BATCH_HEADER_CU` plus the
+    /// `BATCH_FINISH_CU` needed to wrap up. When it runs low the batch stops there, keeping the
+    /// headers stored so far. Returns the number of headers stored; the caller resubmits the
+    /// rest, or raises the limit with `ComputeBudgetInstruction::set_compute_unit_limit`.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidHeaderBatch` if the batch is empty, longer than `MAX_BATCH_SIZE`, or does not link
+    /// - `BlockNotFound` if the remaining accounts are not the PDAs of the headers
+    /// - `DuplicateBlock` if a header is already stored
+    /// - Any validation error of `submit_block_header`
+    pub fn submit_block_header_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, SubmitBlockHeaderBatch<'info>>,
+        headers: Vec<[u8; 80]>,
+    ) -> Result<u32> {
+        require!(!headers.is_empty(), RelayError::InvalidHeaderBatch);
+        check_batch_size(headers.len())?;
+        let oracle_target = oracle_target(&ctx.accounts.relay_state, &ctx.accounts.oracle_config)?;
+        let accounts = &mut *ctx.accounts;
+        let prev_header = (*accounts.prev_header).clone();
+        _store_main_chain_run(
+            &mut accounts.relay_state,
+            &mut accounts.main_fork,
+            &prev_header,
+            &headers,
+            ctx.remaining_accounts,
+            oracle_target,
+            &accounts.user.to_account_info(),
+            &accounts.system_program.to_account_info(),
+        )
+    }
+
+    /// Verifies that `txid` is included at position `index` of the main-chain block at `height`.
+    ///
+    /// A block counts as one confirmation for itself, so a transaction in the current tip has
+    /// exactly 1 confirmation. Unless `insecure` is set, the block must have at least
+    /// `confirmations` confirmations.
+    ///
+    /// Like every instruction returning a value, the result is serialized with `set_return_data`.
+    /// A program verifying through CPI calls `relayer::cpi::verify_tx` (with the `cpi` feature)
+    /// and then reads the result with `get_return_data`, checking the returned program id is
+    /// the relayer's; `programs/relay-consumer` does exactly that.
+    ///
+    /// # Arguments
+    ///
+    /// * `height` - The height of the block containing the transaction
+    /// * `index` - The position of the transaction in the block
+    /// * `txid` - The transaction id, in internal byte order
+    /// * `header` - The 80-byte header of the block at `height`
+    /// * `proof` - The merkle branch, concatenated 32-byte siblings from the leaf upwards
+    /// * `confirmations` - The minimum number of confirmations required
+    /// * `insecure` - Skips the confirmations check
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidTxId` if `txid` is zero
+    /// - `BlockNotFound` if `header` is not the main-chain block at `height`
+    /// - `InsufficientConfirmations` if the block is not buried deep enough
+    /// - `IncorrectMerkleProof` if the proof does not lead to the header's merkle root or
+    ///   `index` does not fit its depth (`index >= 2^(proof.len() / 32)`)
+    pub fn verify_tx(ctx: Context<VerifyTx>, height: u32, index: u32, txid: [u8; 32], header: [u8; 80], proof: Vec<u8>, confirmations: u64, insecure: bool) -> Result<bool> {
+        require!(txid != [0u8; 32], RelayError::InvalidTxId);
+        let confirmations = if insecure { 0 } else { confirmations };
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, confirmations)?;
+        check_tx_inclusion(&header, txid, index, &proof)?;
+        Ok(true)
+    }
+
+    /// `verify_tx` reporting why a transaction fails to verify instead of aborting, to debug
+    /// bridge integrations without scraping logs. The `VerifyResult` is returned through
+    /// `set_return_data` like any other result; its `reason` is 0 on success and a
+    /// `VerifyFailure` code otherwise, the first failing check in the order
+    /// `BlockNotFound`, `NotMainChain`, `TooFewConfirmations`, `ProofMismatch`.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidTxId` if `txid` is zero
+    pub fn verify_tx_with_reason(
+        ctx: Context<VerifyTxWithReason>,
+        height: u32,
+        index: u32,
+        txid: [u8; 32],
+        header: [u8; 80],
+        proof: Vec<u8>,
+        confirmations: u64,
+    ) -> Result<VerifyResult> {
+        require!(txid != [0u8; 32], RelayError::InvalidTxId);
+        let stored_hash = load_block_hash(&ctx.accounts.chain).ok().map(|chain| chain.block_hash);
+        let failure = verify_failure(&ctx.accounts.relay_state, stored_hash, height, &header, txid, index, &proof, confirmations);
+        Ok(VerifyResult::from(failure))
+    }
+
+    /// `verify_tx` for programs calling the relay through CPI: verification is read-only, so
+    /// only the relay state and the `BlockHash` PDA at `height` are required, no signer. The
+    /// confirmations check cannot be skipped.
+    ///
+    /// The result only holds for the chain of the relay state passed. Anyone can initialize an
+    /// instance under a new epoch from made-up headers, so callers must pin the relay state they
+    /// trust (its address, or its seeds with a fixed epoch) rather than accept any `RelayState`.
+    ///
+    /// # Errors
+    ///
+    /// Any error of `verify_tx`
+    pub fn verify_tx_cpi(ctx: Context<VerifyTxCpi>, height: u32, index: u32, txid: [u8; 32], header: [u8; 80], proof: Vec<u8>, confirmations: u64) -> Result<bool> {
+        require!(txid != [0u8; 32], RelayError::InvalidTxId);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, confirmations)?;
+        check_tx_inclusion(&header, txid, index, &proof)?;
+        Ok(true)
+    }
+
+    /// Verifies a transaction as `verify_tx` does and, on success, calls `consumer_program` in
+    /// the same transaction, so a bridge can prove and act (mint, unlock) atomically.
+    ///
+    /// The callback's instruction data is `callback_data` followed by the verification result
+    /// as a borsh `bool`, so for an Anchor consumer `callback_data` is the instruction
+    /// discriminator and every argument but a trailing `verified: bool`. Its accounts are the
+    /// relay's `callback_authority` PDA (seeds `["callback", relay_state]`) as a signer, then
+    /// `relay_state`, followed by the remaining accounts. The consumer must check both: the
+    /// signer proves the call comes from the relay, the relay state which instance verified it,
+    /// and anyone can initialize an instance with a made-up chain.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidCallbackProgram` if `consumer_program` does not match the account passed or is
+    ///   the relay itself
+    /// - Any error of `verify_tx`, or of the callback
+    #[allow(clippy::too_many_arguments)]
+    pub fn verify_and_invoke<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyAndInvoke<'info>>,
+        height: u32,
+        index: u32,
+        txid: [u8; 32],
+        header: [u8; 80],
+        proof: Vec<u8>,
+        confirmations: u64,
+        consumer_program: Pubkey,
+        callback_data: Vec<u8>,
+    ) -> Result<()> {
+        require_keys_eq!(ctx.accounts.consumer_program.key(), consumer_program, RelayError::InvalidCallbackProgram);
+        require_keys_neq!(consumer_program, ID, RelayError::InvalidCallbackProgram);
+        require!(txid != [0u8; 32], RelayError::InvalidTxId);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, confirmations)?;
+        check_tx_inclusion(&header, txid, index, &proof)?;
+
+        let callback_authority = ctx.accounts.callback_authority.to_account_info();
+        let relay_state = ctx.accounts.relay_state.to_account_info();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(callback_authority.key(), true),
+            AccountMeta::new_readonly(relay_state.key(), false),
+        ];
+        let mut infos = vec![callback_authority, relay_state];
+        for info in ctx.remaining_accounts {
+            accounts.push(if info.is_writable {
+                AccountMeta::new(info.key(), info.is_signer)
+            } else {
+                AccountMeta::new_readonly(info.key(), info.is_signer)
+            });
+            infos.push(info.clone());
+        }
+        infos.push(ctx.accounts.consumer_program.to_account_info());
+
+        // the trailing borsh `bool` result is always true here, a failed verification aborts
+        let mut data = callback_data;
+        data.push(1);
+        invoke_signed(
+            &Instruction { program_id: consumer_program, accounts, data },
+            &infos,
+            &[&[b"callback", ctx.accounts.relay_state.key().as_ref(), &[ctx.bumps.callback_authority]]],
+        )?;
+        Ok(())
+    }
+
+    /// Starts a verification whose merkle proof is too long to fit a transaction next to the
+    /// header. The proof is then folded in chunks by `verify_tx_step` and checked against the
+    /// header's root by `verify_tx_finalize`, all signed by the same `caller`.
+    ///
+    /// The intermediate state lives in the caller's `PartialVerification` PDA, so a caller has
+    /// one verification in progress at a time; `verify_tx_cancel` abandons it.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidTxId` if `txid` is zero
+    /// - `BlockNotFound` if `header` is not the main-chain block at `height`
+    /// - `IncorrectMerkleProof` if the header's merkle root is zero
+    pub fn verify_tx_begin(ctx: Context<VerifyTxBegin>, height: u32, index: u32, txid: [u8; 32], header: [u8; 80], confirmations: u64) -> Result<()> {
+        require!(txid != [0u8; 32], RelayError::InvalidTxId);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, 0)?;
+        let merkle_root = extract_merkle_root(&header);
+        require!(merkle_root != [0u8; 32], RelayError::IncorrectMerkleProof);
+
+        let partial = &mut ctx.accounts.partial;
+        partial.caller = ctx.accounts.caller.key();
+        partial.height = height;
+        partial.block_hash = ctx.accounts.chain.block_hash;
+        partial.merkle_root = merkle_root;
+        partial.confirmations = confirmations;
+        partial.current = txid;
+        partial.index = index;
+        Ok(())
+    }
+
+    /// Folds the next levels of the merkle proof, concatenated 32-byte siblings continuing
+    /// upwards from the previous chunk.
+    pub fn verify_tx_step(ctx: Context<VerifyTxStep>, proof_chunk: Vec<u8>) -> Result<()> {
+        let partial = &mut ctx.accounts.partial;
+        partial.current = compute_merkle_root(partial.current, partial.index, &proof_chunk)?;
+        partial.index = partial.index.checked_shr((proof_chunk.len() / 32) as u32).unwrap_or(0);
+        Ok(())
+    }
+
+    /// Completes the verification begun by `verify_tx_begin` and closes the caller's
+    /// `PartialVerification` PDA. The block must still be on the main chain with the requested
+    /// confirmations.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if a reorg replaced the block
+    /// - `InsufficientConfirmations` if the block is not buried deep enough yet
+    /// - `IncorrectMerkleProof` if the folded proof does not lead to the header's merkle root
+    pub fn verify_tx_finalize(ctx: Context<VerifyTxFinalize>) -> Result<bool> {
+        let partial = &ctx.accounts.partial;
+        require!(ctx.accounts.chain.block_hash == partial.block_hash, RelayError::BlockNotFound);
+        let block_confirmations = block_confirmations(ctx.accounts.relay_state.best_height, partial.height).ok_or(RelayError::BlockNotFound)?;
+        require!(block_confirmations as u64 >= partial.confirmations, RelayError::InsufficientConfirmations);
+        // every level is folded, so the index must be used up like in `check_tx_inclusion`
+        require!(partial.index == 0 && partial.current == partial.merkle_root, RelayError::IncorrectMerkleProof);
+        Ok(true)
+    }
+
+    /// Abandons the caller's verification in progress and closes its `PartialVerification` PDA.
+    pub fn verify_tx_cancel(_ctx: Context<VerifyTxCancel>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Verifies that two transactions are both included in the same main-chain block at
+    /// `height`, which has at least `confirmations` confirmations. Each transaction is proven
+    /// as in `verify_tx`; the call fails unless both proofs hold.
+    ///
+    /// # Errors
+    ///
+    /// Any error of `verify_tx`, for either transaction
+    #[allow(clippy::too_many_arguments)]
+    pub fn verify_both_in_block(
+        ctx: Context<VerifyTx>,
+        height: u32,
+        header: [u8; 80],
+        txid_a: [u8; 32],
+        index_a: u32,
+        proof_a: Vec<u8>,
+        txid_b: [u8; 32],
+        index_b: u32,
+        proof_b: Vec<u8>,
+        confirmations: u64,
+    ) -> Result<bool> {
+        require!(txid_a != [0u8; 32] && txid_b != [0u8; 32], RelayError::InvalidTxId);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, confirmations)?;
+        check_tx_inclusion(&header, txid_a, index_a, &proof_a)?;
+        check_tx_inclusion(&header, txid_b, index_b, &proof_b)?;
+        Ok(true)
+    }
+
+    /// Verifies a backlog of transaction proofs spanning different blocks in one call, returning
+    /// one result per entry: `true` if the entry would pass `verify_tx`, `false` otherwise.
+    ///
+    /// The `BlockHash` PDA at each entry's height must be supplied as remaining accounts, in
+    /// entry order.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if the remaining accounts do not line up with the entries
+    pub fn verify_tx_multi_block(ctx: Context<QueryRelayState>, entries: Vec<TxProofEntry>) -> Result<Vec<bool>> {
+        require!(ctx.remaining_accounts.len() == entries.len(), RelayError::BlockNotFound);
+
+        let mut results = Vec::with_capacity(entries.len());
+        for (entry, info) in entries.iter().zip(ctx.remaining_accounts) {
+            require_keys_eq!(info.key(), chain_address(ctx.accounts.relay_state.epoch, entry.height), RelayError::BlockNotFound);
+            let verified = entry.txid != [0u8; 32]
+                && load_block_hash(info).is_ok_and(|chain| {
+                    check_block_depth(&ctx.accounts.relay_state, &chain, entry.height, &entry.header, entry.confirmations).is_ok()
+                        && check_tx_inclusion(&entry.header, entry.txid, entry.index, &entry.proof).is_ok()
+                });
+            results.push(verified);
+        }
+        Ok(results)
+    }
+
+    /// Verifies that `target_txid` sits at `target_index` in the main-chain block at `height` by
+    /// rebuilding its merkle root from the block's full list of `txids`, at least `CONFIRMATIONS`
+    /// deep. Costlier than `verify_tx` and capped at `MAX_BLOCK_TXIDS`, meant for small blocks
+    /// and testing.
+    ///
+    /// # Errors
+    ///
+    /// - `IncorrectMerkleProof` if `txids` is empty, too long, does not commit to the header's
+    ///   root or does not hold `target_txid` at `target_index`
+    /// - `BlockNotFound` if `header` is not the main-chain block at `height`
+    /// - `InsufficientConfirmations` if the block is not deep enough
+    pub fn verify_tx_full_block(
+        ctx: Context<VerifyTx>,
+        height: u32,
+        header: [u8; 80],
+        txids: Vec<[u8; 32]>,
+        target_txid: [u8; 32],
+        target_index: u32,
+    ) -> Result<bool> {
+        require!(!txids.is_empty() && txids.len() <= MAX_BLOCK_TXIDS, RelayError::IncorrectMerkleProof);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, CONFIRMATIONS as u64)?;
+
+        require!(txids.get(target_index as usize) == Some(&target_txid), RelayError::IncorrectMerkleProof);
+        require!(merkle_root_of(&txids) == extract_merkle_root(&header), RelayError::IncorrectMerkleProof);
+
+        Ok(true)
+    }
+
+    /// Proves that `absent_txid` is not in the main-chain block at `height`, at least
+    /// `CONFIRMATIONS` deep, from the block's full list of `txids`: returns `true` if the list
+    /// rebuilds the header's merkle root and does not hold `absent_txid`.
+    ///
+    /// A list of inner tree nodes rebuilds the same root while hiding the transactions below
+    /// them, so `coinbase_tx`, the coinbase in its non-witness serialization, must hash to
+    /// `txids[0]`. A real leaf at the start pins the list to the leaf level of the tree; the
+    /// coinbase must not be 64 bytes long, the one size at which it could pass for an inner node.
+    ///
+    /// # Errors
+    ///
+    /// - `IncorrectMerkleProof` if `txids` is empty, too long or does not commit to the
+    ///   header's root
+    /// - `InvalidCoinbase` if `coinbase_tx` is malformed, 64 bytes long or not `txids[0]`
+    /// - `BlockNotFound` if `header` is not the main-chain block at `height`
+    /// - `InsufficientConfirmations` if the block is not deep enough
+    pub fn prove_absence(
+        ctx: Context<VerifyTx>,
+        height: u32,
+        header: [u8; 80],
+        txids: Vec<[u8; 32]>,
+        absent_txid: [u8; 32],
+        coinbase_tx: Vec<u8>,
+    ) -> Result<bool> {
+        require!(!txids.is_empty() && txids.len() <= MAX_BLOCK_TXIDS, RelayError::IncorrectMerkleProof);
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, CONFIRMATIONS as u64)?;
+
+        coinbase_script(&coinbase_tx)?;
+        require!(coinbase_tx.len() != 64 && hash256(&coinbase_tx) == txids[0], RelayError::InvalidCoinbase);
+        require!(merkle_root_of(&txids) == extract_merkle_root(&header), RelayError::IncorrectMerkleProof);
+
+        Ok(!txids.contains(&absent_txid))
+    }
+
+    /// Verifies the BIP34 height commitment of the main-chain block at `height`: returns `true`
+    /// if `coinbase_tx`, proven at index 0 by `coinbase_proof`, pushes `height` at the start of
+    /// its scriptSig (see `parse_bip34_height`).
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if `header` is not the main-chain block at `height`
+    /// - `IncorrectMerkleProof` if `coinbase_proof` does not lead to the header's merkle root
+    /// - `InvalidCoinbase` if `coinbase_tx` is malformed, 64 bytes long or commits to another height
+    pub fn verify_coinbase_height(
+        ctx: Context<VerifyTx>,
+        height: u32,
+        header: [u8; 80],
+        coinbase_tx: Vec<u8>,
+        coinbase_proof: Vec<u8>,
+    ) -> Result<bool> {
+        check_block_depth(&ctx.accounts.relay_state, &ctx.accounts.chain, height, &header, 0)?;
+        require!(coinbase_tx.len() != 64, RelayError::InvalidCoinbase);
+        check_tx_inclusion(&header, hash256(&coinbase_tx), 0, &coinbase_proof)?;
+
+        let committed = parse_bip34_height(coinbase_script(&coinbase_tx)?)?;
+        require!(committed == height, RelayError::InvalidCoinbase);
+        Ok(true)
+    }
+
+    /// Returns how many confirmations the main-chain block at `height` has, the tip counting
+    /// as one, or 0 when no main-chain block is stored at `height`.
+    pub fn confirmations_at(ctx: Context<ConfirmationsAt>, height: u32) -> Result<u32> {
+        if load_block_hash(&ctx.accounts.chain).is_err() {
+            return Ok(0);
+        }
+        Ok(block_confirmations(ctx.accounts.relay_state.best_height, height).unwrap_or(0))
+    }
+
+    /// Requires the main-chain block stored at `height` to be `expected_hash`, a cheap integrity
+    /// self-test operators can run against well-known checkpoints.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if no main-chain block is stored at `height`
+    /// - `CheckpointMismatch` if the stored block hash differs from `expected_hash`
+    pub fn assert_checkpoint(ctx: Context<AssertCheckpoint>, _height: u32, expected_hash: [u8; 32]) -> Result<()> {
+        let block_hash = load_block_hash(&ctx.accounts.chain)?.block_hash;
+        require!(block_hash == expected_hash, RelayError::CheckpointMismatch);
+        Ok(())
+    }
+
+    /// Returns the work accumulated on the main chain after the block at `height`, as a
+    /// little-endian 256-bit integer: the difficulty-weighted counterpart of confirmations, so
+    /// bridges can require a minimum work depth whatever the difficulty.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if no main-chain block is stored at `height` or `header` is not its
+    ///   `Header` PDA
+    pub fn work_since_height(ctx: Context<WorkSinceHeight>, height: u32) -> Result<[u8; 32]> {
+        let chain = load_block_hash(&ctx.accounts.chain)?;
+        let header = resolve_header_at(ctx.accounts.relay_state.epoch, &chain, &ctx.accounts.header, height)?;
+        let work = work_since(u256_from_le_bytes(&ctx.accounts.relay_state.chain_work), &header)?;
+        Ok(u256_to_le_bytes(work))
+    }
+
+    /// Audits the height index: returns whether the `BlockHash` PDA at `height` and the supplied
+    /// `Header` PDA agree, i.e. the header is the one the index points to and is stored as the
+    /// main-chain block at `height`.
+    pub fn verify_consistency(ctx: Context<VerifyConsistency>, height: u32) -> Result<bool> {
+        Ok(resolve_header_at(ctx.accounts.relay_state.epoch, &ctx.accounts.chain, &ctx.accounts.header, height).is_ok())
+    }
+
+    /// Audits the tip: returns whether `best_block` has a stored `Header` PDA on the main chain
+    /// at `best_height`. Builds with the `check-invariants` feature assert the same after every
+    /// instruction that moves the tip.
+    pub fn audit_tip(ctx: Context<AuditTip>) -> Result<bool> {
+        let relay_state = &ctx.accounts.relay_state;
+        Ok(load_header(&ctx.accounts.header).is_ok_and(|header| is_tip(relay_state, relay_state.best_block, &header)))
+    }
+
+    /// Returns the hash and stored metadata of the main-chain block at `height` in one call.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if no main-chain block is stored at `height` or `header` is not its
+    ///   `Header` PDA
+    pub fn get_block_at_height(ctx: Context<BlockAtHeight>, height: u32) -> Result<BlockView> {
+        let chain = load_block_hash(&ctx.accounts.chain)?;
+        let header = resolve_header_at(ctx.accounts.relay_state.epoch, &chain, &ctx.accounts.header, height)?;
+        Ok(BlockView {
+            block_hash: chain.block_hash,
+            chain_id: header.chain_id,
+            timestamp: header.timestamp,
+            bits: header.bits,
+        })
+    }
+
+    /// Returns the chain id of the branch occupying `height`, or 0 if no stored block does.
+    ///
+    /// Heights indexed by the `BlockHash` PDA belong to the main chain and `header` must be the
+    /// indexed header. Above the main tip only forks reach a height, so there the branch of the
+    /// supplied `header` is returned if it is stored at `height`.
+    ///
+    /// # Errors
+    ///
+    /// - `BlockNotFound` if `header` is not the `Header` PDA indexed at `height`
+    pub fn chain_id_at_height(ctx: Context<BlockAtHeight>, height: u32) -> Result<u32> {
+        let indexed = load_block_hash(&ctx.accounts.chain)
+            .ok()
+            .map(|chain| chain.block_hash)
+            .filter(|hash| *hash != [0u8; 32]);
+        if let Some(block_hash) = indexed {
+            require_keys_eq!(ctx.accounts.header.key(), header_address(ctx.accounts.relay_state.epoch, &block_hash), RelayError::BlockNotFound);
+        }
+        Ok(load_header(&ctx.accounts.header)
+            .ok()
+            .filter(|header| header.height == height)
+            .map_or(0, |header| header.chain_id))
+    }
+
+    /// Returns the cumulative proof-of-work of the main chain, counted from the genesis
+    /// block the relay was initialized with, as a little-endian 256-bit integer.
+    pub fn get_total_work(ctx: Context<QueryRelayState>) -> Result<[u8; 32]> {
+        Ok(ctx.accounts.relay_state.chain_work)
+    }
+
+    /// Returns whether the main chain has accumulated at least `min_work` (little-endian
+    /// 256-bit integer), letting integrators gate their logic on the relay having enough
+    /// proof-of-work behind it.
+    pub fn is_synced(ctx: Context<QueryRelayState>, min_work: [u8; 32]) -> Result<bool> {
+        let chain_work = u256_from_le_bytes(&ctx.accounts.relay_state.chain_work);
+        Ok(chain_work >= u256_from_le_bytes(&min_work))
+    }
+
+    /// Returns the BIP113 median-time-past at `height`: the median timestamp of the block at
+    /// `height` on chain `chain_id` and its 10 predecessors.
+    ///
+    /// The `Header` accounts must be supplied as remaining accounts, ordered from `height`
+    /// downwards. Fewer than 11 are only accepted when the chain itself has fewer blocks
+    /// (`height < 10`); ancestors that were never relayed yield `BlockNotFound`.
+    ///
+    /// For a fork, its `Fork` PDA comes first and the headers follow the fork's own ancestry:
+    /// its descendants down to the fork point, then its ancestor and the main chain below it.
+    /// Only forks branching off the main chain are supported.
+    pub fn get_median_time_past(ctx: Context<QueryRelayState>, height: u32, chain_id: u32) -> Result<u32> {
+        let mut headers = ctx.remaining_accounts;
+        let fork = if chain_id == MAIN_CHAIN_ID {
+            None
+        } else {
+            let (fork_info, rest) = headers.split_first().ok_or(RelayError::ForkNotFound)?;
+            require_keys_eq!(fork_info.key(), fork_address(ctx.accounts.relay_state.epoch, chain_id), RelayError::ForkNotFound);
+            headers = rest;
+            Some(load_fork(fork_info)?)
+        };
+
+        let span = MEDIAN_TIME_SPAN.min(height.saturating_add(1)) as usize;
+        require!(headers.len() == span, RelayError::BlockNotFound);
+
+        let mut timestamps = Vec::with_capacity(span);
+        for (i, info) in headers.iter().enumerate() {
+            let header = match &fork {
+                Some(fork) => load_fork_header_at(info, fork, ctx.accounts.relay_state.epoch, chain_id, height - i as u32)?,
+                None => load_main_header_at(info, height - i as u32)?,
+            };
+            timestamps.push(header.timestamp);
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    // Add other functions as needed
+}
+
+/// Promotes the fork `chain_id`, whose tip is the block at `height`, to the main chain and
+/// demotes the main-chain blocks it replaces into a new fork.
+///
+/// Only forks branching directly off the main chain can be promoted, or off a fork that does:
+/// a fork whose ancestor an earlier reorg demoted first takes over the blocks of that parent
+/// fork up to its ancestor (see `adopt_parent_blocks`), the parent keeping the rest as a fork
+/// of the promoted chain. The accounts touched by the reorg are passed as remaining accounts,
+/// in order:
+/// - the main chain `Fork` PDA
+/// - if the fork's ancestor was demoted, its `Header` PDA and the parent fork's `Fork` PDA
+/// - the `BlockHash` PDA at the fork point (the height of `fork.ancestor`, the parent fork's
+///   ancestor if the fork's own was demoted)
+/// - for every fork block below `height`, ascending: its `BlockHash` PDA, its `Header` PDA and,
+///   while the height is not above the old tip, the `Header` PDA of the main-chain block it replaces
+/// - unless the old tip is below `height`, the `Header` PDA of the main-chain block at `height`
+/// - for every height above `height` up to the old tip, ascending: its `BlockHash` PDA and the
+///   `Header` PDA of the main-chain block there, as a fork with more work can end below the old tip
+/// - the depositor of the fork's bond, if it carries one, to refund it
+///
+/// The epoch anchors are replayed from the promoted headers, so retargets after the reorg are
+/// validated against the new chain's period boundaries. A fork branching at or below
+/// `finalized_height` is rejected with `BelowFinality`. `new_fork` grows to hold every demoted
+/// block, `payer` topping up its rent.
+///
+/// Emits `ChainReorg` with `rewind_from` set to the lowest rewritten height; any proof against
+/// a block at or above it must be re-checked. A `HeightReorged` per rewritten height follows,
+/// so indexers can swap the hash at each height without re-reading the chain.
+#[allow(clippy::too_many_arguments)]
+fn reorg_chain<'info>(
+    relay_state: &mut Account<'info, RelayState>,
+    fork_info: &AccountInfo<'info>,
+    tip_header: &mut Account<'info, Header>,
+    tip_chain: &mut Account<'info, BlockHash>,
+    new_fork: &mut Account<'info, Fork>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    mut fork: Fork,
+    chain_id: u32,
+    height: u32,
+    hash_curr_block: [u8; 32],
+) -> Result<()> {
+    let old_best_block = relay_state.best_block;
+    let old_best_height = relay_state.best_height;
+    let epoch = relay_state.epoch;
+    let fork_id = relay_state.chain_counter + 1;
+
+    let mut accounts = remaining_accounts.iter().peekable();
+    let main_fork_info = accounts.next().ok_or(RelayError::BlockNotFound)?;
+    require_keys_eq!(main_fork_info.key(), fork_address(epoch, MAIN_CHAIN_ID), RelayError::ForkNotFound);
+    let mut main_fork = load_fork(main_fork_info)?;
+
+    // a fork branching off a block demoted by an earlier reorg first takes that block's branch over
+    let ancestor_address = header_address(epoch, &fork.ancestor);
+    let adopted = match accounts.next_if(|info| info.key() == ancestor_address) {
+        Some(ancestor_info) => {
+            let parent_id = load_header(ancestor_info)?.chain_id;
+            require!(parent_id != MAIN_CHAIN_ID && parent_id != chain_id, RelayError::NestedFork);
+            let parent_info = accounts.next().ok_or(RelayError::ForkNotFound)?;
+            require_keys_eq!(parent_info.key(), fork_address(epoch, parent_id), RelayError::ForkNotFound);
+            let mut parent = load_fork(parent_info)?;
+            let adopted = adopt_parent_blocks(&mut fork, &mut parent)?;
+            store_account(parent_info, &parent)?;
+            Some((parent_id, adopted))
+        }
+        None => None,
+    };
+
+    let rewind_from = height + 1 - fork.descendants.len() as u32;
+    require!(rewind_from > relay_state.finalized_height, RelayError::BelowFinality);
+    // the walk back ends at the fork point, at the lowest the genesis block
+    require!(rewind_from > relay_state.genesis_height, RelayError::NotChainExtension);
+
+    let mut next_account = || accounts.next().ok_or_else(|| error!(RelayError::BlockNotFound));
+    let fork_point_info = next_account()?;
+    require_keys_eq!(fork_point_info.key(), chain_address(epoch, rewind_from - 1), RelayError::BlockNotFound);
+    require!(load_block_hash(fork_point_info)?.block_hash == fork.ancestor, RelayError::NotChainExtension);
+
+    let mut demoted = Vec::new();
+    for (i, descendant) in fork.descendants[..fork.descendants.len() - 1].iter().enumerate() {
+        let descendant_height = rewind_from + i as u32;
+
+        let chain_info = next_account()?;
+        require_keys_eq!(chain_info.key(), chain_address(epoch, descendant_height), RelayError::BlockNotFound);
+        let mut chain = load_block_hash(chain_info)?;
+
+        // promote header to main chain
+        let header_info = next_account()?;
+        require_keys_eq!(header_info.key(), header_address(epoch, descendant), RelayError::BlockNotFound);
+        let mut header = load_header(header_info)?;
+        let owner_id = match adopted {
+            Some((parent_id, adopted)) if i < adopted => parent_id,
+            _ => chain_id,
+        };
+        require!(header.height == descendant_height && header.chain_id == owner_id, RelayError::BlockNotFound);
+        header.chain_id = MAIN_CHAIN_ID;
+        store_account(header_info, &header)?;
+        update_epoch(relay_state, descendant_height, bits_to_target(header.bits)?, header.timestamp);
+        record_main_timestamp(relay_state, descendant_height, header.timestamp);
+
+        // demote old header to new fork
+        if descendant_height <= old_best_height {
+            demoted.push(demote_header_at(epoch, &chain, next_account()?, descendant_height, fork_id)?);
+        }
+
+        // swap header at height
+        chain.block_hash = *descendant;
+        store_account(chain_info, &chain)?;
+    }
+
+    // the rest of the old main chain, from the new tip's height up
+    if height <= old_best_height {
+        demoted.push(demote_header_at(epoch, tip_chain, next_account()?, height, fork_id)?);
+    }
+    for old_height in height + 1..=old_best_height {
+        let chain_info = next_account()?;
+        require_keys_eq!(chain_info.key(), chain_address(epoch, old_height), RelayError::BlockNotFound);
+        demoted.push(demote_header_at(epoch, &load_block_hash(chain_info)?, next_account()?, old_height, fork_id)?);
+    }
+
+    // the absorbed fork was not spam, refund its bond
+    if fork.bond > 0 {
+        let owner_info = next_account()?;
+        require_keys_eq!(owner_info.key(), fork.bond_owner, RelayError::Unauthorized);
+        release_fork_bond(fork_info, owner_info, &mut fork)?;
+        // adopted parent blocks may not fit the account as sized for the fork's own
+        grow_fork(fork_info, &fork, fork.descendants.len(), payer, system_program)?;
+        store_account(fork_info, &fork)?;
+    }
+
+    // extend to current head
+    tip_header.chain_id = MAIN_CHAIN_ID;
+    tip_chain.block_hash = hash_curr_block;
+    let tip_target = bits_to_target(tip_header.bits)?;
+    update_epoch(relay_state, height, tip_target, tip_header.timestamp);
+    record_main_timestamp(relay_state, height, tip_header.timestamp);
+    // the old main chain may have ended the new tip's period, which the new chain has not
+    let period_end = height - height % DIFFICULTY_ADJUSTMENT_INTERVAL + DIFFICULTY_ADJUSTMENT_INTERVAL - 1;
+    if height < period_end && period_end <= old_best_height {
+        relay_state.epoch_end_target = String::new();
+        relay_state.epoch_end_time = 0;
+    }
+
+    main_fork.height = height;
+    store_account(main_fork_info, &main_fork)?;
+    let swaps = height_swaps(rewind_from, &demoted, &fork.descendants);
+
+    // every main-chain block above the fork point is demoted, however deep the reorg
+    grow_fork(&new_fork.to_account_info(), new_fork, demoted.len(), payer, system_program)?;
+    new_fork.height = old_best_height;
+    new_fork.ancestor = fork.ancestor;
+    new_fork.descendants = demoted;
+
+    // the promoted fork is now the main chain, the old main chain lives on as `fork_id`
+    relay_state.active_fork_ids.retain(|&id| id != chain_id);
+    relay_state.active_fork_ids.push(fork_id);
+    relay_state.chain_counter = fork_id;
+    relay_state.best_block = hash_curr_block;
+    relay_state.best_height = height;
+    relay_state.finalized_height = height.saturating_sub(FINALITY_DEPTH);
+    relay_state.best_timestamp = tip_header.timestamp;
+    relay_state.chain_work = tip_header.chain_work;
+    check_tip_invariant(relay_state, hash_curr_block, tip_header)?;
+
+    emit!(ChainReorg {
+        from: old_best_block,
+        to: hash_curr_block,
+        chain_id: chain_id as u64,
+        rewind_from,
+    });
+    for swap in swaps {
+        emit!(swap);
+    }
+
+    Ok(())
+}
+
+/// Re-roots `fork`, whose ancestor an earlier reorg demoted into `parent`, onto the parent's
+/// ancestor: the parent's blocks up to and including the fork's ancestor move to the front of
+/// `fork`, and `parent` keeps the blocks above as a fork branching off that ancestor. Returns the
+/// number of blocks moved, whose headers still carry the parent's chain id.
+///
+/// The parent keeps at least one block: a fork's ancestor had a main-chain child when the fork
+/// was created, demoted along with it.
+fn adopt_parent_blocks(fork: &mut Fork, parent: &mut Fork) -> Result<usize> {
+    let position = parent
+        .descendants
+        .iter()
+        .position(|hash| *hash == fork.ancestor)
+        .ok_or(RelayError::NestedFork)?;
+    require!(position + 1 < parent.descendants.len(), RelayError::NestedFork);
+
+    let mut descendants: Vec<[u8; 32]> = parent.descendants.drain(..=position).collect();
+    descendants.append(&mut fork.descendants);
+    fork.descendants = descendants;
+    std::mem::swap(&mut fork.ancestor, &mut parent.ancestor);
+    Ok(position + 1)
+}
+
+/// The `HeightReorged` events of a reorg from `rewind_from` on: `replaced` main-chain blocks
+/// give way to the `promoted` fork blocks at the same heights, at most `MAX_REORG_DEPTH`.
+fn height_swaps(rewind_from: u32, replaced: &[[u8; 32]], promoted: &[[u8; 32]]) -> Vec<HeightReorged> {
+    replaced
+        .iter()
+        .zip(promoted)
+        .take(MAX_REORG_DEPTH as usize)
+        .enumerate()
+        .map(|(i, (old_hash, new_hash))| HeightReorged {
+            height: rewind_from + i as u32,
+            old_hash: *old_hash,
+            new_hash: *new_hash,
+        })
+        .collect()
+}
+
+/// Checks a header against the stored chain without modifying it and returns its target.
+fn validate_block_header(
+    relay_state: &RelayState,
+    prev_header: &Header,
+    header: &[u8; 80],
+    block_hash: [u8; 32],
+    prev_block_hash_chain_id: u32,
+    block_height: u32,
+    oracle_target: Option<U256>,
+) -> Result<U256> {
+    let hash_curr_block: [u8; 32] = hash256(header);
+    require!(hash_curr_block == block_hash, RelayError::InvalidBlockHash);
+    let prv_height = prev_header.height;
+    require!(prv_height > 0 && prv_height == block_height - 1, RelayError::PreviousBlockNotFound);
+    require!(prev_header.chain_id == prev_block_hash_chain_id, RelayError::InvalidChainId);
+    check_after_genesis(relay_state, block_height)?;
+    check_version(relay_state, header)?;
+
+    let target = extract_target_at(header, 0)?;
+    require!(hash_to_u256_le(&hash_curr_block) <= target, RelayError::LowDifficulty);
+    check_assumevalid(relay_state, block_height, hash_curr_block)?;
+
+    match oracle_target {
+        // the oracle vouches for the required difficulty, no retarget logic runs
+        Some(oracle_target) => require!(hash_to_u256_le(&hash_curr_block) <= oracle_target, RelayError::LowDifficulty),
+        // the authority vouches for the targets, the header still meets its own
+        None if is_assumed_valid(relay_state, block_height) => {}
+        None => check_target(relay_state, block_height, target)?,
+    }
+
+    Ok(target)
+}
+
+/// Validates a segment against scratch epoch anchors, see `validate_segment`.
+fn is_valid_segment(headers: &[[u8; 80]], start_height: u32, start_target: U256, start_time: u32) -> bool {
+    let mut epoch = RelayState {
+        epoch_start_target: u256_to_dec_string(start_target),
+        epoch_start_time: start_time,
+        epoch_anchored: true,
+        ..Default::default()
+    };
+    let mut prev_hash: Option<[u8; 32]> = None;
+    for (header, height) in headers.iter().zip(start_height..) {
+        if prev_hash.is_some_and(|prev_hash| header[4..36] != prev_hash) {
+            return false;
+        }
+        let hash = hash256(header);
+        let Ok(target) = extract_target_at(header, 0) else {
+            return false;
+        };
+        // a segment starting a period has no previous period to recompute its target from
+        let target_ok = if prev_hash.is_none() && is_period_start(height) {
+            target == start_target
+        } else {
+            check_target(&epoch, height, target).is_ok()
+        };
+        if !target_ok || hash_to_u256_le(&hash) > target {
+            return false;
+        }
+        update_epoch(&mut epoch, height, target, extract_timestamp(header));
+        prev_hash = Some(hash);
+    }
+    true
+}
+
+/// Returns the target published by the oracle when the relay runs in oracle mode.
+fn oracle_target(relay_state: &RelayState, oracle_config: &Option<Account<'_, OracleConfig>>) -> Result<Option<U256>> {
+    if !relay_state.oracle_mode {
+        return Ok(None);
+    }
+    let oracle_config = oracle_config.as_ref().ok_or(RelayError::OracleNotConfigured)?;
+    let target = u256_from_le_bytes(&oracle_config.target);
+    require!(!target.is_zero(), RelayError::OracleNotConfigured);
+    Ok(Some(target))
+}
+
+fn emit_admin_action(action: u8, authority: Pubkey) -> Result<()> {
+    emit!(AdminAction {
+        action,
+        authority,
+        slot: Clock::get()?.slot,
+    });
+    Ok(())
+}
+
+/// Rejects a header at or below the genesis height, which points at backfill data submitted out
+/// of order. The timestamp is not compared: consensus lets a block be dated before its ancestors
+/// as long as it is above the median time past.
+fn check_after_genesis(relay_state: &RelayState, height: u32) -> Result<()> {
+    require!(height > relay_state.genesis_height, RelayError::PredatesGenesis);
+    Ok(())
+}
+
+/// Rejects a header that is already stored.
+///
+/// The `Header` and `BlockHash` PDAs are written in one transaction, so a main-chain header the
+/// index at its height does not point to is reported as `InconsistentState` rather than as a
+/// duplicate. The opposite case heals itself: an index entry without a stored header lies
+/// above the tip and is overwritten by the next block at that height.
+fn check_not_stored(header: &Header, chain: &BlockHash, block_hash: [u8; 32], block_height: u32) -> Result<()> {
+    if header.chain_id == 0 {
+        return Ok(());
+    }
+    let indexed = header.chain_id != MAIN_CHAIN_ID || header.height != block_height || chain.block_hash == block_hash;
+    require!(indexed, RelayError::InconsistentState);
+    err!(RelayError::DuplicateBlock)
+}
+
+/// Rejects any submission but a main-chain extension in strict mode.
+fn check_fork_allowed(relay_state: &RelayState, extends_main_chain: bool) -> Result<()> {
+    require!(!relay_state.strict_no_fork || extends_main_chain, RelayError::NotChainExtension);
+    Ok(())
+}
+
+/// Fails with `TooManyForks` if `relay_state` cannot track another active fork, capping the
+/// fork PDAs a spammer can make the relay carry at `MAX_FORKS`.
+fn check_fork_capacity(relay_state: &RelayState) -> Result<()> {
+    require!(relay_state.active_fork_ids.len() < MAX_FORKS, RelayError::TooManyForks);
+    Ok(())
+}
+
+/// Counts the creation of fork `chain_id` at `height` in `recent_fork_creations`, first halving
+/// the counter once per `FORK_STORM_WINDOW` passed since the last creation. Returns the
+/// `ForkStormDetected` event to emit when the count exceeds `FORK_STORM_THRESHOLD`.
+fn record_fork_creation(relay_state: &mut RelayState, chain_id: u32, height: u32) -> Option<ForkStormDetected> {
+    let window = height / FORK_STORM_WINDOW;
+    // forks may branch below earlier ones, which never rewinds the window
+    let elapsed = window.saturating_sub(relay_state.fork_storm_window);
+    relay_state.fork_storm_window = relay_state.fork_storm_window.max(window);
+    relay_state.recent_fork_creations = relay_state.recent_fork_creations.checked_shr(elapsed).unwrap_or(0).saturating_add(1);
+
+    (relay_state.recent_fork_creations > FORK_STORM_THRESHOLD).then_some(ForkStormDetected {
+        recent_fork_creations: relay_state.recent_fork_creations,
+        chain_id,
+        height,
+    })
+}
+
+fn check_version(relay_state: &RelayState, header: &[u8]) -> Result<()> {
+    let version = extract_version(header);
+    require!(
+        (relay_state.min_version..=relay_state.max_version).contains(&version),
+        RelayError::InvalidVersion
+    );
+    Ok(())
+}
+
+/// The target of the current difficulty period. `update_epoch` clears only the end anchor at a
+/// period start, but an empty start anchor fails with a typed error rather than a parse error.
+fn current_target(relay_state: &RelayState) -> Result<U256> {
+    require!(!relay_state.epoch_start_target.is_empty(), RelayError::InvalidDifficultyPeriod);
+    dec_string_to_u256(&relay_state.epoch_start_target)
+}
+
+/// Checks the target of a block at `height`: period starts must carry the correct retarget,
+/// every other block the target of its period.
+fn check_target(relay_state: &RelayState, height: u32, target: U256) -> Result<()> {
+    let period_target = current_target(relay_state)?;
+    if is_period_start(height) {
+        // holds even for the first retarget, which cannot be recomputed exactly
+        require!(within_retarget_bounds(period_target, target), RelayError::RetargetOutOfBounds);
+        return check_retarget(relay_state, target);
+    }
+    // byte for byte, whatever form the period target is kept in
+    require!(u256_to_le_bytes(target) == u256_to_le_bytes(period_target), RelayError::UnexpectedTargetChange);
+    Ok(())
+}
+
+/// Whether the target of a block at `height` is trusted, see `set_assumevalid`.
+fn is_assumed_valid(relay_state: &RelayState, height: u32) -> bool {
+    relay_state.assumevalid != [0u8; 32] && height <= relay_state.assumevalid_height
+}
+
+/// Checks that a block at the assumevalid height is the `assumevalid` block, so the trusted
+/// targets below it only ever lead up to that block.
+fn check_assumevalid(relay_state: &RelayState, height: u32, hash: [u8; 32]) -> Result<()> {
+    if relay_state.assumevalid != [0u8; 32] && height == relay_state.assumevalid_height {
+        require!(hash == relay_state.assumevalid, RelayError::NotAssumevalidBlock);
+    }
+    Ok(())
+}
+
+/// Checks that `target` is the correct retarget from the epoch tracked in `relay_state`.
+///
+/// Skipped until the relay has seen a full period: when genesis is not at a period start the
+/// first retarget cannot be recomputed and is accepted as is.
+fn check_retarget(relay_state: &RelayState, target: U256) -> Result<()> {
+    if !relay_state.epoch_anchored {
+        return Ok(());
+    }
+    let prv_target = dec_string_to_u256(&relay_state.epoch_start_target)?;
+    let prv_end_target = dec_string_to_u256(&relay_state.epoch_end_target)?;
+    require!(
+        is_correct_difficulty_target(
+            prv_target,
+            relay_state.epoch_start_time,
+            prv_end_target,
+            relay_state.epoch_end_time,
+            target,
+        ).unwrap_or_default(),
+        RelayError::IncorrectDifficultyTarget
+    );
+    Ok(())
+}
+
+/// Records the timestamp of the main-chain block at `height`, counting it in
+/// `timestamp_anomaly_count` when it is not above the median of the `MEDIAN_TIME_SPAN` blocks
+/// before it, which Bitcoin's median-time-past rule would reject. Monitoring only, the header
+/// is accepted either way; slots not filled yet read as zero and never flag an anomaly.
+fn record_main_timestamp(relay_state: &mut RelayState, height: u32, timestamp: u32) {
+    let mut window = relay_state.recent_timestamps;
+    window.sort_unstable();
+    if timestamp <= window[window.len() / 2] {
+        relay_state.timestamp_anomaly_count = relay_state.timestamp_anomaly_count.saturating_add(1);
+    }
+    relay_state.recent_timestamps[(height % MEDIAN_TIME_SPAN) as usize] = timestamp;
+}
+
+/// Whether `target` lies within `MAX_RETARGET_FACTOR` of `prev_target` either way, like Bitcoin
+/// Core's `PermittedDifficultyTransition`. The lower bound is rounded through the compact form
+/// like retargets are, so a retarget clamped to the fast extreme stays within it.
+fn within_retarget_bounds(prev_target: U256, target: U256) -> bool {
+    let factor = U256::from(MAX_RETARGET_FACTOR);
+    let smallest = compact_to_u256(u256_to_compact(prev_target / factor)).unwrap_or_default();
+    let largest = prev_target.checked_mul(factor).unwrap_or(U256::MAX);
+    smallest <= target && target <= largest
+}
+
+fn epoch_progress(relay_state: &RelayState) -> EpochProgress {
+    EpochProgress {
+        blocks_into_period: relay_state.best_height % DIFFICULTY_ADJUSTMENT_INTERVAL,
+        period_length: DIFFICULTY_ADJUSTMENT_INTERVAL,
+        epoch_start_time: relay_state.epoch_start_time,
+    }
+}
+
+/// Records the epoch anchors when `height` starts or ends a difficulty period.
+fn update_epoch(relay_state: &mut RelayState, height: u32, target: U256, timestamp: u32) {
+    if is_period_start(height) {
+        relay_state.epoch_start_target = u256_to_dec_string(target);
+        relay_state.epoch_start_time = timestamp;
+        relay_state.epoch_end_target = String::new();
+        relay_state.epoch_end_time = 0;
+        relay_state.epoch_anchored = true;
+    } else if is_period_end(height) {
+        relay_state.epoch_end_target = u256_to_dec_string(target);
+        relay_state.epoch_end_time = timestamp;
+    }
+}
+
+/// Whether `next_target` is the retarget of the previous period, given the targets and
+/// timestamps of its first and last block.
+///
+/// The target is constant within a period, so differing start and end targets mean the
+/// anchors were taken from two periods and fail with `InvalidDifficultyPeriod`.
+pub fn is_correct_difficulty_target(
+    prev_start_target: U256,
+    prev_start_time: u32,
+    prev_end_target: U256,
+    prev_end_time: u32,
+    next_target: U256,
+) -> Result<bool> {
+    require!(prev_start_target == prev_end_target, RelayError::InvalidDifficultyPeriod);
+    let expected_target = retarget_algorithm(prev_start_target, prev_start_time, prev_end_time)?;
+
+    // consensus enforces the lossy compact form, not the full-precision target
+    Ok(target_to_bits(next_target) == target_to_bits(expected_target))
+}
+
+/// Decodes a compact `bits` field into the 256-bit target it encodes, see
+/// `byteorder::compact_to_u256`.
+pub fn bits_to_target(bits: u32) -> Result<U256> {
+    compact_to_u256(bits)
+}
+
+/// Encodes `target` in compact `bits` form, see `byteorder::u256_to_compact`.
+pub fn target_to_bits(target: U256) -> u32 {
+    u256_to_compact(target)
+}
+
+/// Returns the difficulty of `target` relative to the difficulty-1 target, rounded down as
+/// integer division; a zero target has difficulty 0.
+pub fn target_to_difficulty(target: U256) -> U256 {
+    let diff1_target = U256::from_str_radix(DIFF1_TARGET, 16).unwrap();
+    diff1_target.checked_div(target).unwrap_or_default()
+}
+
+/// Computes the target of the next difficulty period the way Bitcoin Core does.
+///
+/// `first_timestamp` and `second_timestamp` are the timestamps of the first and last block of
+/// the previous period (heights `N * 2016` and `N * 2016 + 2015`), so the measured timespan
+/// covers only 2015 block intervals. Bitcoin Core nonetheless divides by the full
+/// `RETARGET_PERIOD` (2016 intervals of 10 minutes); this off-by-one is part of consensus and
+/// is deliberately reproduced here.
+///
+/// Like `CalculateNextWorkRequired`, the timespan is clamped to a factor of 4 first and the
+/// resulting target is capped at `POW_LIMIT` afterwards, before the caller compacts it.
+pub fn retarget_algorithm(
+    previous_target: U256,
+    first_timestamp: u32,
+    second_timestamp: u32
+) -> Result<U256> {
+    // timestamps need not be monotonic, a negative timespan clamps to the minimum below
+    let mut elapsed_time = second_timestamp.saturating_sub(first_timestamp);
+
+    // Normalize ratio to factor of 4 if very long or very short
+    if elapsed_time < RETARGET_PERIOD / 4 {
+        elapsed_time = RETARGET_PERIOD / 4;
+    }
+    if elapsed_time > RETARGET_PERIOD * 4 {
+        elapsed_time = RETARGET_PERIOD * 4;
+    }
+
+    // Multiply before dividing like Bitcoin Core, so no low bits of the target are lost. The
+    // timespan is below 2^23, so mainnet targets (below `POW_LIMIT`, 2^224) cannot overflow,
+    // but `bits` accepts targets up to 2^255: from about 2^233 on this is `ArithmeticError`.
+    let result = previous_target
+        .checked_mul(elapsed_time.into())
+        .ok_or(RelayError::ArithmeticError)?
+        .checked_div(RETARGET_PERIOD.into())
+        .ok_or(RelayError::ArithmeticError)?;
+
+    let pow_limit = U256::from_str_radix(POW_LIMIT, 16).unwrap();
+    Ok(result.min(pow_limit))
+}
+
+/// Expected number of hashes needed to find a block meeting `target`,
+/// computed as `2^256 / (target + 1)` without leaving 256-bit arithmetic.
+fn calculate_work(target: U256) -> Result<U256> {
+    let denominator = target.checked_add(U256::one()).ok_or(RelayError::ArithmeticError)?;
+    Ok((!target / denominator) + U256::one())
+}
+
+/// Whether a fork tip with cumulative `fork_work` should replace a main chain with `main_work`:
+/// the fork must lead by the work of `CONFIRMATIONS` blocks at the main chain's `main_target`.
+///
+/// At a constant target this is a lead of `CONFIRMATIONS` blocks, but a fork mined at a higher
+/// difficulty can lead with fewer blocks, and a longer one with less work cannot. A fork with
+/// equal work never overtakes, the main chain is kept on ties.
+fn overtakes_main_chain(fork_work: U256, main_work: U256, main_target: U256) -> Result<bool> {
+    let margin = calculate_work(main_target)?
+        .checked_mul(CONFIRMATIONS.into())
+        .ok_or(RelayError::ArithmeticError)?;
+    Ok(main_work.checked_add(margin).is_some_and(|threshold| fork_work >= threshold))
+}
+
+/// Whether a fork tip at `block_height` with cumulative `chain_work`, whose first block is at
+/// `rewind_from`, replaces the main chain. Work decides (see `overtakes_main_chain`), whether the
+/// fork tip is above, at or below the main chain's tip, as long as `replays_anchors` holds;
+/// otherwise the fork waits until it grows past the next period start.
+fn fork_overtakes(relay_state: &RelayState, block_height: u32, rewind_from: u32, chain_work: U256) -> Result<bool> {
+    if !replays_anchors(relay_state, block_height, rewind_from) {
+        return Ok(false);
+    }
+    overtakes_main_chain(chain_work, u256_from_le_bytes(&relay_state.chain_work), current_target(relay_state)?)
+}
+
+/// Whether a reorg onto a fork tip at `block_height`, whose first block is at `rewind_from`, can
+/// restore the epoch anchors of the tip's period. It cannot when the main chain already started
+/// a period above the tip, overwriting them, and the fork does not reach back to the start of the
+/// tip's period to replay them.
+fn replays_anchors(relay_state: &RelayState, block_height: u32, rewind_from: u32) -> bool {
+    let passed_period = period_starts(block_height + 1, relay_state.best_height.saturating_sub(block_height)).next().is_some();
+    !passed_period || block_height - block_height % DIFFICULTY_ADJUSTMENT_INTERVAL >= rewind_from
+}
+
+/// Work of the main chain with cumulative `main_work` accumulated after `header`.
+fn work_since(main_work: U256, header: &Header) -> Result<U256> {
+    Ok(main_work
+        .checked_sub(u256_from_le_bytes(&header.chain_work))
+        .ok_or(RelayError::ArithmeticError)?)
+}
+
+/// Confirmations of the main-chain block at `height`, counting the block itself,
+/// or `None` above the tip.
+fn block_confirmations(best_height: u32, height: u32) -> Option<u32> {
+    best_height.checked_sub(height).map(|depth| depth + 1)
+}
+
+/// The blocks to add on top of `best_height` before the block at `height` is at or below the
+/// finalized height, `None` above the tip.
+fn confirmations_until_final(best_height: u32, height: u32) -> Option<u32> {
+    best_height.checked_sub(height).map(|depth| FINALITY_DEPTH.saturating_sub(depth))
+}
+
+/// Whether `height` is the first block of a difficulty period, the one that retargets. Its
+/// timestamp is the start anchor Bitcoin Core's `pindexFirst` reads when the next period
+/// retargets.
+fn is_period_start(height: u32) -> bool {
+    height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0
+}
+
+/// The heights in `[start_height, start_height + count)` that start a difficulty period.
+fn period_starts(start_height: u32, count: u32) -> StepBy<Range<u32>> {
+    let end = start_height.saturating_add(count);
+    let first = start_height.checked_next_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL).unwrap_or(end);
+    (first..end).step_by(DIFFICULTY_ADJUSTMENT_INTERVAL as usize)
+}
+
+/// Whether `height` is the last block of a difficulty period, `pindexLast` in Bitcoin Core's
+/// retarget: the timespan runs from the period's first block to this one.
+fn is_period_end(height: u32) -> bool {
+    height % DIFFICULTY_ADJUSTMENT_INTERVAL == DIFFICULTY_ADJUSTMENT_INTERVAL - 1
+}
+
+fn _initialize_fork(fork: &mut Account<'_, Fork>, hash_curr_block: [u8; 32], hash_prev_block: [u8; 32], new_chain_id: u32, height: u32) -> Result<()> {
+    fork.height = height;
+    fork.ancestor = hash_prev_block;
+    fork.descendants = vec![hash_curr_block];
+    Ok(())
+}
+
+fn load_fork(info: &AccountInfo) -> Result<Fork> {
+    require!(info.owner == &ID && !info.data_is_empty(), RelayError::ForkNotFound);
+    Fork::try_deserialize(&mut &info.try_borrow_data()?[..]).map_err(|_| error!(RelayError::ForkNotFound))
+}
+
+fn load_header(info: &AccountInfo) -> Result<Header> {
+    require!(info.owner == &ID && !info.data_is_empty(), RelayError::BlockNotFound);
+    Header::try_deserialize(&mut &info.try_borrow_data()?[..]).map_err(|_| error!(RelayError::BlockNotFound))
+}
+
+fn load_main_header_at(info: &AccountInfo, height: u32) -> Result<Header> {
+    let header = load_header(info)?;
+    require!(header.height == height && header.chain_id == MAIN_CHAIN_ID, RelayError::BlockNotFound);
+    Ok(header)
+}
+
+/// Moves the main-chain header at `height`, the `Header` PDA `info` named by the `BlockHash` PDA
+/// `chain`, to the fork `fork_id` and returns its hash.
+fn demote_header_at(epoch: u64, chain: &BlockHash, info: &AccountInfo, height: u32, fork_id: u32) -> Result<[u8; 32]> {
+    let mut old = resolve_header_at(epoch, chain, info, height)?;
+    old.chain_id = fork_id;
+    store_account(info, &old)?;
+    Ok(chain.block_hash)
+}
+
+/// Resolves the height index: loads the main-chain header at `height` from `info`, which must
+/// be the `Header` PDA named by `chain`, the `BlockHash` PDA at `height`. The two are written
+/// together, see `_store_block_header`, so a failure means a wrong account or a stale index.
+fn resolve_header_at(epoch: u64, chain: &BlockHash, info: &AccountInfo, height: u32) -> Result<Header> {
+    require_keys_eq!(info.key(), header_address(epoch, &chain.block_hash), RelayError::BlockNotFound);
+    load_main_header_at(info, height)
+}
+
+/// Loads the header at `height` on the branch of `fork`: one of its descendants above the fork
+/// point, its ancestor at the fork point and the main chain below.
+fn load_fork_header_at(info: &AccountInfo, fork: &Fork, epoch: u64, chain_id: u32, height: u32) -> Result<Header> {
+    let first_height = fork.height + 1 - fork.descendants.len() as u32;
+    require!(height <= fork.height, RelayError::BlockNotFound);
+    if height < first_height - 1 {
+        return load_main_header_at(info, height);
+    }
+
+    let expected = if height == first_height - 1 {
+        fork.ancestor
+    } else {
+        fork.descendants[(height - first_height) as usize]
+    };
+    require_keys_eq!(info.key(), header_address(epoch, &expected), RelayError::BlockNotFound);
+    let header = load_header(info)?;
+    require!(header.height == height && (height < first_height || header.chain_id == chain_id), RelayError::BlockNotFound);
+    Ok(header)
+}
+
+/// Whether `header`, stored under `block_hash`, is the main-chain block `relay_state` names as
+/// its tip.
+fn is_tip(relay_state: &RelayState, block_hash: [u8; 32], header: &Header) -> bool {
+    block_hash == relay_state.best_block && header.chain_id == MAIN_CHAIN_ID && header.height == relay_state.best_height
+}
+
+/// Fails with `DanglingTip` unless `header` is the new tip, in builds with the
+/// `check-invariants` feature; a no-op otherwise.
+fn check_tip_invariant(relay_state: &RelayState, block_hash: [u8; 32], header: &Header) -> Result<()> {
+    if cfg!(feature = "check-invariants") {
+        require!(is_tip(relay_state, block_hash, header), RelayError::DanglingTip);
+    }
+    Ok(())
+}
+
+fn is_header_stored(info: &AccountInfo) -> bool {
+    load_header(info).is_ok_and(|header| header.chain_id != 0)
+}
+
+fn load_block_hash(info: &AccountInfo) -> Result<BlockHash> {
+    require!(info.owner == &ID && !info.data_is_empty(), RelayError::BlockNotFound);
+    BlockHash::try_deserialize(&mut &info.try_borrow_data()?[..]).map_err(|_| error!(RelayError::BlockNotFound))
+}
+
+fn store_account<T: AccountSerialize>(info: &AccountInfo, account: &T) -> Result<()> {
+    account.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])
+}
+
+/// Moves the bond held by a fork PDA to `to`, leaving the PDA rent exempt.
+fn release_fork_bond(fork_info: &AccountInfo, to: &AccountInfo, fork: &mut Fork) -> Result<()> {
+    **fork_info.try_borrow_mut_lamports()? -= fork.bond;
+    **to.try_borrow_mut_lamports()? += fork.bond;
+    fork.bond = 0;
+    Ok(())
+}
+
+/// Grows the fork account `info` to hold `descendants` block hashes if it is too small, `payer`
+/// topping up its rent. The new bytes are not zeroed, the next `store_account` writes them.
+fn grow_fork<'info>(
+    info: &AccountInfo<'info>,
+    fork: &Fork,
+    descendants: usize,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let space = Fork::space(descendants);
+    if space <= info.data_len() {
+        return Ok(());
+    }
+    // the account holds the fork's bond on top of its rent
+    let top_up = Rent::get()?
+        .minimum_balance(space)
+        .saturating_add(fork.bond)
+        .saturating_sub(info.lamports());
+    if top_up > 0 {
+        system_program::transfer(
+            CpiContext::new(system_program.clone(), system_program::Transfer { from: payer.clone(), to: info.clone() }),
+            top_up,
+        )?;
+    }
+    info.realloc(space, false)?;
+    Ok(())
+}
+
+/// Creates the program-owned PDA `info` at `seeds` with `space` bytes, paid by `payer`, unless it
+/// already exists.
+fn create_pda<'info>(
+    info: &AccountInfo<'info>,
+    seeds: &[&[u8]],
+    space: usize,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let (address, bump) = Pubkey::find_program_address(seeds, &ID);
+    require_keys_eq!(info.key(), address, RelayError::BlockNotFound);
+    if info.owner == &ID {
+        return Ok(());
+    }
+    let bump = [bump];
+    let signer_seeds: Vec<&[u8]> = seeds.iter().copied().chain([bump.as_ref()]).collect();
+    system_program::create_account(
+        CpiContext::new_with_signer(
+            system_program.clone(),
+            system_program::CreateAccount { from: payer.clone(), to: info.clone() },
+            &[&signer_seeds[..]],
+        ),
+        Rent::get()?.minimum_balance(space),
+        space as u64,
+        &ID,
+    )
+}
+
+/// Closes a program-owned account passed outside the context, moving its rent to `to`.
+fn close_account(info: &AccountInfo, to: &AccountInfo) -> Result<()> {
+    **to.try_borrow_mut_lamports()? += info.lamports();
+    **info.try_borrow_mut_lamports()? = 0;
+    info.assign(&system_program::ID);
+    info.realloc(0, false)?;
+    Ok(())
+}
+
+fn fork_address(epoch: u64, chain_id: u32) -> Pubkey {
+    Pubkey::find_program_address(&[b"fork", epoch.to_le_bytes().as_ref(), chain_id.to_le_bytes().as_ref()], &ID).0
+}
+
+fn chain_address(epoch: u64, height: u32) -> Pubkey {
+    Pubkey::find_program_address(&[b"chain", epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], &ID).0
+}
+
+fn header_address(epoch: u64, block_hash: &[u8; 32]) -> Pubkey {
+    Pubkey::find_program_address(&[b"header", epoch.to_le_bytes().as_ref(), block_hash.as_ref()], &ID).0
+}
+
+/// Initializes the relay state and stores the genesis block, see `initialize`.
+fn _initialize(
+    accounts: &mut Initialize,
+    genesis_header: [u8; 80],
+    genesis_height: u32,
+    genesis_block_hash: [u8; 32],
+    config: RelayConfig,
+) -> Result<()> {
+    _initialize_relay(
+        &mut accounts.relay_state,
+        &mut accounts.fork,
+        &mut accounts.header,
+        &mut accounts.chain,
+        accounts.user.key(),
+        genesis_header,
+        genesis_height,
+        genesis_block_hash,
+        config,
+    )
+}
+
+/// Sets up a relay instance rooted at the block `genesis_header`, owned by `authority`.
+#[allow(clippy::too_many_arguments)]
+fn _initialize_relay(
+    relay_state: &mut RelayState,
+    fork: &mut Fork,
+    header: &mut Account<'_, Header>,
+    chain: &mut Account<'_, BlockHash>,
+    authority: Pubkey,
+    genesis_header: [u8; 80],
+    genesis_height: u32,
+    genesis_block_hash: [u8; 32],
+    config: RelayConfig,
+) -> Result<()> {
+    require!(genesis_height > 0, RelayError::InvalidGenesisHeight);
+    require!(genesis_block_hash != [0u8; 32], RelayError::InvalidBlockHash);
+
+    let digest = hash256(&genesis_header);
+    require!(digest == genesis_block_hash, RelayError::InvalidBlockHash);
+
+    let target = extract_target_at(&genesis_header, 0)?;
+    let timestamp = extract_timestamp(&genesis_header);
+
+    // store bitcoin header 
+    relay_state.best_block = digest;
+    relay_state.best_height = genesis_height;
+    relay_state.finalized_height = genesis_height.saturating_sub(FINALITY_DEPTH);
+    relay_state.genesis_height = genesis_height;
+    relay_state.epoch_start_target = u256_to_dec_string(target);
+    relay_state.epoch_end_target = u256_to_dec_string(target);
+    relay_state.epoch_start_time = timestamp;
+    relay_state.epoch_end_time = timestamp;
+    // anchors seeded from a genesis inside a period do not describe that period
+    relay_state.epoch_anchored = is_period_start(genesis_height);
+    relay_state.chain_counter = MAIN_CHAIN_ID;
+    relay_state.best_timestamp = timestamp;
+    relay_state.recent_timestamps[(genesis_height % MEDIAN_TIME_SPAN) as usize] = timestamp;
+    relay_state.total_headers = 1;
+    relay_state.authority = authority;
+    relay_state.max_version = u32::MAX;
+    relay_state.oracle_mode = config.oracle_mode;
+    relay_state.strict_no_fork = config.strict_no_fork;
+    relay_state.epoch = config.epoch;
+
+    let work = calculate_work(target)?;
+    relay_state.chain_work = u256_to_le_bytes(work);
+
+    // the main chain is rooted at genesis, nothing below it is ever stored
+    fork.height = genesis_height;
+    fork.ancestor = digest;
+
+    _store_block_header(header, chain, &genesis_header, digest, genesis_height, MAIN_CHAIN_ID, work, authority)?;
+    Ok(())
+}
+
+/// Fails with `InvalidHeaderBatch` if `len` headers and their PDAs exceed the accounts a
+/// transaction can reference, see `MAX_BATCH_SIZE`.
+fn check_batch_size(len: usize) -> Result<()> {
+    if len > MAX_BATCH_SIZE {
+        msg!("Batch of {} headers exceeds MAX_BATCH_SIZE {}, split it and pass the PDAs through an address lookup table", len, MAX_BATCH_SIZE);
+        return err!(RelayError::InvalidHeaderBatch);
+    }
+    Ok(())
+}
+
+/// Validates `headers` on top of the main-chain tip `prev_header` and stores them in their
+/// `Header` and `BlockHash` PDAs, passed in pairs in `accounts`, moving the tip to the last one
+/// stored. Stops before a header the remaining compute budget may not cover and returns the
+/// number stored. The index of the first header not linking to its predecessor is left in
+/// the return data.
+#[allow(clippy::too_many_arguments)]
+fn _store_main_chain_run<'info>(
+    relay_state: &mut RelayState,
+    main_fork: &mut Fork,
+    prev_header: &Header,
+    headers: &[[u8; 80]],
+    accounts: &[AccountInfo<'info>],
+    oracle_target: Option<U256>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<u32> {
+    if let Some(index) = first_unlinked_header(relay_state.best_block, headers) {
+        set_return_data(&(index as u32).to_le_bytes());
+        return err!(RelayError::InvalidHeaderBatch);
+    }
+    require!(accounts.len() == 2 * headers.len(), RelayError::BlockNotFound);
+
+    let mut prev_header = prev_header.clone();
+    let mut block_hash = relay_state.best_block;
+    let mut stored = 0;
+    for (header, accounts) in headers.iter().zip(accounts.chunks_exact(2)) {
+        // running out of compute units would revert the headers stored so far, stop before
+        if stored > 0 && sol_remaining_compute_units() < BATCH_HEADER_CU + BATCH_FINISH_CU {
+            msg!("Compute budget low, stopping the batch after {} of {} headers", stored, headers.len());
+            break;
+        }
+        let (header_info, chain_info) = (&accounts[0], &accounts[1]);
+        block_hash = hash256(header);
+        let height = prev_header.height + 1;
+        require!(!is_header_stored(header_info), RelayError::DuplicateBlock);
+
+        let target = validate_block_header(relay_state, &prev_header, header, block_hash, MAIN_CHAIN_ID, height, oracle_target)?;
+        let chain_work = u256_from_le_bytes(&prev_header.chain_work)
+            .checked_add(calculate_work(target)?)
+            .ok_or(RelayError::ArithmeticError)?;
+        update_epoch(relay_state, height, target, extract_timestamp(header));
+        record_main_timestamp(relay_state, height, extract_timestamp(header));
+        relay_state.total_headers += 1;
+
+        let epoch = relay_state.epoch.to_le_bytes();
+        create_pda(header_info, &[b"header", epoch.as_ref(), block_hash.as_ref()], size_of::<Header>() + 8, payer, system_program)?;
+        create_pda(chain_info, &[b"chain", epoch.as_ref(), height.to_le_bytes().as_ref()], size_of::<BlockHash>() + 8, payer, system_program)?;
+        prev_header = header_record(header, height, MAIN_CHAIN_ID, chain_work, payer.key());
+        store_account(header_info, &prev_header)?;
+        store_account(chain_info, &BlockHash { block_hash })?;
+        stored += 1;
+    }
+
+    let tip = &headers[stored - 1];
+    let chain_work = u256_from_le_bytes(&prev_header.chain_work);
+    _extend_main_chain(relay_state, main_fork, block_hash, prev_header.height, extract_timestamp(tip), chain_work);
+    check_tip_invariant(relay_state, block_hash, &prev_header)?;
+    Ok(stored as u32)
+}
+
+/// Validates `header` against the main chain tip `prev_header` and stores it as the new tip.
+#[allow(clippy::too_many_arguments)]
+fn _connect_to_tip(
+    relay_state: &mut RelayState,
+    main_fork: &mut Fork,
+    prev_header: &Header,
+    chain: &mut Account<'_, BlockHash>,
+    header_account: &mut Account<'_, Header>,
+    header: &[u8; 80],
+    submitter: Pubkey,
+    oracle_target: Option<U256>,
+) -> Result<()> {
+    require!(
+        prev_header.chain_id == MAIN_CHAIN_ID && prev_header.height == relay_state.best_height,
+        RelayError::NotChainExtension
+    );
+
+    let block_height = prev_header.height + 1;
+    let block_hash = hash256(header);
+    let target = validate_block_header(relay_state, prev_header, header, block_hash, MAIN_CHAIN_ID, block_height, oracle_target)?;
+    update_epoch(relay_state, block_height, target, extract_timestamp(header));
+    record_main_timestamp(relay_state, block_height, extract_timestamp(header));
+
+    let chain_work = u256_from_le_bytes(&prev_header.chain_work)
+        .checked_add(calculate_work(target)?)
+        .ok_or(RelayError::ArithmeticError)?;
+
+    _store_block_header(header_account, chain, header, block_hash, block_height, MAIN_CHAIN_ID, chain_work, submitter)?;
+    relay_state.total_headers += 1;
+    _extend_main_chain(relay_state, main_fork, block_hash, block_height, extract_timestamp(header), chain_work);
+    check_tip_invariant(relay_state, block_hash, header_account)
+}
+
+fn _extend_main_chain(relay_state: &mut RelayState, main_fork: &mut Fork, block_hash: [u8; 32], height: u32, timestamp: u32, chain_work: U256) {
+    relay_state.best_block = block_hash;
+    relay_state.best_height = height;
+    relay_state.finalized_height = height.saturating_sub(FINALITY_DEPTH);
+    relay_state.best_timestamp = timestamp;
+    relay_state.chain_work = u256_to_le_bytes(chain_work);
+    main_fork.height = height;
+}
+
+fn header_record(raw_header: &[u8], height: u32, chain_id: u32, chain_work: U256, submitter: Pubkey) -> Header {
+    Header {
+        height,
+        chain_id,
+        chain_work: u256_to_le_bytes(chain_work),
+        timestamp: extract_timestamp(raw_header),
+        version: extract_version(raw_header),
+        bits: read_u32_le(raw_header, 72),
+        submitter,
+    }
+}
+
+/// Writes a header and, on the main chain, its height index entry in one step, keeping the
+/// `BlockHash` PDA at `height` and the `Header` PDA of `digest` pointing at each other.
+#[allow(clippy::too_many_arguments)]
+fn _store_block_header(
+    header: &mut Account<'_, Header>,
+    chain: &mut Account<'_, BlockHash>,
+    raw_header: &[u8],
+    digest: [u8; 32],
+    height: u32,
+    chain_id: u32,
+    chain_work: U256,
+    submitter: Pubkey,
+) -> Result<()> {
+    header.set_inner(header_record(raw_header, height, chain_id, chain_work, submitter));
+    // only the main chain is indexed by height, fork blocks are tracked by their fork
+    if chain_id == MAIN_CHAIN_ID {
+        chain.block_hash = digest;
+    }
+    
+    Ok(())
+}
+
+/// Index of the first header of `headers` that does not link to its predecessor, the first
+/// one to `prev_hash`.
+fn first_unlinked_header(prev_hash: [u8; 32], headers: &[[u8; 80]]) -> Option<usize> {
+    let mut prev_hash = prev_hash;
+    headers.iter().position(|header| {
+        let linked = header[4..36] == prev_hash;
+        prev_hash = hash256(header);
+        !linked
+    })
+}
+
+/// Hash of the last header of a run, used to derive the tip's `Header` PDA in `submit_header_chain`.
+pub fn chain_tip_hash(headers: &[[u8; 80]]) -> [u8; 32] {
+    headers.last().map(|header| hash256(header)).unwrap_or_default()
+}
+
+fn hash256(b: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(Sha256::digest(b));
+    hasher.finalize().into()
+}
+
+fn extract_target_at(header: &[u8], at: usize) -> Result<U256> {
+    bits_to_target(read_u32_le(header, 72 + at))
+}
+
+fn extract_version(header: &[u8]) -> u32 {
+    read_u32_le(header, 0)
+}
+
+fn extract_prev_block_hash(header: &[u8]) -> [u8; 32] {
+    header[4..36].try_into().unwrap()
+}
+
+fn extract_merkle_root(header: &[u8]) -> [u8; 32] {
+    header[36..68].try_into().unwrap()
+}
+
+/// Folds a merkle branch of concatenated 32-byte siblings into the root committing to `txid`
+/// at position `index`.
+fn compute_merkle_root(txid: [u8; 32], index: u32, proof: &[u8]) -> Result<[u8; 32]> {
+    require!(proof.len().is_multiple_of(32), RelayError::IncorrectMerkleProof);
+
+    let mut current = txid;
+    let mut index = index;
+    for sibling in proof.chunks_exact(32) {
+        let mut concat = [0u8; 64];
+        if index & 1 == 0 {
+            concat[..32].copy_from_slice(&current);
+            concat[32..].copy_from_slice(sibling);
+        } else {
+            concat[..32].copy_from_slice(sibling);
+            concat[32..].copy_from_slice(&current);
+        }
+        current = hash256(&concat);
+        index >>= 1;
+    }
+    Ok(current)
+}
+
+/// Checks that `header` is the main-chain block indexed by `chain` at `height` and has at
+/// least `confirmations` confirmations.
+fn check_block_depth(relay_state: &RelayState, chain: &BlockHash, height: u32, header: &[u8; 80], confirmations: u64) -> Result<()> {
+    require!(hash256(header) == chain.block_hash, RelayError::BlockNotFound);
+    let block_confirmations = block_confirmations(relay_state.best_height, height).ok_or(RelayError::BlockNotFound)?;
+    require!(block_confirmations as u64 >= confirmations, RelayError::InsufficientConfirmations);
+    Ok(())
+}
+
+/// The first check of `verify_tx` that the proof fails, given the hash `stored_hash` of the
+/// `BlockHash` PDA at `height` if it is initialized.
+#[allow(clippy::too_many_arguments)]
+fn verify_failure(
+    relay_state: &RelayState,
+    stored_hash: Option<[u8; 32]>,
+    height: u32,
+    header: &[u8; 80],
+    txid: [u8; 32],
+    index: u32,
+    proof: &[u8],
+    confirmations: u64,
+) -> Option<VerifyFailure> {
+    // heights above the tip may hold the index entry of a fork block, which is never set
+    let Some(block_confirmations) = block_confirmations(relay_state.best_height, height) else {
+        return Some(VerifyFailure::BlockNotFound);
+    };
+    let Some(stored_hash) = stored_hash.filter(|hash| *hash != [0u8; 32]) else {
+        return Some(VerifyFailure::BlockNotFound);
+    };
+    if stored_hash != hash256(header) {
+        return Some(VerifyFailure::NotMainChain);
+    }
+    if (block_confirmations as u64) < confirmations {
+        return Some(VerifyFailure::TooFewConfirmations);
+    }
+    if check_tx_inclusion(header, txid, index, proof).is_err() {
+        return Some(VerifyFailure::ProofMismatch);
+    }
+    None
+}
+
+/// Checks that the merkle branch `proof` places `txid` at `index` under the root of `header`.
+fn check_tx_inclusion(header: &[u8; 80], txid: [u8; 32], index: u32, proof: &[u8]) -> Result<()> {
+    // a zero root only shows up in degenerate headers, never prove against it
+    let header_root = extract_merkle_root(header);
+    require!(header_root != [0u8; 32], RelayError::IncorrectMerkleProof);
+    require!(proves_inclusion(txid, index, proof, header_root), RelayError::IncorrectMerkleProof);
+    Ok(())
+}
+
+/// Whether the merkle branch `proof` places `txid` at `index` under `root`.
+fn proves_inclusion(txid: [u8; 32], index: u32, proof: &[u8], root: [u8; 32]) -> bool {
+    index_fits_proof(index, proof.len() / 32)
+        && compute_merkle_root(txid, index, proof).is_ok_and(|merkle_root| merkle_root == root)
+}
+
+/// Approximate compute units of a `verify_tx` call with a `proof_len`-byte merkle branch, one
+/// `hash256` per level on top of a fixed base, for clients to size their
+/// `ComputeBudgetInstruction::set_compute_unit_limit`. The figures carry some headroom but are
+/// estimates, simulate the transaction where exact numbers matter.
+pub fn estimate_verify_cu(proof_len: usize) -> u32 {
+    let levels = u32::try_from(proof_len / 32).unwrap_or(u32::MAX);
+    VERIFY_TX_BASE_CU.saturating_add(levels.saturating_mul(HASH256_CU))
+}
+
+/// Whether `index` addresses a leaf of a tree `levels` deep. Higher bits would be ignored by
+/// the fold, silently proving the transaction at a different position.
+///
+/// Merkle indices are `u32` throughout: a block cannot hold 2^32 transactions, and a single
+/// width keeps the fold, this check and `PartialVerification::index` free of casts.
+fn index_fits_proof(index: u32, levels: usize) -> bool {
+    index.checked_shr(u32::try_from(levels).unwrap_or(u32::MAX)).unwrap_or(0) == 0
+}
+
+/// Builds the merkle root of a block's full, non-empty list of txids, duplicating the last node
+/// of odd levels as Bitcoin does.
+fn merkle_root_of(txids: &[[u8; 32]]) -> [u8; 32] {
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        level = level.chunks(2).map(|pair| hash256(&[pair[0], pair[1]].concat())).collect();
+    }
+    level[0]
+}
+
+/// Returns the scriptSig of a coinbase transaction in its non-witness serialization.
+fn coinbase_script(tx: &[u8]) -> Result<&[u8]> {
+    // version (4) | input count (1) | null outpoint (36) | script length (1) | script
+    require!(tx.len() > 42 && tx[4] == 1, RelayError::InvalidCoinbase);
+    require!(tx[5..37] == [0u8; 32] && tx[37..41] == [0xff; 4], RelayError::InvalidCoinbase);
+    // coinbase scripts are at most 100 bytes, so the length is a single-byte varint
+    let len = tx[41] as usize;
+    tx.get(42..42 + len).ok_or_else(|| error!(RelayError::InvalidCoinbase))
+}
+
+/// Reads a transaction serialization front to back, failing with `InvalidCoinbase` past its end.
+struct TxReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TxReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(RelayError::InvalidCoinbase)?;
+        let bytes = self.data.get(self.pos..end).ok_or(RelayError::InvalidCoinbase)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reads a Bitcoin CompactSize integer.
+    fn compact_size(&mut self) -> Result<usize> {
+        let len = match self.take(1)?[0] {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            small => return Ok(small as usize),
+        };
+        let mut value = [0u8; 8];
+        value[..len].copy_from_slice(self.take(len)?);
+        usize::try_from(u64::from_le_bytes(value)).map_err(|_| error!(RelayError::InvalidCoinbase))
+    }
+}
+
+/// Returns the BIP141 witness commitment of a coinbase in its witness serialization, if any,
+/// and its witness reserved value.
+fn parse_witness_commitment(tx: &[u8]) -> Result<(Option<[u8; 32]>, [u8; 32])> {
+    let mut reader = TxReader { data: tx, pos: 0 };
+    reader.take(4)?;
+    // segwit marker and flag
+    require!(reader.take(2)? == [0x00, 0x01], RelayError::InvalidCoinbase);
+    require!(reader.compact_size()? == 1, RelayError::InvalidCoinbase);
+    let outpoint = reader.take(36)?;
+    require!(outpoint[..32] == [0u8; 32] && outpoint[32..] == [0xff; 4], RelayError::InvalidCoinbase);
+    let script_len = reader.compact_size()?;
+    reader.take(script_len)?;
+    reader.take(4)?;
+
+    let mut commitment = None;
+    for _ in 0..reader.compact_size()? {
+        reader.take(8)?;
+        let script_len = reader.compact_size()?;
+        let script = reader.take(script_len)?;
+        // the last matching output is the commitment
+        if script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER {
+            commitment = Some(script[6..38].try_into().unwrap());
+        }
+    }
+
+    require!(reader.compact_size()? == 1 && reader.compact_size()? == 32, RelayError::InvalidCoinbase);
+    let reserved_value = reader.take(32)?.try_into().unwrap();
+    reader.take(4)?;
+    require!(reader.pos == tx.len(), RelayError::InvalidCoinbase);
+    Ok((commitment, reserved_value))
+}
+
+/// Reads the BIP34 block height pushed at the start of a coinbase scriptSig.
+///
+/// Heights up to 16 are encoded as `OP_1`..`OP_16`, larger ones as a minimal little-endian
+/// script number push of up to 4 bytes.
+pub fn parse_bip34_height(script: &[u8]) -> Result<u32> {
+    let opcode = *script.first().ok_or(RelayError::InvalidCoinbase)?;
+    match opcode {
+        0x51..=0x60 => Ok((opcode - 0x50) as u32),
+        1..=4 => {
+            let len = opcode as usize;
+            let bytes = script.get(1..=len).ok_or(RelayError::InvalidCoinbase)?;
+            // the sign bit of a script number must not be set
+            require!(bytes[len - 1] & 0x80 == 0, RelayError::InvalidCoinbase);
+            let mut height = [0u8; 4];
+            height[..len].copy_from_slice(bytes);
+            Ok(read_u32_le(&height, 0))
+        }
+        _ => err!(RelayError::InvalidCoinbase),
+    }
+}
+
+fn extract_timestamp(data: &[u8]) -> u32 {
+    read_u32_le(data, 68)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retarget_matches_first_mainnet_adjustment() {
+        // Height 32256: period from block #30240 to block #32255 at bits 0x1d00ffff,
+        // retargeted to bits 0x1d00d86a
+        let previous_target = U256::from(0xffffu32) << 208u32;
+        let target = retarget_algorithm(previous_target, 1261130161, 1262152739).unwrap();
+        assert_eq!(target >> 208u32, U256::from(0xd86au32));
+    }
+
+    #[test]
+    fn epoch_anchors_span_the_core_retarget_window() {
+        // the period of the first mainnet retarget: blocks #30240 to #32255
+        let previous_target = bits_to_target(0x1d00ffff).unwrap();
+        let mut relay_state = relay_state_at(30240, previous_target, 1261130161);
+        for height in 30241..32256 {
+            let timestamp = if height == 32255 { 1262152739 } else { 1261130161 + (height - 30240) * 500 };
+            update_epoch(&mut relay_state, height, previous_target, timestamp);
+        }
+        assert!(!is_period_end(32254) && is_period_end(32255) && is_period_start(32256));
+        assert_eq!(relay_state.epoch_start_time, 1261130161);
+        assert_eq!(relay_state.epoch_end_time, 1262152739);
+
+        // block #32256 is checked against the anchors of its predecessors' period
+        let target = retarget_algorithm(previous_target, relay_state.epoch_start_time, relay_state.epoch_end_time).unwrap();
+        assert_eq!(target_to_bits(target), 0x1d00d86a);
+
+        // and starts the next window
+        update_epoch(&mut relay_state, 32256, target, 1262153464);
+        assert_eq!(relay_state.epoch_start_time, 1262153464);
+        assert_eq!(relay_state.epoch_end_time, 0);
+    }
+
+    #[test]
+    fn accepts_retarget_after_a_period_with_a_constant_target() {
+        // the first mainnet retarget, the period ran at 0x1d00ffff from start to end
+        let previous_target = bits_to_target(0x1d00ffff).unwrap();
+        let mut relay_state = relay_state_at(30240, previous_target, 1261130161);
+        update_epoch(&mut relay_state, 32255, previous_target, 1262152739);
+        assert!(check_retarget(&relay_state, bits_to_target(0x1d00d86a).unwrap()).is_ok());
+        assert!(check_retarget(&relay_state, previous_target).is_err());
+
+        // anchors whose targets differ cannot describe a single period
+        let next_target = bits_to_target(0x1d00d86a).unwrap();
+        assert!(is_correct_difficulty_target(previous_target, 1261130161, next_target, 1262152739, next_target).is_err());
+    }
+
+    #[test]
+    fn genesis_bits_have_difficulty_one() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        assert_eq!(target, U256::from(0xffffu32) << 208u32);
+        assert_eq!(target_to_difficulty(target), U256::from(1u32));
+    }
+
+    #[test]
+    fn diff1_target_is_the_genesis_target() {
+        assert_eq!(DIFF1_TARGET.len(), 64);
+        assert_eq!(U256::from_str_radix(DIFF1_TARGET, 16).unwrap(), bits_to_target(0x1d00ffff).unwrap());
+    }
+
+    #[test]
+    fn decodes_known_mainnet_difficulties() {
+        // block 100000, difficulty 14484.16
+        assert_eq!(target_to_difficulty(bits_to_target(0x1b04864c).unwrap()), U256::from(14484u32));
+        // block 32256, the first retarget, difficulty 1.18
+        assert_eq!(target_to_difficulty(bits_to_target(0x1d00d86a).unwrap()), U256::from(1u32));
+        // regtest targets are easier than difficulty 1
+        assert_eq!(target_to_difficulty(bits_to_target(0x207fffff).unwrap()), U256::zero());
+    }
+
+    #[test]
+    fn encodes_targets_in_compact_form() {
+        for bits in [0x1d00ffff, 0x1b04864c, 0x207fffff, 0x1c0168fd] {
+            assert_eq!(target_to_bits(bits_to_target(bits).unwrap()), bits);
+        }
+        // a mantissa with the sign bit set moves to the next byte
+        assert_eq!(target_to_bits(U256::from(0x80u32)), 0x02008000);
+        assert_eq!(target_to_bits(U256::zero()), 0);
+    }
+
+    #[test]
+    fn compact_form_truncates_full_precision_retarget() {
+        // the first mainnet retarget is only exact in its compact form 0x1d00d86a
+        let previous_target = bits_to_target(0x1d00ffff).unwrap();
+        let target = retarget_algorithm(previous_target, 1261130161, 1262152739).unwrap();
+        assert_ne!(target, bits_to_target(0x1d00d86a).unwrap());
+        assert_eq!(target_to_bits(target), 0x1d00d86a);
+    }
+
+    #[test]
+    fn rejects_negative_and_oversized_bits() {
+        assert!(bits_to_target(0x1d80ffff).is_err());
+        assert!(bits_to_target(0x2200ffff).is_err());
+        assert_eq!(bits_to_target(0x01003456).unwrap(), U256::zero());
+        assert_eq!(bits_to_target(0x02123456).unwrap(), U256::from(0x1234u32));
+    }
+
+    fn relay_state_at(height: u32, target: U256, timestamp: u32) -> RelayState {
+        RelayState {
+            best_height: height,
+            epoch_start_target: u256_to_dec_string(target),
+            epoch_end_target: u256_to_dec_string(target),
+            epoch_start_time: timestamp,
+            epoch_end_time: timestamp,
+            epoch_anchored: is_period_start(height),
+            max_version: u32::MAX,
+            ..Default::default()
+        }
+    }
+
+    fn mine_header(bits: u32) -> [u8; 80] {
+        let mut header = [0u8; 80];
+        header[0..4].copy_from_slice(&0x2000_0000u32.to_le_bytes());
+        header[72..76].copy_from_slice(&bits.to_le_bytes());
+        let target = bits_to_target(bits).unwrap();
+        for nonce in 0u32.. {
+            header[76..80].copy_from_slice(&nonce.to_le_bytes());
+            if hash_to_u256_le(&hash256(&header)) <= target {
+                break;
+            }
+        }
+        header
+    }
+
+    #[test]
+    fn oracle_target_replaces_difficulty_checks() {
+        let target = bits_to_target(0x207fffff).unwrap();
+        // the period's target differs, only the oracle's target makes the header acceptable
+        let relay_state = relay_state_at(100, target >> 8u32, 1_700_000_000);
+        let prev_header = Header { height: 100, chain_id: MAIN_CHAIN_ID, ..Default::default() };
+        let header = mine_header(0x207fffff);
+        let hash = hash256(&header);
+        let validate = |oracle_target| {
+            validate_block_header(&relay_state, &prev_header, &header, hash, MAIN_CHAIN_ID, 101, oracle_target)
+        };
+
+        assert!(validate(None).is_err());
+        assert!(validate(Some(target)).is_ok());
+        assert!(validate(Some(hash_to_u256_le(&hash) - U256::one())).is_err());
+    }
+
+    #[test]
+    fn assumevalid_skips_target_checks_up_to_its_height() {
+        let target = bits_to_target(0x207fffff).unwrap();
+        // the header's target is not the period's, it is accepted only where assumed valid
+        let mut relay_state = relay_state_at(100, target >> 8u32, 1_700_000_000);
+        let prev_header = Header { height: 100, chain_id: MAIN_CHAIN_ID, ..Default::default() };
+        let header = mine_header(0x207fffff);
+        let hash = hash256(&header);
+        let validate = |relay_state: &RelayState| {
+            validate_block_header(relay_state, &prev_header, &header, hash, MAIN_CHAIN_ID, 101, None)
+        };
+        assert_eq!(validate(&relay_state).unwrap_err(), error!(RelayError::UnexpectedTargetChange));
+
+        relay_state.assumevalid = hash;
+        relay_state.assumevalid_height = 101;
+        assert_eq!(validate(&relay_state).unwrap(), target);
+        // any other block at the assumevalid height is rejected, not trusted
+        relay_state.assumevalid = [3u8; 32];
+        assert_eq!(validate(&relay_state).unwrap_err(), error!(RelayError::NotAssumevalidBlock));
+        // below it blocks are trusted until a chain reaches the assumevalid height
+        relay_state.assumevalid_height = 102;
+        assert_eq!(validate(&relay_state).unwrap(), target);
+        relay_state.assumevalid_height = 100;
+        assert!(validate(&relay_state).is_err());
+        // unset, whatever the height
+        relay_state.assumevalid = [0u8; 32];
+        relay_state.assumevalid_height = u32::MAX;
+        assert!(validate(&relay_state).is_err());
+    }
+
+    #[test]
+    fn oracle_mode_requires_a_published_target() {
+        let mut relay_state = relay_state_at(100, U256::one(), 1_700_000_000);
+        assert_eq!(oracle_target(&relay_state, &None).unwrap(), None);
+        relay_state.oracle_mode = true;
+        assert!(oracle_target(&relay_state, &None).is_err());
+    }
+
+    #[test]
+    fn reports_headers_missing_from_the_height_index() {
+        let hash = [7u8; 32];
+        let unset = Header::default();
+        let stored = Header { height: 101, chain_id: MAIN_CHAIN_ID, ..Default::default() };
+        let indexed = BlockHash { block_hash: hash };
+        let empty = BlockHash { block_hash: [0u8; 32] };
+
+        // a fresh header, even at a height with a stale index entry
+        assert!(check_not_stored(&unset, &empty, hash, 101).is_ok());
+        assert!(check_not_stored(&unset, &BlockHash { block_hash: [9u8; 32] }, hash, 101).is_ok());
+        // a consistent duplicate
+        assert_eq!(check_not_stored(&stored, &indexed, hash, 101).unwrap_err(), error!(RelayError::DuplicateBlock));
+        // a main-chain header the index does not point to
+        assert_eq!(check_not_stored(&stored, &empty, hash, 101).unwrap_err(), error!(RelayError::InconsistentState));
+        // fork headers are not indexed
+        let fork = Header { chain_id: MAIN_CHAIN_ID + 1, ..stored };
+        assert_eq!(check_not_stored(&fork, &empty, hash, 101).unwrap_err(), error!(RelayError::DuplicateBlock));
+    }
+
+    #[test]
+    fn strict_mode_only_accepts_main_chain_extensions() {
+        let mut relay_state = relay_state_at(100, U256::one(), 1_700_000_000);
+        assert!(check_fork_allowed(&relay_state, false).is_ok());
+        relay_state.strict_no_fork = true;
+        assert!(check_fork_allowed(&relay_state, true).is_ok());
+        assert!(check_fork_allowed(&relay_state, false).is_err());
+    }
+
+    #[test]
+    fn skips_first_retarget_after_unaligned_genesis() {
+        let target = U256::from(0xffffu32) << 208u32;
+        let mut relay_state = relay_state_at(100, target, 1_700_000_000);
+        assert!(!relay_state.epoch_anchored);
+
+        // the anchors do not cover a full period, any target is accepted at the first start
+        assert!(check_retarget(&relay_state, target >> 4u32).is_ok());
+        update_epoch(&mut relay_state, 2016, target >> 4u32, 1_701_000_000);
+        assert!(relay_state.epoch_anchored);
+        assert_eq!(relay_state.epoch_start_time, 1_701_000_000);
+
+        // from then on retargets are validated
+        update_epoch(&mut relay_state, 4031, target >> 4u32, 1_702_209_600);
+        assert!(check_retarget(&relay_state, target).is_err());
+    }
+
+    #[test]
+    fn reads_the_current_target_after_a_period_start() {
+        let target = U256::from(0xffffu32) << 208u32;
+        let mut relay_state = relay_state_at(4031, target, 1_700_000_000);
+
+        update_epoch(&mut relay_state, 4032, target >> 1u32, 1_701_209_600);
+        assert!(relay_state.epoch_end_target.is_empty());
+        assert_eq!(current_target(&relay_state).unwrap(), target >> 1u32);
+        assert_eq!(target_to_difficulty(current_target(&relay_state).unwrap()), U256::from(2));
+
+        relay_state.epoch_start_target = String::new();
+        assert_eq!(current_target(&relay_state).unwrap_err(), error!(RelayError::InvalidDifficultyPeriod));
+    }
+
+    #[test]
+    fn reports_epoch_progress_within_the_period() {
+        let target = U256::from(0xffffu32) << 208u32;
+        for (height, into_period) in [(2016, 0), (2017, 1), (4031, 2015), (4032, 0), (840_000, 1344)] {
+            let progress = epoch_progress(&relay_state_at(height, target, 1_700_000_000));
+            assert_eq!(progress.blocks_into_period, into_period);
+            assert_eq!(progress.period_length, DIFFICULTY_ADJUSTMENT_INTERVAL);
+            assert_eq!(progress.epoch_start_time, 1_700_000_000);
+        }
+    }
+
+    #[test]
+    fn counts_blocks_until_finality() {
+        let best_height = 1_000;
+        let finalized_height = best_height - FINALITY_DEPTH;
+        assert_eq!(confirmations_until_final(best_height, best_height), Some(FINALITY_DEPTH));
+        assert_eq!(confirmations_until_final(best_height, best_height - 3), Some(FINALITY_DEPTH - 3));
+        assert_eq!(confirmations_until_final(best_height, finalized_height + 1), Some(1));
+        assert_eq!(confirmations_until_final(best_height, finalized_height), Some(0));
+        assert_eq!(confirmations_until_final(best_height, 1), Some(0));
+        assert_eq!(confirmations_until_final(best_height, best_height + 1), None);
+    }
+
+    #[test]
+    fn validates_first_retarget_after_aligned_genesis() {
+        let target = U256::from(0xffffu32) << 208u32;
+        let mut relay_state = relay_state_at(2016, target, 1_700_000_000);
+        assert!(relay_state.epoch_anchored);
+
+        update_epoch(&mut relay_state, 4031, target, 1_701_209_600);
+        assert!(check_retarget(&relay_state, target >> 4u32).is_err());
+    }
+
+    #[test]
+    fn caps_slow_periods_at_the_pow_limit() {
+        // Bitcoin Core `get_next_work_pow_limit`: a 4x slow period at difficulty 1 would
+        // quadruple the target, the powLimit cap keeps it at 0x1d00ffff
+        let previous_target = bits_to_target(0x1d00ffff).unwrap();
+        let target = retarget_algorithm(previous_target, 1231006505, 1233061996).unwrap();
+        assert_eq!(target, U256::from_str_radix(POW_LIMIT, 16).unwrap());
+        assert_eq!(target_to_bits(target), 0x1d00ffff);
+    }
+
+    #[test]
+    fn clamps_timespan_at_both_extremes() {
+        // Bitcoin Core `get_next_work_upper_limit_actual`: more than 4x slow, clamped to 4x
+        let target = retarget_algorithm(bits_to_target(0x1c387f6f).unwrap(), 1263163443, 1269211443).unwrap();
+        assert_eq!(target_to_bits(target), 0x1d00e1fd);
+        // Bitcoin Core `get_next_work_lower_limit_actual`: more than 4x fast, clamped to 1/4
+        let target = retarget_algorithm(bits_to_target(0x1c05a3f4).unwrap(), 1279008237, 1279297671).unwrap();
+        assert_eq!(target_to_bits(target), 0x1c0168fd);
+    }
+
+    #[test]
+    fn bounds_retargets_to_a_factor_of_four() {
+        let prev_target = bits_to_target(0x1c05a3f4).unwrap();
+        for target in [prev_target * U256::from(3u32), prev_target / U256::from(3u32)] {
+            assert!(within_retarget_bounds(prev_target, bits_to_target(target_to_bits(target)).unwrap()));
+        }
+        for target in [prev_target * U256::from(5u32), prev_target / U256::from(5u32)] {
+            assert!(!within_retarget_bounds(prev_target, bits_to_target(target_to_bits(target)).unwrap()));
+        }
+        // both clamped extremes are within bounds
+        for timespan in [0, RETARGET_PERIOD * 4] {
+            let target = retarget_algorithm(prev_target, 0, timespan).unwrap();
+            assert!(within_retarget_bounds(prev_target, bits_to_target(target_to_bits(target)).unwrap()));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_first_retarget() {
+        // the first retarget after an unaligned genesis is not recomputed, only bounded
+        let target = bits_to_target(0x1d00ffff).unwrap() >> 4u32;
+        let relay_state = relay_state_at(100, target, 1_700_000_000);
+        assert!(check_target(&relay_state, 2016, target * U256::from(3u32)).is_ok());
+        assert!(check_target(&relay_state, 2016, target * U256::from(5u32)).is_err());
+    }
+
+    #[test]
+    fn rejects_mid_period_target_changes() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        let relay_state = relay_state_at(2100, target, 1_700_000_000);
+        assert!(check_target(&relay_state, 2101, target).is_ok());
+        // one off in the compact form, either way
+        for bits in [0x1d00fffe, 0x1d010000] {
+            assert_eq!(
+                check_target(&relay_state, 2101, bits_to_target(bits).unwrap()).unwrap_err(),
+                error!(RelayError::UnexpectedTargetChange)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_retargets_overflowing_256_bits() {
+        // regtest bits, far above POW_LIMIT
+        let previous_target = bits_to_target(0x207fffff).unwrap();
+        assert_eq!(
+            retarget_algorithm(previous_target, 0, RETARGET_PERIOD).unwrap_err(),
+            error!(RelayError::ArithmeticError)
+        );
+        assert!(is_correct_difficulty_target(previous_target, 0, previous_target, RETARGET_PERIOD, previous_target).is_err());
+    }
+
+    #[test]
+    fn clamps_negative_timespan_to_minimum() {
+        let previous_target = bits_to_target(0x1d00ffff).unwrap();
+        let minimum = retarget_algorithm(previous_target, 0, RETARGET_PERIOD / 4).unwrap();
+        assert_eq!(retarget_algorithm(previous_target, 1262152739, 1261130161).unwrap(), minimum);
+        assert_eq!(retarget_algorithm(previous_target, 1261130161, 1261130161).unwrap(), minimum);
+        assert_eq!(minimum, previous_target / U256::from(4u32));
+    }
+
+    #[test]
+    fn relay_state_space_fits_largest_contents() {
+        let max_target = u256_to_dec_string(U256::MAX);
+        assert_eq!(max_target.len(), MAX_TARGET_DIGITS);
+        let relay_state = RelayState {
+            epoch_start_target: max_target.clone(),
+            epoch_end_target: max_target,
+            active_fork_ids: vec![u32::MAX; MAX_FORKS],
+            ..Default::default()
+        };
+
+        let mut data = Vec::new();
+        relay_state.try_serialize(&mut data).unwrap();
+        assert!(data.len() <= RelayState::SPACE);
+    }
+
+    #[test]
+    fn separates_relay_instances_by_epoch() {
+        let block_hash = [7u8; 32];
+        assert_ne!(header_address(0, &block_hash), header_address(1, &block_hash));
+        assert_ne!(chain_address(0, 100), chain_address(1, 100));
+        assert_ne!(fork_address(0, MAIN_CHAIN_ID), fork_address(1, MAIN_CHAIN_ID));
+    }
+
+    #[test]
+    fn state_snapshot_fits_return_data() {
+        let max_target = u256_to_dec_string(U256::MAX);
+        let relay_state = RelayState {
+            epoch_start_target: max_target.clone(),
+            epoch_end_target: max_target,
+            active_fork_ids: vec![u32::MAX; MAX_FORKS],
+            ..Default::default()
+        };
+
+        let snapshot = StateSnapshot::from(&relay_state);
+        assert_eq!(snapshot.active_fork_ids, relay_state.active_fork_ids);
+        assert!(snapshot.try_to_vec().unwrap().len() <= anchor_lang::solana_program::program::MAX_RETURN_DATA);
+    }
+
+    /// A coinbase in witness serialization paying 50 BTC to `OP_TRUE`, with the given
+    /// commitment output scripts and a 32-byte witness reserved value.
+    fn segwit_coinbase(commitment_scripts: &[Vec<u8>], reserved_value: [u8; 32]) -> Vec<u8> {
+        let mut tx = vec![2, 0, 0, 0, 0x00, 0x01, 1];
+        tx.extend([0u8; 32]);
+        tx.extend([0xff; 4]);
+        tx.extend([4, 0x03, 0x20, 0x5a, 0x07]);
+        tx.extend([0xff; 4]);
+        tx.push(1 + commitment_scripts.len() as u8);
+        tx.extend(5_000_000_000u64.to_le_bytes());
+        tx.extend([1, 0x51]);
+        for script in commitment_scripts {
+            tx.extend([0u8; 8]);
+            tx.push(script.len() as u8);
+            tx.extend(script);
+        }
+        tx.extend([1, 32]);
+        tx.extend(reserved_value);
+        tx.extend([0u8; 4]);
+        tx
+    }
+
+    fn commitment_script(witness_root: [u8; 32], reserved_value: [u8; 32]) -> Vec<u8> {
+        [&WITNESS_COMMITMENT_HEADER[..], &hash256(&[witness_root, reserved_value].concat())[..]].concat()
+    }
+
+    #[test]
+    fn parses_the_witness_commitment_of_a_coinbase() {
+        let witness_root = hash256(b"wtxids");
+        let reserved_value = [0u8; 32];
+        let tx = segwit_coinbase(&[commitment_script(witness_root, reserved_value)], reserved_value);
+        let (commitment, reserved) = parse_witness_commitment(&tx).unwrap();
+        assert_eq!(commitment, Some(hash256(&[witness_root, reserved_value].concat())));
+        assert_eq!(reserved, reserved_value);
+
+        // the last commitment output counts, with the reserved value from the witness
+        let other_root = hash256(b"other wtxids");
+        let reserved_value = [9u8; 32];
+        let scripts = [commitment_script(witness_root, reserved_value), commitment_script(other_root, reserved_value)];
+        let tx = segwit_coinbase(&scripts, reserved_value);
+        assert_eq!(parse_witness_commitment(&tx).unwrap().0, Some(hash256(&[other_root, reserved_value].concat())));
+
+        // no commitment output
+        assert_eq!(parse_witness_commitment(&segwit_coinbase(&[], reserved_value)).unwrap().0, None);
+    }
+
+    #[test]
+    fn rejects_malformed_segwit_coinbases() {
+        let reserved_value = [0u8; 32];
+        let tx = segwit_coinbase(&[commitment_script([1u8; 32], reserved_value)], reserved_value);
+
+        // the non-witness serialization carries no reserved value
+        let mut legacy = tx.clone();
+        legacy.drain(4..6);
+        assert!(parse_witness_commitment(&legacy).is_err());
+        assert!(parse_witness_commitment(&tx[..tx.len() - 1]).is_err());
+        assert!(parse_witness_commitment(&[tx.as_slice(), &[0u8][..]].concat()).is_err());
+        let mut not_coinbase = tx.clone();
+        not_coinbase[7] = 1;
+        assert!(parse_witness_commitment(&not_coinbase).is_err());
+    }
+
+    #[test]
+    fn parses_variable_length_bip34_heights() {
+        assert_eq!(parse_bip34_height(&[0x60]).unwrap(), 16);
+        assert_eq!(parse_bip34_height(&[0x01, 0x64, 0xff]).unwrap(), 100);
+        // 128 needs a second byte to keep the sign bit clear
+        assert_eq!(parse_bip34_height(&[0x02, 0x80, 0x00]).unwrap(), 128);
+        assert_eq!(parse_bip34_height(&[0x02, 0x2c, 0x01]).unwrap(), 300);
+        // the first mainnet block enforcing BIP34
+        assert_eq!(parse_bip34_height(&[0x03, 0xfc, 0x79, 0x03, 0x2f]).unwrap(), 227_836);
+
+        assert!(parse_bip34_height(&[0x01, 0x80]).is_err());
+        assert!(parse_bip34_height(&[0x03, 0xfc, 0x79]).is_err());
+        assert!(parse_bip34_height(&[]).is_err());
+    }
+
+    #[test]
+    fn reports_the_first_failing_verify_check() {
+        let relay_state = relay_state_at(110, U256::one(), 1_700_000_000);
+        let txid = [5u8; 32];
+        // a single transaction is its own merkle root
+        let mut header = mine_header(0x207fffff);
+        header[36..68].copy_from_slice(&txid);
+        let stored = Some(hash256(&header));
+        let failure = |stored_hash, height, txid, confirmations| {
+            verify_failure(&relay_state, stored_hash, height, &header, txid, 0, &[], confirmations)
+        };
+
+        assert_eq!(failure(stored, 105, txid, 6), None);
+        assert_eq!(VerifyResult::from(failure(stored, 105, txid, 6)), VerifyResult { ok: true, reason: 0 });
+        assert_eq!(failure(stored, 111, txid, 1), Some(VerifyFailure::BlockNotFound));
+        assert_eq!(failure(None, 105, txid, 1), Some(VerifyFailure::BlockNotFound));
+        assert_eq!(failure(Some([9u8; 32]), 105, txid, 1), Some(VerifyFailure::NotMainChain));
+        assert_eq!(failure(stored, 105, txid, 7), Some(VerifyFailure::TooFewConfirmations));
+        assert_eq!(failure(stored, 105, [6u8; 32], 1), Some(VerifyFailure::ProofMismatch));
+        assert_eq!(VerifyResult::from(Some(VerifyFailure::NotMainChain)), VerifyResult { ok: false, reason: 4 });
+    }
+
+    #[test]
+    fn verify_cu_estimate_scales_with_proof_depth() {
+        assert_eq!(estimate_verify_cu(0), VERIFY_TX_BASE_CU);
+        for levels in 1..=20 {
+            let estimate = estimate_verify_cu(32 * levels);
+            assert_eq!(estimate - estimate_verify_cu(32 * (levels - 1)), HASH256_CU);
+            assert_eq!(estimate, VERIFY_TX_BASE_CU + levels as u32 * HASH256_CU);
+        }
+        // a trailing partial node adds no level
+        assert_eq!(estimate_verify_cu(32 * 3 + 31), estimate_verify_cu(32 * 3));
+        assert_eq!(estimate_verify_cu(usize::MAX), u32::MAX);
+    }
+
+    // parses a hash in the hex order block explorers display
+    fn display_hash(hex: &str) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        crate::byteorder::display_to_internal(hash)
+    }
+
+    #[test]
+    fn verifies_merkle_branches_against_a_known_root() {
+        // the four transactions of mainnet block 100000
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]
+        .map(display_hash);
+        let root = display_hash("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766");
+        assert_eq!(merkle_root_of(&txids), root);
+
+        let proof = [txids[3], hash256(&[txids[0], txids[1]].concat())].concat();
+        assert!(proves_inclusion(txids[2], 2, &proof, root));
+
+        let mut tampered = proof.clone();
+        tampered[0] ^= 1;
+        assert!(!proves_inclusion(txids[2], 2, &tampered, root));
+        assert!(!proves_inclusion(txids[2], 3, &proof, root));
+        assert!(!proves_inclusion(txids[3], 2, &proof, root));
+        assert!(!proves_inclusion(txids[2], 2, &proof[..63], root));
+        assert!(!proves_inclusion(txids[2], 2, &proof, txids[0]));
+    }
+
+    #[test]
+    fn caps_batches_at_the_account_limit() {
+        assert!(check_batch_size(MAX_BATCH_SIZE).is_ok());
+        assert_eq!(check_batch_size(MAX_BATCH_SIZE + 1).unwrap_err(), error!(RelayError::InvalidHeaderBatch));
+    }
+
+    #[test]
+    fn deepest_reorg_fits_the_account_limit() {
+        // the fixed accounts, then three per promoted block below the tip
+        assert!(13 + 3 * (MAX_REORG_DEPTH as usize - 1) <= MAX_TX_ACCOUNTS);
+        assert!(13 + 3 * MAX_REORG_DEPTH as usize > MAX_TX_ACCOUNTS);
+        // fresh fork accounts hold MAX_FORK_LENGTH descendants before they grow
+        assert!(Fork::space(MAX_FORK_LENGTH as usize) <= size_of::<Fork>() + 8 + 32 * MAX_FORK_LENGTH as usize);
+    }
+
+    #[test]
+    fn full_batches_fit_the_maximum_compute_budget() {
+        // 1.4M units is the most a transaction can request, a batch stopping early only saves
+        // the headers a lower limit cannot cover
+        assert!(MAX_BATCH_SIZE as u64 * BATCH_HEADER_CU + BATCH_FINISH_CU <= 1_400_000);
+    }
+
+    #[test]
+    fn counts_timestamps_not_above_the_median_time_past() {
+        let mut relay_state = RelayState::default();
+        // early blocks are compared against a window padded with zeros
+        for height in 0..MEDIAN_TIME_SPAN {
+            record_main_timestamp(&mut relay_state, height, 1_000_000 + height * 600);
+        }
+        assert_eq!(relay_state.timestamp_anomaly_count, 0);
+
+        // the median of heights 0..=10 is the timestamp of height 5
+        let median = 1_000_000 + 5 * 600;
+        record_main_timestamp(&mut relay_state, 11, median + 1);
+        assert_eq!(relay_state.timestamp_anomaly_count, 0);
+        record_main_timestamp(&mut relay_state, 12, median);
+        assert_eq!(relay_state.timestamp_anomaly_count, 1);
+        assert_eq!(relay_state.recent_timestamps[(12 % MEDIAN_TIME_SPAN) as usize], median);
+    }
+
+    #[test]
+    fn detects_fork_storms_and_decays_the_count() {
+        let mut relay_state = RelayState::default();
+        let height = 10 * FORK_STORM_WINDOW;
+        for chain_id in 2..FORK_STORM_THRESHOLD + 2 {
+            assert_eq!(record_fork_creation(&mut relay_state, chain_id, height), None);
+        }
+        assert_eq!(relay_state.recent_fork_creations, FORK_STORM_THRESHOLD);
+        assert_eq!(
+            record_fork_creation(&mut relay_state, 10, height + 1),
+            Some(ForkStormDetected { recent_fork_creations: FORK_STORM_THRESHOLD + 1, chain_id: 10, height: height + 1 })
+        );
+
+        // an older branch point counts without decaying
+        assert!(record_fork_creation(&mut relay_state, 11, height - 1).is_some());
+        assert_eq!(relay_state.recent_fork_creations, FORK_STORM_THRESHOLD + 2);
+
+        // one window later the count has halved, long after it has decayed away
+        assert_eq!(record_fork_creation(&mut relay_state, 12, height + FORK_STORM_WINDOW), None);
+        assert_eq!(relay_state.recent_fork_creations, (FORK_STORM_THRESHOLD + 2) / 2 + 1);
+        record_fork_creation(&mut relay_state, 13, height + 40 * FORK_STORM_WINDOW);
+        assert_eq!(relay_state.recent_fork_creations, 1);
+    }
+
+    #[test]
+    fn caps_active_forks_at_max_forks() {
+        let mut relay_state = RelayState { active_fork_ids: (2..MAX_FORKS as u32 + 1).collect(), ..Default::default() };
+        assert_eq!(relay_state.active_fork_ids.len(), MAX_FORKS - 1);
+        assert!(check_fork_capacity(&relay_state).is_ok());
+        relay_state.active_fork_ids.push(MAX_FORKS as u32 + 1);
+        assert_eq!(check_fork_capacity(&relay_state).unwrap_err(), error!(RelayError::TooManyForks));
+    }
+
+    #[test]
+    fn pairs_each_rewritten_height_with_its_old_and_new_hash() {
+        let replaced = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        // the promoted fork reaches above the old tip
+        let promoted = [[11u8; 32], [12u8; 32], [13u8; 32], [14u8; 32]];
+        let swaps = height_swaps(500, &replaced, &promoted);
+        assert_eq!(swaps.len(), 3);
+        for (i, swap) in swaps.iter().enumerate() {
+            assert_eq!(swap, &HeightReorged { height: 500 + i as u32, old_hash: replaced[i], new_hash: promoted[i] });
+        }
+    }
+
+    #[test]
+    fn lists_the_period_starts_of_a_range() {
+        assert_eq!(period_starts(2015, 2).collect::<Vec<_>>(), vec![2016]);
+        assert_eq!(period_starts(2016, 2017).collect::<Vec<_>>(), vec![2016, 4032]);
+        assert_eq!(period_starts(2017, 2015).count(), 0);
+        assert_eq!(period_starts(u32::MAX - 10, 100).count(), 0);
+        assert_eq!(period_starts(0, u32::MAX).len(), (u32::MAX / DIFFICULTY_ADJUSTMENT_INTERVAL + 1) as usize);
+        assert!(period_starts(0, MAX_DIFFICULTY_SERIES as u32 * DIFFICULTY_ADJUSTMENT_INTERVAL).len() <= MAX_DIFFICULTY_SERIES);
+    }
+
+    #[test]
+    fn reports_differing_difficulties_across_periods() {
+        // mainnet's first period and one of mid 2010
+        let series: Vec<U256> = [0x1d00ffff, 0x1c05a3f4]
+            .iter()
+            .map(|&bits| target_to_difficulty(bits_to_target(bits).unwrap()))
+            .collect();
+        assert_eq!(series, vec![U256::from(1u32), U256::from(45u32)]);
+        let return_data = (0..MAX_DIFFICULTY_SERIES).map(|_| [0u8; 32]).collect::<Vec<_>>().try_to_vec().unwrap();
+        assert!(return_data.len() <= anchor_lang::solana_program::program::MAX_RETURN_DATA);
+    }
+
+    #[test]
+    fn index_must_fit_proof_depth() {
+        assert!(index_fits_proof(0, 0));
+        assert!(!index_fits_proof(1, 0));
+        assert!(index_fits_proof(3, 2));
+        assert!(!index_fits_proof(4, 2));
+        assert!(index_fits_proof(u32::MAX, 32));
+        assert!(!index_fits_proof(u32::MAX, 31));
+        assert!(index_fits_proof(u32::MAX, 100));
+    }
+
+    #[test]
+    fn folds_high_indices_by_their_bits() {
+        let txid = [1u8; 32];
+        let siblings: Vec<[u8; 32]> = (0..32u8).map(|level| [level + 2; 32]).collect();
+        let proof = siblings.concat();
+
+        // 0xfffffffe: the leaf is a left child, every node above it a right child
+        let mut expected = hash256(&[txid, siblings[0]].concat());
+        for sibling in &siblings[1..] {
+            expected = hash256(&[*sibling, expected].concat());
+        }
+        assert_eq!(compute_merkle_root(txid, u32::MAX - 1, &proof).unwrap(), expected);
+        assert!(proves_inclusion(txid, u32::MAX - 1, &proof, expected));
+        assert!(!proves_inclusion(txid, u32::MAX, &proof, expected));
+        // the top bit picks the side of the last level only
+        assert!(!proves_inclusion(txid, (u32::MAX - 1) & !(1 << 31), &proof, expected));
+    }
+
+    #[test]
+    fn work_since_weights_blocks_by_difficulty() {
+        let easy = calculate_work(bits_to_target(0x1d00ffff).unwrap()).unwrap();
+        let hard = calculate_work(bits_to_target(0x1c7fff80).unwrap()).unwrap();
+        assert_eq!(hard, easy * U256::from(2u32));
+
+        // two more blocks at difficulty 1, then a retarget doubling it for two more
+        let header = Header { chain_work: u256_to_le_bytes(easy * U256::from(10u32)), ..Default::default() };
+        let main_work = easy * U256::from(12u32) + hard * U256::from(2u32);
+        assert_eq!(work_since(main_work, &header).unwrap(), easy * U256::from(6u32));
+        assert_eq!(work_since(u256_from_le_bytes(&header.chain_work), &header).unwrap(), U256::zero());
+        assert!(work_since(U256::zero(), &header).is_err());
+    }
+
+    #[test]
+    fn tip_has_one_confirmation() {
+        assert_eq!(block_confirmations(100, 100), Some(1));
+        assert_eq!(block_confirmations(100, 95), Some(6));
+        assert_eq!(block_confirmations(100, 101), None);
+    }
+
+    #[test]
+    fn forks_overtake_by_work_not_height() {
+        let main_target = bits_to_target(0x1d00ffff).unwrap();
+        let blocks = |target: U256, count: u32| calculate_work(target).unwrap() * U256::from(count);
+        let main_work = blocks(main_target, 10);
+
+        // the same height at a quarter of the difficulty has less work
+        assert!(!overtakes_main_chain(blocks(main_target << 2u32, 10), main_work, main_target).unwrap());
+        // the same height at four times the difficulty leads by 30 blocks' worth of work
+        assert!(overtakes_main_chain(blocks(main_target >> 2u32, 10), main_work, main_target).unwrap());
+        // a longer fork does not overtake with less work
+        assert!(!overtakes_main_chain(blocks(main_target << 2u32, 20), main_work, main_target).unwrap());
+    }
+
+    #[test]
+    fn keeps_the_main_chain_until_the_fork_leads_by_the_margin() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        let block = calculate_work(target).unwrap();
+        let main_work = block * U256::from(100u32);
+
+        // a fork tying the main chain, then leading by fewer than CONFIRMATIONS blocks
+        for lead in 0..CONFIRMATIONS {
+            assert!(!overtakes_main_chain(main_work + block * U256::from(lead), main_work, target).unwrap());
+        }
+        assert!(overtakes_main_chain(main_work + block * U256::from(CONFIRMATIONS), main_work, target).unwrap());
+    }
+
+    #[test]
+    fn forks_overtake_by_work_at_any_height() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        let block = calculate_work(target).unwrap();
+        let mut relay_state = relay_state_at(100, target, 0);
+        relay_state.chain_work = u256_to_le_bytes(block * U256::from(100u32));
+        let lighter = block * U256::from(99u32);
+        // enough work to overtake, which a fork mined at a higher difficulty has at equal height
+        let heavier = block * U256::from(100 + 4 * CONFIRMATIONS);
+
+        assert!(!fork_overtakes(&relay_state, 100, 95, lighter).unwrap());
+        assert!(fork_overtakes(&relay_state, 100, 95, heavier).unwrap());
+        assert!(fork_overtakes(&relay_state, 98, 95, heavier).unwrap());
+    }
+
+    #[test]
+    fn waits_for_forks_that_cannot_replay_the_anchors() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        let mut relay_state = relay_state_at(4040, target, 0);
+        relay_state.chain_work = u256_to_le_bytes(U256::zero());
+        let heavier = calculate_work(target).unwrap() * U256::from(100u32);
+
+        // the main chain started the period at 4032 above the fork tip
+        assert!(fork_overtakes(&relay_state, 4030, 2010, heavier).unwrap());
+        assert!(!fork_overtakes(&relay_state, 4030, 2020, heavier).unwrap());
+        // a fork reaching the main chain's period start replays it
+        assert!(fork_overtakes(&relay_state, 4032, 4020, heavier).unwrap());
+    }
+
+    #[test]
+    fn adopts_the_parent_blocks_up_to_a_demoted_ancestor() {
+        let hashes: Vec<[u8; 32]> = (0..6u8).map(|i| [i; 32]).collect();
+        let fork_of = |height, ancestor, descendants: &[[u8; 32]]| Fork {
+            height,
+            ancestor,
+            descendants: descendants.to_vec(),
+            bond: 0,
+            bond_owner: Pubkey::default(),
+        };
+        // the demoted main chain 1..=3 off block 0, and a fork 4..=5 branching off block 2
+        let mut parent = fork_of(103, hashes[0], &hashes[1..4]);
+        let mut fork = fork_of(104, hashes[2], &hashes[4..]);
+
+        assert_eq!(adopt_parent_blocks(&mut fork, &mut parent).unwrap(), 2);
+        assert_eq!(fork.ancestor, hashes[0]);
+        assert_eq!(fork.descendants, [hashes[1], hashes[2], hashes[4], hashes[5]]);
+        assert_eq!(parent.ancestor, hashes[2]);
+        assert_eq!(parent.descendants, [hashes[3]]);
+        assert_eq!(parent.height, 103);
+
+        // a fork off the parent's tip, or off a block the parent does not hold, is not adopted
+        let mut parent = fork_of(103, hashes[0], &hashes[1..4]);
+        for ancestor in [hashes[3], hashes[5]] {
+            let mut fork = fork_of(104, ancestor, &hashes[4..5]);
+            assert_eq!(adopt_parent_blocks(&mut fork, &mut parent).unwrap_err(), error!(RelayError::NestedFork));
+        }
+    }
+
+    #[test]
+    fn overtakes_main_chain_without_overflow() {
+        let target = bits_to_target(0x1d00ffff).unwrap();
+        let block = calculate_work(target).unwrap();
+        let margin = block * U256::from(CONFIRMATIONS);
+        assert!(!overtakes_main_chain(U256::MAX, U256::MAX - U256::one(), target).unwrap());
+        assert!(!overtakes_main_chain(U256::MAX, U256::MAX - margin + U256::one(), target).unwrap());
+        assert!(overtakes_main_chain(U256::MAX, U256::MAX - margin, target).unwrap());
+        assert!(!overtakes_main_chain(margin - U256::one(), U256::zero(), target).unwrap());
+        assert!(overtakes_main_chain(margin, U256::zero(), target).unwrap());
+    }
+
+    #[test]
+    fn forks_overtake_exactly_when_confirmations_ahead() {
+        let target = bits_to_target(0x207fffff).unwrap();
+        let block = calculate_work(target).unwrap();
+        let mut seed = 0x9e37_79b9u32;
+        for _ in 0..200 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let main_len = 1 + seed % 24;
+            let fork_point = (seed >> 8) % (main_len + 1);
+            let fork_len = 1 + (seed >> 16) % 24;
+
+            // both branches at a constant target on top of the block at height 1000
+            let (main_height, fork_height) = (1000 + main_len, 1000 + fork_point + fork_len);
+            let main_work = block * U256::from(main_len);
+            let fork_work = block * U256::from(fork_point + fork_len);
+            let mut relay_state = relay_state_at(main_height, target, 0);
+            relay_state.chain_work = u256_to_le_bytes(main_work);
+
+            let expected = fork_height >= main_height + CONFIRMATIONS;
+            assert_eq!(overtakes_main_chain(fork_work, main_work, target).unwrap(), expected);
+            assert_eq!(fork_overtakes(&relay_state, fork_height, 1001 + fork_point, fork_work).unwrap(), expected);
+            // at a constant target the longer chain is the heavier one
+            assert_eq!(fork_height.cmp(&main_height), fork_work.cmp(&main_work));
+        }
+    }
+}
@@ -1,24 +1,84 @@
 use anchor_lang::prelude::*;
 use std::mem::size_of;
+use crate::errors::RelayError;
 
-// chain id must != 0
-pub const CHAIN_ID: u32 = 10;
 pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 2016;
-pub const DIFF1_TARGET: &str = "ffff0000000000000000000000000000000000000000000000000000";
+// difficulty-1 target (compact 0x1d00ffff), hex at the full 32-byte width
+pub const DIFF1_TARGET: &str = "00000000ffff0000000000000000000000000000000000000000000000000000";
+// mainnet powLimit, the easiest target a retarget may produce
+pub const POW_LIMIT: &str = "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
 pub const RETARGET_PERIOD: u32 = 1209600; // 2 weeks in seconds
+// a retarget moves the target by at most this factor either way
+pub const MAX_RETARGET_FACTOR: u32 = 4;
 pub const CONFIRMATIONS: u32 = 6;
+pub const MEDIAN_TIME_SPAN: u32 = 11;
+// main-chain blocks after which an abandoned fork's bond can be forfeited, about one day
+pub const FORK_BOND_TIMEOUT: u32 = 144;
+// heights over which `recent_fork_creations` halves, about one day
+pub const FORK_STORM_WINDOW: u32 = 144;
+// recent fork creations above which every new fork emits `ForkStormDetected`
+pub const FORK_STORM_THRESHOLD: u32 = 4;
+// BIP9 version bits signalling
+pub const VERSIONBITS_TOP_MASK: u32 = 0xE0000000;
+pub const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+pub const VERSIONBITS_NUM_BITS: u8 = 29;
+// 80-byte headers that fit a transaction next to the accounts of `SubmitHeaderChain`, the PDAs
+// of all but the last header going through an address lookup table
+pub const MAX_HEADER_CHAIN_LENGTH: usize = 12;
+// accounts a single transaction may reference
+pub const MAX_TX_ACCOUNTS: usize = 64;
+// headers of `submit_block_header_batch`: two PDAs each next to its six accounts and the program
+// id. Within the 1232-byte transaction only lookup tables make room for the keys of that many
+pub const MAX_BATCH_SIZE: usize = (MAX_TX_ACCOUNTS - 7) / 2;
+// every header's PDAs plus the fixed accounts still fit a transaction
+const _: () = assert!(2 * MAX_BATCH_SIZE + 7 <= MAX_TX_ACCOUNTS);
+// approximate compute units of one header of a batch, its validation and two PDA creations
+pub const BATCH_HEADER_CU: u64 = 40_000;
+// compute units kept to finish a batch cut short: moving the tip and writing back the relay state
+pub const BATCH_FINISH_CU: u64 = 30_000;
+// BIP141 witness commitment output script: OP_RETURN, a 36-byte push and the commitment tag
+pub const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+// txids accepted by verify_tx_full_block, the transaction size limit allows little more
+pub const MAX_BLOCK_TXIDS: usize = 32;
+// descendants fork accounts are allocated for, extending a fork beyond grows its account
+pub const MAX_FORK_LENGTH: u32 = 8;
+// heights a reorg can rewrite: every fork block below the tip takes up to three remaining
+// accounts, next to the ten of `submit_block_header`, the main fork, the fork point and the
+// bond owner
+pub const MAX_REORG_DEPTH: u32 = ((MAX_TX_ACCOUNTS - 13) / 3 + 1) as u32;
+// chain id must != 0
 pub const MAIN_CHAIN_ID: u32 = 1;
+// main-chain blocks buried this deep are final and never reorganized
+pub const FINALITY_DEPTH: u32 = 100;
+// approximate compute units of a `verify_tx` call besides its merkle branch, header hash included
+pub const VERIFY_TX_BASE_CU: u32 = 15_000;
+// approximate compute units of one `hash256` of a 64-byte merkle node on-chain
+pub const HASH256_CU: u32 = 4_000;
+// difficulties returned by one `get_difficulty_series` call, within the 1024 bytes of return data
+pub const MAX_DIFFICULTY_SERIES: usize = 31;
+// digits of the largest 256-bit target in decimal, as stored in `RelayState`
+pub const MAX_TARGET_DIGITS: usize = 78;
 
 #[derive(Accounts)]
-#[instruction(genesis_height: u32, genesis_block_hash: [u8; 32])]
+#[instruction(genesis_header: [u8; 80], genesis_height: u32, genesis_block_hash: [u8; 32], config: RelayConfig)]
 pub struct Initialize<'info> {
-    #[account(init, payer = user, space = size_of::<RelayState>() + 8 + 32 * 2, seeds = [b"relay_state"], bump)]
+    // a zero hash is never a digest. Anchor creates each `init` account before running its own
+    // `constraint`, and all of them before any other account's, so the first one rejects it
+    // earliest: after one PDA is created instead of four, the failed transaction undoing it
+    #[account(
+        init,
+        payer = user,
+        space = RelayState::SPACE,
+        seeds = [b"relay_state", config.epoch.to_le_bytes().as_ref()],
+        bump,
+        constraint = genesis_block_hash != [0u8; 32] @ RelayError::InvalidBlockHash,
+    )]
     pub relay_state: Account<'info, RelayState>,
-    #[account(init, payer = user, space = size_of::<Fork>() + 8 + 32 * 8, seeds = [b"fork", CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    #[account(init, payer = user, space = size_of::<Fork>() + 8 + 32 * MAX_FORK_LENGTH as usize, seeds = [b"fork", config.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
     pub fork: Account<'info, Fork>,
-    #[account(init, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", genesis_height.to_le_bytes().as_ref()], bump)]
+    #[account(init, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", config.epoch.to_le_bytes().as_ref(), genesis_height.to_le_bytes().as_ref()], bump)]
     pub chain: Account<'info, BlockHash>,
-    #[account(init, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", genesis_block_hash.as_ref()], bump)]
+    #[account(init, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", config.epoch.to_le_bytes().as_ref(), genesis_block_hash.as_ref()], bump)]
     pub header: Account<'info, Header>,
     #[account(mut)]
     pub user: Signer<'info>,
@@ -26,54 +86,738 @@ pub struct Initialize<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(block_hash: [u8; 32], prev_block_hash: [u8; 32], prev_block_hash_chain_id: u32, block_height: u32, next_counter: u32)] //, 
-pub struct SubmitBlockHeader<'info> {
+#[instruction(tip_header: [u8; 80], best_height: u32, best_block: [u8; 32], config: RelayConfig)]
+pub struct CloneTipTo<'info> {
+    #[account(has_one = authority @ RelayError::Unauthorized)]
+    pub source_relay_state: Account<'info, RelayState>,
+    #[account(init, payer = authority, space = RelayState::SPACE, seeds = [b"relay_state", config.epoch.to_le_bytes().as_ref()], bump)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(init, payer = authority, space = size_of::<Fork>() + 8 + 32 * MAX_FORK_LENGTH as usize, seeds = [b"fork", config.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    pub fork: Account<'info, Fork>,
+    #[account(init, payer = authority, space = size_of::<BlockHash>() + 8, seeds = [b"chain", config.epoch.to_le_bytes().as_ref(), best_height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(init, payer = authority, space = size_of::<Header>() + 8, seeds = [b"header", config.epoch.to_le_bytes().as_ref(), best_block.as_ref()], bump)]
+    pub header: Account<'info, Header>,
     #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+// `raw_header`, not `header`, see `ExtendChain`
+#[instruction(raw_header: [u8; 80], block_hash: [u8; 32], block_height: u32, next_counter: u32)]
+pub struct SubmitBlockHeader<'info> {
+    #[account(mut, constraint = !relay_state.paused @ RelayError::RelayPaused)]
     pub relay_state: Account<'info, RelayState>,
-    #[account(seeds = [b"header", prev_block_hash.as_ref()], bump)]
+    // derived from the header's prevhash, so the parent cannot be misreported
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), raw_header[4..36].as_ref()], bump)]
     pub prev_header: Account<'info, Header>, 
-    #[account(seeds = [b"fork", prev_block_hash_chain_id.to_le_bytes().as_ref()], bump)]
-    pub prev_fork: Account<'info, Fork>,
-    #[account(init_if_needed, payer = user, space = size_of::<Fork>() + 8 + 32 * 8, seeds = [b"fork", next_counter.to_le_bytes().as_ref()], bump)]
+    /// CHECK: the fork may not exist (never created or pruned), so it is loaded in the
+    /// handler to surface `ForkNotFound` instead of Anchor's account-not-initialized error
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), prev_header.chain_id.to_le_bytes().as_ref()], bump)]
+    pub prev_fork: UncheckedAccount<'info>,
+    // only written when the block branches, or holds the old main chain after a reorg;
+    // extending a fork grows `prev_fork` instead
+    // also where a zero hash, never a digest, is rejected: the first account Anchor initializes,
+    // see `Initialize::relay_state`
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = size_of::<Fork>() + 8 + 32 * MAX_FORK_LENGTH as usize,
+        seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), next_counter.to_le_bytes().as_ref()],
+        bump,
+        constraint = block_hash != [0u8; 32] @ RelayError::InvalidBlockHash,
+    )]
     pub fork: Account<'info, Fork>,
-    #[account(init_if_needed, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", block_height.to_le_bytes().as_ref()], bump)]
+    #[account(init_if_needed, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), block_height.to_le_bytes().as_ref()], bump)]
     pub chain: Account<'info, BlockHash>,
-    #[account(init_if_needed, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", block_hash.as_ref()], bump)]
+    #[account(init_if_needed, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), block_hash.as_ref()], bump)]
     pub header: Account<'info, Header>, 
+    // only required in oracle mode
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
     #[account(mut)]
     pub user: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct SubmitBlockHeaderBatch<'info> {
+// `raw_header`, not `header`, see `ExtendChain`
+#[instruction(raw_header: [u8; 80], block_hash: [u8; 32])]
+pub struct SimulateSubmit<'info> {
+    #[account(constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), raw_header[4..36].as_ref()], bump)]
+    pub prev_header: Account<'info, Header>,
+    /// CHECK: loaded in the handler, see `SubmitBlockHeader::prev_fork`
+    #[account(seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), prev_header.chain_id.to_le_bytes().as_ref()], bump)]
+    pub prev_fork: UncheckedAccount<'info>,
+    /// CHECK: usually not initialized yet, only inspected to detect duplicates
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), block_hash.as_ref()], bump)]
+    pub header: UncheckedAccount<'info>,
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
+}
+
+#[derive(Accounts)]
+#[instruction(headers: Vec<[u8; 80]>)]
+pub struct SubmitHeaderChain<'info> {
+    #[account(mut, constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    pub main_fork: Account<'info, Fork>,
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), relay_state.best_block.as_ref()], bump)]
+    pub prev_header: Account<'info, Header>,
+    #[account(init_if_needed, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), (relay_state.best_height + headers.len() as u32).to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(init, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), crate::chain_tip_hash(&headers).as_ref()], bump)]
+    pub header: Account<'info, Header>,
+    // only required in oracle mode
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
     #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(block_hash: [u8; 32])]
+pub struct GetBlockHeader<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, loaded by the handler
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), block_hash.as_ref()], bump)]
+    pub header: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(block_hash: [u8; 32])]
+pub struct HeaderExists<'info> {
     pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, inspected by the handler
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), block_hash.as_ref()], bump)]
+    pub header: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+// not `header`: in the seeds that name would resolve to the `header` account below
+#[instruction(raw_header: [u8; 80])]
+pub struct ExtendChain<'info> {
+    #[account(mut, constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    pub main_fork: Account<'info, Fork>,
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), raw_header[4..36].as_ref()], bump)]
+    pub prev_header: Account<'info, Header>,
+    #[account(init_if_needed, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), (prev_header.height + 1).to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(init, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), crate::hash256(&raw_header).as_ref()], bump)]
+    pub header: Account<'info, Header>,
+    // only required in oracle mode
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(header: [u8; 80])]
+pub struct SubmitOrphan<'info> {
+    #[account(constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(init, payer = user, space = size_of::<Orphan>() + 8, seeds = [b"orphan", relay_state.epoch.to_le_bytes().as_ref(), header[4..36].as_ref()], bump)]
+    pub orphan: Account<'info, Orphan>,
+    #[account(mut)]
     pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(parent_hash: [u8; 32])]
+pub struct ConnectOrphans<'info> {
+    #[account(mut, constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    pub main_fork: Account<'info, Fork>,
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), parent_hash.as_ref()], bump)]
+    pub prev_header: Account<'info, Header>,
+    #[account(mut, close = submitter, seeds = [b"orphan", relay_state.epoch.to_le_bytes().as_ref(), parent_hash.as_ref()], bump)]
+    pub orphan: Account<'info, Orphan>,
+    /// CHECK: only receives the orphan's rent back
+    #[account(mut, address = orphan.submitter)]
+    pub submitter: UncheckedAccount<'info>,
+    #[account(init_if_needed, payer = user, space = size_of::<BlockHash>() + 8, seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), (prev_header.height + 1).to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(init, payer = user, space = size_of::<Header>() + 8, seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), crate::hash256(&orphan.header).as_ref()], bump)]
+    pub header: Account<'info, Header>,
+    // only required in oracle mode
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SubmitBlockHeaderBatch<'info> {
+    #[account(mut, constraint = !relay_state.paused @ RelayError::RelayPaused)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), MAIN_CHAIN_ID.to_le_bytes().as_ref()], bump)]
+    pub main_fork: Account<'info, Fork>,
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), relay_state.best_block.as_ref()], bump)]
+    pub prev_header: Account<'info, Header>,
+    #[account(seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Option<Account<'info, OracleConfig>>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetConfig<'info> {
+    #[account(mut, has_one = authority @ RelayError::Unauthorized)]
+    pub relay_state: Account<'info, RelayState>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct SubmitFilterHeader<'info> {
+    #[account(has_one = authority @ RelayError::Unauthorized)]
+    pub relay_state: Account<'info, RelayState>,
+    // absent only for the first filter header, at the genesis height
+    #[account(seeds = [b"filter_header", relay_state.epoch.to_le_bytes().as_ref(), height.wrapping_sub(1).to_le_bytes().as_ref()], bump)]
+    pub prev_filter_header: Option<Account<'info, FilterHeader>>,
+    // overwritable, so the authority can correct filter headers after a reorg
+    #[account(init_if_needed, payer = authority, space = size_of::<FilterHeader>() + 8, seeds = [b"filter_header", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub filter_header: Account<'info, FilterHeader>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct GetFilterHeader<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"filter_header", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub filter_header: Account<'info, FilterHeader>,
+}
+
+#[derive(Accounts)]
+pub struct SetOracle<'info> {
+    #[account(has_one = authority @ RelayError::Unauthorized)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(init_if_needed, payer = authority, space = size_of::<OracleConfig>() + 8, seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump)]
+    pub oracle_config: Account<'info, OracleConfig>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PublishTarget<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"oracle_config", relay_state.epoch.to_le_bytes().as_ref()], bump, has_one = oracle @ RelayError::Unauthorized)]
+    pub oracle_config: Account<'info, OracleConfig>,
+    pub oracle: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(chain_id: u32, tip_hash: [u8; 32], tip_height: u32)]
+pub struct ForceSetMain<'info> {
+    #[account(mut, has_one = authority @ RelayError::Unauthorized)]
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: loaded in the handler to surface `ForkNotFound`, see `SubmitBlockHeader::prev_fork`
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), chain_id.to_le_bytes().as_ref()], bump)]
+    pub fork: UncheckedAccount<'info>,
+    // holds the old main chain after the reorg
+    #[account(init_if_needed, payer = authority, space = size_of::<Fork>() + 8 + 32 * MAX_FORK_LENGTH as usize, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), (relay_state.chain_counter + 1).to_le_bytes().as_ref()], bump)]
+    pub new_fork: Account<'info, Fork>,
+    #[account(mut, seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), tip_hash.as_ref()], bump)]
+    pub header: Account<'info, Header>,
+    // created when the fork tip was submitted, the reorg writes the tip hash into it
+    #[account(mut, seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), tip_height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chain_id: u32)]
+pub struct ForfeitForkBond<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), chain_id.to_le_bytes().as_ref()], bump)]
+    pub fork: Account<'info, Fork>,
+    /// CHECK: only receives the forfeited bond, must be the relay authority
+    #[account(mut, address = relay_state.authority @ RelayError::Unauthorized)]
+    pub authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(chain_id: u32)]
+pub struct PurgeFork<'info> {
+    #[account(mut)]
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, close = authority, seeds = [b"fork", relay_state.epoch.to_le_bytes().as_ref(), chain_id.to_le_bytes().as_ref()], bump)]
+    pub fork: Account<'info, Fork>,
+    /// CHECK: only receives the reclaimed rent, must be the relay authority
+    #[account(mut, address = relay_state.authority @ RelayError::Unauthorized)]
+    pub authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct VerifyConsistency<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    /// CHECK: matched against `chain.block_hash` by the handler
+    pub header: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AuditTip<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, loaded by the handler
+    #[account(seeds = [b"header", relay_state.epoch.to_le_bytes().as_ref(), relay_state.best_block.as_ref()], bump)]
+    pub header: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct BlockAtHeight<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, loaded by the handler
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: UncheckedAccount<'info>,
+    /// CHECK: matched against `chain.block_hash` and loaded by the handler
+    pub header: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DerivePdas<'info> {
+    // never read, Anchor's accounts structs need one account to carry `'info`
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ValidateSegment<'info> {
+    // never read, see `DerivePdas`
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
+pub struct VerifyMerkle<'info> {
+    // never read, see `DerivePdas`
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct QueryRelayState<'info> {
+    pub relay_state: Account<'info, RelayState>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
 pub struct VerifyTx<'info> {
     pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
     pub user: Signer<'info>,
 }
 
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct VerifyTxWithReason<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, a missing block is reported as a reason
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct VerifyTxCpi<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct VerifyAndInvoke<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    /// CHECK: holds nothing, signs callbacks so consumers can tell they come from the relay. One
+    /// per relay state: anyone can initialize an epoch, so its signature alone proves nothing
+    #[account(seeds = [b"callback", relay_state.key().as_ref()], bump)]
+    pub callback_authority: UncheckedAccount<'info>,
+    /// CHECK: the program called back, matched against the instruction argument
+    #[account(executable)]
+    pub consumer_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct VerifyTxBegin<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(init, payer = caller, space = size_of::<PartialVerification>() + 8, seeds = [b"partial", relay_state.epoch.to_le_bytes().as_ref(), caller.key().as_ref()], bump)]
+    pub partial: Account<'info, PartialVerification>,
+    #[account(mut)]
+    pub caller: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyTxStep<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, seeds = [b"partial", relay_state.epoch.to_le_bytes().as_ref(), caller.key().as_ref()], bump, has_one = caller @ RelayError::Unauthorized)]
+    pub partial: Account<'info, PartialVerification>,
+    pub caller: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyTxFinalize<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), partial.height.to_le_bytes().as_ref()], bump)]
+    pub chain: Account<'info, BlockHash>,
+    #[account(mut, close = caller, seeds = [b"partial", relay_state.epoch.to_le_bytes().as_ref(), caller.key().as_ref()], bump, has_one = caller @ RelayError::Unauthorized)]
+    pub partial: Account<'info, PartialVerification>,
+    #[account(mut)]
+    pub caller: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyTxCancel<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    #[account(mut, close = caller, seeds = [b"partial", relay_state.epoch.to_le_bytes().as_ref(), caller.key().as_ref()], bump, has_one = caller @ RelayError::Unauthorized)]
+    pub partial: Account<'info, PartialVerification>,
+    #[account(mut)]
+    pub caller: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct ConfirmationsAt<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, inspected by the handler
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct AssertCheckpoint<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, loaded by the handler
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(height: u32)]
+pub struct WorkSinceHeight<'info> {
+    pub relay_state: Account<'info, RelayState>,
+    /// CHECK: may not be initialized, loaded by the handler
+    #[account(seeds = [b"chain", relay_state.epoch.to_le_bytes().as_ref(), height.to_le_bytes().as_ref()], bump)]
+    pub chain: UncheckedAccount<'info>,
+    /// CHECK: matched against `chain.block_hash` and loaded by the handler
+    pub header: UncheckedAccount<'info>,
+}
+
 #[account]
+#[derive(Default)]
 pub struct RelayState {
     pub best_block: [u8; 32],
     pub best_height: u32,
+    // blocks at or below this height are final, `best_height - FINALITY_DEPTH`
+    pub finalized_height: u32,
+    // the block the relay was initialized from, nothing at or below it is accepted
+    pub genesis_height: u32,
     pub epoch_start_target: String,
     pub epoch_end_target: String,
     pub epoch_start_time: u32,
     pub epoch_end_time: u32,
+    // whether the epoch anchors were taken at a period start rather than seeded from genesis
+    pub epoch_anchored: bool,
     pub chain_counter: u32,
+    // cumulative work of the main chain, little-endian
+    pub chain_work: [u8; 32],
+    pub best_timestamp: u32,
+    pub total_headers: u32,
+    // forks other than the main chain
+    pub active_fork_count: u32,
+    // chain ids of those forks, in creation order
+    pub active_fork_ids: Vec<u32>,
+    pub authority: Pubkey,
+    pub paused: bool,
+    // lamports deposited by the creator of a fork
+    pub fork_bond: u64,
+    // accepted header versions, inclusive
+    pub min_version: u32,
+    pub max_version: u32,
+    // difficulty is checked against `OracleConfig` instead of retargeting, see `RelayConfig`
+    pub oracle_mode: bool,
+    // only main-chain extensions are accepted, see `RelayConfig`
+    pub strict_no_fork: bool,
+    // seed component of every PDA of this relay instance, see `RelayConfig`
+    pub epoch: u64,
+    // timestamps of the last `MEDIAN_TIME_SPAN` main-chain blocks, indexed by height modulo the span
+    pub recent_timestamps: [u32; MEDIAN_TIME_SPAN as usize],
+    // main-chain blocks whose timestamp was not above the median of the blocks before them
+    pub timestamp_anomaly_count: u32,
+    // forks created recently, halved every `FORK_STORM_WINDOW` heights
+    pub recent_fork_creations: u32,
+    // `FORK_STORM_WINDOW` the counter was last decayed to, as fork height / window
+    pub fork_storm_window: u32,
+    // block trusted by the authority, retargets up to its height are not validated; zero when unset
+    pub assumevalid: [u8; 32],
+    pub assumevalid_height: u32,
+}
+
+impl RelayState {
+    /// Allocated size, discriminator included. `size_of` counts the `String` and `Vec` headers
+    /// only, so the largest contents are reserved on top: two decimal targets of up to
+    /// `MAX_TARGET_DIGITS` digits and `MAX_FORKS` fork ids.
+    pub const SPACE: usize = size_of::<RelayState>() + 8 + 32 * 2 + 4 * MAX_FORKS + 2 * MAX_TARGET_DIGITS;
+}
+
+/// Options chosen at `initialize` and fixed for the lifetime of the relay.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// Trust the target published by the oracle in `OracleConfig` instead of validating
+    /// retargets, trading trust for compute. Every path that extends a chain consults the
+    /// oracle: `submit_block_header`, `simulate_submit`, `submit_block_header_batch`,
+    /// `extend_chain`, `connect_orphans` and `submit_header_chain`. `initialize_with_headers`
+    /// runs before an oracle can be set, so in oracle mode it takes no follow-up headers.
+    pub oracle_mode: bool,
+    /// Reject every submission that would create or extend a fork, tracking the main chain
+    /// only. A competing branch then halts the relay until someone intervenes.
+    pub strict_no_fork: bool,
+    /// Seed component of the relay state and of every fork, height index and header PDA, so
+    /// independent relay instances can live side by side under different epochs, e.g. to
+    /// restart from scratch in a test environment without closing the old accounts. Unrelated
+    /// to difficulty epochs.
+    pub epoch: u64,
+}
+
+/// Difficulty-period anchors of a relay tip, seeding the instance made by `clone_tip_to`.
+/// Targets are little-endian 256-bit integers.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EpochAnchors {
+    /// Target of the period of the tip.
+    pub start_target: [u8; 32],
+    /// Timestamp of the first block of that period.
+    pub start_time: u32,
+    /// Target of the last block of the period, zero while it is not relayed.
+    pub end_target: [u8; 32],
+    /// Timestamp of the last block of the period, zero while it is not relayed.
+    pub end_time: u32,
+    /// Whether the anchors were taken at a period start, i.e. the next retarget is validated.
+    pub anchored: bool,
+}
+
+#[account]
+pub struct OracleConfig {
+    pub oracle: Pubkey,
+    // required target, little-endian; zero until the oracle first publishes
+    pub target: [u8; 32],
+    pub updated_slot: u64,
+}
+
+/// The outcome of `verify_tx_with_reason`: `reason` is 0 when `ok`, a `VerifyFailure` code
+/// otherwise.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VerifyResult {
+    pub ok: bool,
+    pub reason: u8,
+}
+
+impl From<Option<VerifyFailure>> for VerifyResult {
+    fn from(failure: Option<VerifyFailure>) -> Self {
+        VerifyResult {
+            ok: failure.is_none(),
+            reason: failure.map_or(0, |failure| failure as u8),
+        }
+    }
+}
+
+/// Why a transaction failed to verify, the `reason` codes of a `VerifyResult`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VerifyFailure {
+    // the merkle branch does not lead to the header's merkle root
+    ProofMismatch = 1,
+    TooFewConfirmations = 2,
+    // no main-chain block is stored at the height
+    BlockNotFound = 3,
+    // the header is not the main-chain block stored at the height
+    NotMainChain = 4,
+}
+
+/// A single `verify_tx` proof, as batched by `verify_tx_multi_block`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxProofEntry {
+    pub height: u32,
+    pub header: [u8; 80],
+    pub txid: [u8; 32],
+    pub index: u32,
+    pub proof: Vec<u8>,
+    pub confirmations: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockView {
+    pub block_hash: [u8; 32],
+    pub chain_id: u32,
+    pub timestamp: u32,
+    pub bits: u32,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockPdas {
+    pub header: Pubkey,
+    pub chain: Pubkey,
+    pub fork: Pubkey,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RelayHealth {
+    pub best_height: u32,
+    pub best_timestamp: u32,
+    pub chain_counter: u32,
+    pub total_headers: u32,
+    pub active_fork_count: u32,
+    pub paused: bool,
+    pub timestamp_anomaly_count: u32,
+    pub recent_fork_creations: u32,
+}
+
+/// How far the main chain is into the current difficulty period, returned by
+/// `get_epoch_progress`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EpochProgress {
+    // blocks of the period up to and including the tip, the period start counting as 0
+    pub blocks_into_period: u32,
+    pub period_length: u32,
+    pub epoch_start_time: u32,
+}
+
+/// Every field of `RelayState`, returned by `get_state_snapshot`. Even with `MAX_FORKS` fork
+/// ids and the longest targets it fits the 1024 bytes of return data.
+///
+/// Headers and forks are not included. They are found deterministically by seed: the main
+/// chain is the `BlockHash` PDA of every height from genesis to `best_height`, each naming its
+/// `Header` PDA, and the forks are the `Fork` PDAs of `active_fork_ids`, whose descendants name
+/// theirs.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateSnapshot {
+    pub best_block: [u8; 32],
+    pub best_height: u32,
+    pub finalized_height: u32,
+    pub genesis_height: u32,
+    pub epoch_start_target: String,
+    pub epoch_end_target: String,
+    pub epoch_start_time: u32,
+    pub epoch_end_time: u32,
+    pub epoch_anchored: bool,
+    pub chain_counter: u32,
+    pub chain_work: [u8; 32],
+    pub best_timestamp: u32,
+    pub total_headers: u32,
+    pub active_fork_count: u32,
+    pub active_fork_ids: Vec<u32>,
+    pub authority: Pubkey,
+    pub paused: bool,
+    pub fork_bond: u64,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub oracle_mode: bool,
+    pub strict_no_fork: bool,
+    pub epoch: u64,
+    pub recent_timestamps: [u32; MEDIAN_TIME_SPAN as usize],
+    pub timestamp_anomaly_count: u32,
+    pub recent_fork_creations: u32,
+    pub fork_storm_window: u32,
+    pub assumevalid: [u8; 32],
+    pub assumevalid_height: u32,
+}
+
+impl From<&RelayState> for StateSnapshot {
+    fn from(relay_state: &RelayState) -> Self {
+        StateSnapshot {
+            best_block: relay_state.best_block,
+            best_height: relay_state.best_height,
+            finalized_height: relay_state.finalized_height,
+            genesis_height: relay_state.genesis_height,
+            epoch_start_target: relay_state.epoch_start_target.clone(),
+            epoch_end_target: relay_state.epoch_end_target.clone(),
+            epoch_start_time: relay_state.epoch_start_time,
+            epoch_end_time: relay_state.epoch_end_time,
+            epoch_anchored: relay_state.epoch_anchored,
+            chain_counter: relay_state.chain_counter,
+            chain_work: relay_state.chain_work,
+            best_timestamp: relay_state.best_timestamp,
+            total_headers: relay_state.total_headers,
+            active_fork_count: relay_state.active_fork_count,
+            active_fork_ids: relay_state.active_fork_ids.clone(),
+            authority: relay_state.authority,
+            paused: relay_state.paused,
+            fork_bond: relay_state.fork_bond,
+            min_version: relay_state.min_version,
+            max_version: relay_state.max_version,
+            oracle_mode: relay_state.oracle_mode,
+            strict_no_fork: relay_state.strict_no_fork,
+            epoch: relay_state.epoch,
+            recent_timestamps: relay_state.recent_timestamps,
+            timestamp_anomaly_count: relay_state.timestamp_anomaly_count,
+            recent_fork_creations: relay_state.recent_fork_creations,
+            fork_storm_window: relay_state.fork_storm_window,
+            assumevalid: relay_state.assumevalid,
+            assumevalid_height: relay_state.assumevalid_height,
+        }
+    }
 }
 
 // You might need to create custom types for some of the complex structures
 #[account]
+#[derive(Default)]
 pub struct Header {
     pub height: u32,
     pub chain_id: u32,
+    // cumulative work up to and including this block, little-endian
+    pub chain_work: [u8; 32],
+    pub timestamp: u32,
+    pub version: u32,
+    // compact target
+    pub bits: u32,
+    // the signer that relayed the header, for relayer rewards
+    pub submitter: Pubkey,
+}
+
+// a merkle proof folded across transactions, one per caller
+#[account]
+pub struct PartialVerification {
+    pub caller: Pubkey,
+    pub height: u32,
+    pub block_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub confirmations: u64,
+    // node hashed up to so far and its position in its level
+    pub current: [u8; 32],
+    pub index: u32,
+}
+
+// a header waiting for its parent, keyed by the parent's hash
+#[account]
+pub struct Orphan {
+    pub header: [u8; 80],
+    pub submitter: Pubkey,
 }
 
 #[account]
@@ -81,11 +825,29 @@ pub struct BlockHash {
     pub block_hash: [u8; 32],
 }
 
+/// BIP158 basic filter header of the main-chain block at a height, as attested by the relay
+/// authority; headers are not committed to by the blocks themselves.
+#[account]
+pub struct FilterHeader {
+    pub filter_header: [u8; 32],
+}
+
 #[account]
 pub struct Fork {
     pub height: u32,
+    // block the fork branches off; the main fork has none and holds the genesis hash instead
     pub ancestor: [u8; 32],
     pub descendants: Vec<[u8; 32]>,
+    // lamports deposited on creation and who to refund them to
+    pub bond: u64,
+    pub bond_owner: Pubkey,
+}
+
+impl Fork {
+    /// Serialized size, discriminator included, of a fork holding `descendants` block hashes.
+    pub fn space(descendants: usize) -> usize {
+        8 + 4 + 32 + 4 + 32 * descendants + 8 + 32
+    }
 }
 
 #[event]
@@ -93,6 +855,42 @@ pub struct ChainReorg {
     pub from: [u8; 32],
     pub to: [u8; 32],
     pub chain_id: u64,
+    // lowest height whose block was replaced
+    pub rewind_from: u32,
+}
+
+/// Emitted by a reorg for every rewritten height, after `ChainReorg`.
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct HeightReorged {
+    pub height: u32,
+    pub old_hash: [u8; 32],
+    pub new_hash: [u8; 32],
+}
+
+/// Emitted for every new fork while `recent_fork_creations` exceeds `FORK_STORM_THRESHOLD`,
+/// hinting at an attack or a network split.
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct ForkStormDetected {
+    pub recent_fork_creations: u32,
+    pub chain_id: u32,
+    pub height: u32,
+}
+
+// `AdminAction::action` codes, one per authority-gated instruction
+pub const ADMIN_SET_PAUSED: u8 = 0;
+pub const ADMIN_SET_FORK_BOND: u8 = 1;
+pub const ADMIN_SET_VERSION_RANGE: u8 = 2;
+pub const ADMIN_SET_ORACLE: u8 = 3;
+pub const ADMIN_FORCE_SET_MAIN: u8 = 4;
+pub const ADMIN_SET_ASSUMEVALID: u8 = 5;
+
+#[event]
+pub struct AdminAction {
+    pub action: u8,
+    pub authority: Pubkey,
+    pub slot: u64,
 }
 
 // Constants for the maximum number of headers and forks
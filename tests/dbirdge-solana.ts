@@ -1,16 +1,2564 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
-import { DbirdgeSolana } from "../target/types/dbirdge_solana";
+import { assert } from "chai";
+import { createHash } from "crypto";
+import { Relayer } from "../target/types/relayer";
+import { RelayConsumer } from "../target/types/relay_consumer";
 
-describe("dbirdge-solana", () => {
-  // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+// regtest compact target: roughly every other nonce satisfies it
+const EASY_BITS = 0x207fffff;
+const MAIN_CHAIN_ID = 1;
+const CONFIRMATIONS = 6;
+const ANCHOR_HEIGHT = 100;
+const ANCHOR_TIME = 1_700_000_000;
+const FORK_BOND_TIMEOUT = 144;
+const MAX_FORK_LENGTH = 8;
+const FINALITY_DEPTH = 100;
 
-  const program = anchor.workspace.DbirdgeSolana as Program<DbirdgeSolana>;
+type Block = {
+  header: Buffer;
+  hash: Buffer;
+  height: number;
+  chainId: number;
+  signature?: string;
+};
+
+function sha256d(data: Buffer): Buffer {
+  const first = createHash("sha256").update(data).digest();
+  return createHash("sha256").update(first).digest();
+}
+
+function u32le(n: number): Buffer {
+  const b = Buffer.alloc(4);
+  b.writeUInt32LE(n);
+  return b;
+}
+
+function u64le(n: number): Buffer {
+  const b = Buffer.alloc(8);
+  b.writeBigUInt64LE(BigInt(n));
+  return b;
+}
+
+function bitsToTarget(bits: number): anchor.BN {
+  const exponent = bits >>> 24;
+  const mantissa = new anchor.BN(bits & 0x7fffff);
+  return exponent <= 3
+    ? mantissa.shrn(8 * (3 - exponent))
+    : mantissa.shln(8 * (exponent - 3));
+}
+
+function blockWork(bits: number): anchor.BN {
+  return new anchor.BN(1).shln(256).div(bitsToTarget(bits).addn(1));
+}
+
+type HeaderOpts = { bits?: number; version?: number; merkleRoot?: Buffer };
+
+function buildHeader(prevHash: Buffer, timestamp: number, opts: HeaderOpts = {}): Buffer {
+  const header = Buffer.alloc(80);
+  header.writeUInt32LE(opts.version ?? 0x20000000, 0);
+  prevHash.copy(header, 4);
+  (opts.merkleRoot ?? Buffer.alloc(32, 0xab)).copy(header, 36);
+  header.writeUInt32LE(timestamp, 68);
+  header.writeUInt32LE(opts.bits ?? EASY_BITS, 72);
+  return header;
+}
+
+function mineHeader(prevHash: Buffer, timestamp: number, opts: HeaderOpts = {}): Buffer {
+  const header = buildHeader(prevHash, timestamp, opts);
+  const target = bitsToTarget(opts.bits ?? EASY_BITS);
+  for (let nonce = 0; ; nonce++) {
+    header.writeUInt32LE(nonce, 76);
+    if (new anchor.BN(sha256d(header), "le").lte(target)) return header;
+  }
+}
+
+const readonly = (pubkey: anchor.web3.PublicKey) => ({
+  pubkey,
+  isWritable: false,
+  isSigner: false,
+});
+
+// bitcoin merkle tree over internal-order txids, duplicating the last node of odd levels
+function merkleLevels(txids: Buffer[]): Buffer[][] {
+  const levels = [txids];
+  while (levels[levels.length - 1].length > 1) {
+    const level = levels[levels.length - 1];
+    const next: Buffer[] = [];
+    for (let i = 0; i < level.length; i += 2) {
+      next.push(sha256d(Buffer.concat([level[i], level[i + 1] ?? level[i]])));
+    }
+    levels.push(next);
+  }
+  return levels;
+}
+
+function merkleRoot(txids: Buffer[]): Buffer {
+  const levels = merkleLevels(txids);
+  return levels[levels.length - 1][0];
+}
+
+function merkleProof(txids: Buffer[], index: number): Buffer {
+  const siblings = merkleLevels(txids)
+    .slice(0, -1)
+    .map((level, depth) => {
+      const i = (index >> depth) ^ 1;
+      return level[i] ?? level[i - 1];
+    });
+  return Buffer.concat(siblings);
+}
+
+// block 170, the first bitcoin payment, in internal byte order
+const BLOCK_170_TXIDS = [
+  "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082",
+  "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+].map((txid) => Buffer.from(txid, "hex").reverse());
+const BLOCK_170_MERKLE_ROOT = Buffer.from(
+  "7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff",
+  "hex"
+).reverse();
+
+const txidsOf = (label: string, count: number) =>
+  Array.from({ length: count }, (_, i) => sha256d(Buffer.from(`${label} tx ${i}`)));
+
+// BIP34 script number push of a block height above 16
+function bip34Push(height: number): Buffer {
+  const bytes: number[] = [];
+  for (let h = height; h > 0; h >>>= 8) bytes.push(h & 0xff);
+  if (bytes[bytes.length - 1] & 0x80) bytes.push(0);
+  return Buffer.from([bytes.length, ...bytes]);
+}
+
+// non-witness serialization of a coinbase paying to OP_TRUE
+function buildCoinbase(scriptSig: Buffer): Buffer {
+  return Buffer.concat([
+    u32le(1),
+    Buffer.from([1]),
+    Buffer.alloc(32),
+    Buffer.alloc(4, 0xff),
+    Buffer.from([scriptSig.length]),
+    scriptSig,
+    Buffer.alloc(4, 0xff),
+    Buffer.from([1]),
+    Buffer.from("00f2052a01000000", "hex"),
+    Buffer.from([1, 0x51]),
+    u32le(0),
+  ]);
+}
+
+const writable = (pubkey: anchor.web3.PublicKey) => ({
+  pubkey,
+  isWritable: true,
+  isSigner: false,
+});
+
+async function expectError(promise: Promise<unknown>, code: string) {
+  try {
+    await promise;
+  } catch (err) {
+    assert.equal(err.error?.errorCode?.code, code);
+    return;
+  }
+  assert.fail(`expected ${code}`);
+}
+
+describe("relayer", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.Relayer as Program<Relayer>;
+  const user = provider.wallet.publicKey;
+
+  const pda = (...seeds: Buffer[]) =>
+    anchor.web3.PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+  // every relay PDA is seeded with the epoch of its relay instance, the tests mostly use epoch 0
+  const relayStatePda = (epoch = 0) => pda(Buffer.from("relay_state"), u64le(epoch));
+  const relayState = relayStatePda();
+  const headerPda = (hash: Buffer, epoch = 0) => pda(Buffer.from("header"), u64le(epoch), hash);
+  const chainPda = (height: number, epoch = 0) => pda(Buffer.from("chain"), u64le(epoch), u32le(height));
+  const forkPda = (chainId: number, epoch = 0) => pda(Buffer.from("fork"), u64le(epoch), u32le(chainId));
+  const oracleConfigPda = (epoch = 0) => pda(Buffer.from("oracle_config"), u64le(epoch));
+  const relayConfig = (epoch = 0) => ({ oracleMode: false, strictNoFork: false, epoch: new anchor.BN(epoch) });
+
+  let genesis: Block;
+  let tip: Block;
+
+  async function submit(
+    header: Buffer,
+    prev: Block,
+    opts: {
+      remainingAccounts?: anchor.web3.AccountMeta[];
+      epoch?: number;
+      oracleConfig?: anchor.web3.PublicKey;
+    } = {}
+  ): Promise<Block> {
+    const hash = sha256d(header);
+    const height = prev.height + 1;
+    const epoch = opts.epoch ?? 0;
+    const { chainCounter } = await program.account.relayState.fetch(relayStatePda(epoch));
+    const nextCounter = chainCounter + 1;
+    const signature = await program.methods
+      .submitBlockHeader([...header], [...hash], height, nextCounter)
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        prevHeader: headerPda(prev.hash, epoch),
+        prevFork: forkPda(prev.chainId, epoch),
+        fork: forkPda(nextCounter, epoch),
+        chain: chainPda(height, epoch),
+        header: headerPda(hash, epoch),
+        oracleConfig: opts.oracleConfig ?? null,
+        user,
+      })
+      .remainingAccounts(opts.remainingAccounts ?? [])
+      .rpc({ commitment: "confirmed" });
+    const stored = await program.account.header.fetch(headerPda(hash, epoch));
+    return { header, hash, height, chainId: stored.chainId, signature };
+  }
+
+  // accounts expected by reorg_chain when `forkBlocks` (all but the new tip) replace `replaced`;
+  // `parent` is the fork holding the demoted ancestor of a fork branching off an old main chain
+  function reorgAccounts(
+    forkPoint: Block,
+    forkBlocks: Block[],
+    replaced: Block[],
+    epoch = 0,
+    parent?: { ancestor: Block; chainId: number }
+  ) {
+    const accounts = [forkPda(MAIN_CHAIN_ID, epoch)];
+    if (parent) accounts.push(headerPda(parent.ancestor.hash, epoch), forkPda(parent.chainId, epoch));
+    accounts.push(chainPda(forkPoint.height, epoch));
+    for (const block of forkBlocks) {
+      accounts.push(chainPda(block.height, epoch), headerPda(block.hash, epoch));
+      const old = replaced.find((b) => b.height === block.height);
+      if (old) accounts.push(headerPda(old.hash, epoch));
+    }
+    // the old main chain from the new tip's height up, when the fork does not pass it
+    const tipHeight = forkPoint.height + forkBlocks.length + 1;
+    for (const old of replaced.filter((b) => b.height >= tipHeight)) {
+      if (old.height > tipHeight) accounts.push(chainPda(old.height, epoch));
+      accounts.push(headerPda(old.hash, epoch));
+    }
+    return accounts.map(writable);
+  }
+
+  async function findEvents(signature: string, name: string) {
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    return [...parser.parseLogs(tx.meta.logMessages)].filter((e) => e.name === name).map((e) => e.data);
+  }
+
+  const findEvent = async (signature: string, name: string) => (await findEvents(signature, name))[0];
+
+  const verifyTx = (
+    block: Block,
+    txids: Buffer[],
+    index: number,
+    confirmations: number,
+    opts: { proof?: Buffer; txid?: Buffer } = {}
+  ) =>
+    program.methods
+      .verifyTx(
+        block.height,
+        index,
+        [...(opts.txid ?? txids[index])],
+        [...block.header],
+        opts.proof ?? merkleProof(txids, index),
+        new anchor.BN(confirmations),
+        false
+      )
+      .accountsPartial({ relayState, chain: chainPda(block.height), user });
+
+  async function extendMain(count: number): Promise<Block[]> {
+    const blocks: Block[] = [];
+    for (let i = 0; i < count; i++) {
+      const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600);
+      tip = await submit(header, tip);
+      blocks.push(tip);
+    }
+    return blocks;
+  }
+
+  // the PDAs of every header of a submit_header_chain run but the last, stored from the remaining accounts
+  const headerChainAccounts = (headers: Buffer[], height: number) =>
+    headers.slice(0, -1).flatMap((h, i) => [writable(headerPda(sha256d(h))), writable(chainPda(height + 1 + i))]);
+
+  // extends the main chain through submit_header_chain, 4 mined headers per transaction
+  async function syncMain(count: number) {
+    while (count > 0) {
+      const size = Math.min(count, 4);
+      const headers = mineRun(size);
+      const prevHash = sha256d(headers[size - 1]);
+      await program.methods
+        .submitHeaderChain(headers.map((h) => [...h]), 1)
+        .accountsPartial({
+          relayState,
+          mainFork: forkPda(MAIN_CHAIN_ID),
+          prevHeader: headerPda(tip.hash),
+          chain: chainPda(tip.height + size),
+          header: headerPda(prevHash),
+          oracleConfig: null,
+          user,
+        })
+        .remainingAccounts(headerChainAccounts(headers, tip.height))
+        .rpc();
+      tip = { header: headers[size - 1], hash: prevHash, height: tip.height + size, chainId: MAIN_CHAIN_ID };
+      count -= size;
+    }
+  }
+
+  // simulates `instructions` without signatures, exposing the return data of failed instructions too
+  async function simulate(...instructions: anchor.web3.TransactionInstruction[]) {
+    const { blockhash } = await provider.connection.getLatestBlockhash();
+    const message = new anchor.web3.TransactionMessage({
+      payerKey: user,
+      recentBlockhash: blockhash,
+      instructions,
+    }).compileToV0Message();
+    const transaction = new anchor.web3.VersionedTransaction(message);
+    return (await provider.connection.simulateTransaction(transaction, { sigVerify: false })).value;
+  }
+
+  // mines `count` headers linking to the tip, 600 seconds apart
+  function mineRun(count: number): Buffer[] {
+    const headers: Buffer[] = [];
+    let prevHash = tip.hash;
+    let time = tip.header.readUInt32LE(68);
+    for (let i = 0; i < count; i++) {
+      time += 600;
+      headers.push(mineHeader(prevHash, time));
+      prevHash = sha256d(headers[i]);
+    }
+    return headers;
+  }
+
+  // submit_block_header_batch for `headers` on top of the tip, taking heights from their order
+  const batchSubmission = (headers: Buffer[]) =>
+    program.methods
+      .submitBlockHeaderBatch(headers.map((h) => [...h]))
+      .accountsPartial({
+        relayState,
+        mainFork: forkPda(MAIN_CHAIN_ID),
+        prevHeader: headerPda(tip.hash),
+        oracleConfig: null,
+        user,
+      })
+      .remainingAccounts(
+        headers.flatMap((h, i) => [writable(headerPda(sha256d(h))), writable(chainPda(tip.height + 1 + i))])
+      );
+
+  // a checkpoint block whose coinbase commits to `coinbaseHeight`, next to one other tx
+  function buildCheckpoint(coinbaseHeight: number) {
+    const coinbase = buildCoinbase(Buffer.concat([bip34Push(coinbaseHeight), Buffer.from("/relay/")]));
+    const sibling = sha256d(Buffer.from("second tx"));
+    const merkleRoot = sha256d(Buffer.concat([sha256d(coinbase), sibling]));
+    const header = mineHeader(Buffer.alloc(32, 0x11), ANCHOR_TIME, { merkleRoot });
+    return { header, hash: sha256d(header), coinbase, proof: sibling };
+  }
+
+  const initializeCheckpoint = (checkpoint: ReturnType<typeof buildCheckpoint>) =>
+    program.methods
+      .initializeCheckpoint(
+        [...checkpoint.header],
+        ANCHOR_HEIGHT,
+        [...checkpoint.hash],
+        relayConfig(),
+        checkpoint.coinbase,
+        checkpoint.proof
+      )
+      .accountsPartial({
+        relayState,
+        fork: forkPda(MAIN_CHAIN_ID),
+        chain: chainPda(ANCHOR_HEIGHT),
+        header: headerPda(checkpoint.hash),
+        user,
+      })
+      .rpc();
+
+  it("initializes atomically with follow-up headers that must link", async () => {
+    // only the failure can be exercised here, the relay state is initialized once per epoch
+    const genesisHeader = mineHeader(Buffer.alloc(32, 0x33), ANCHOR_TIME);
+    const genesisHash = sha256d(genesisHeader);
+    const followUp = [mineHeader(genesisHash, ANCHOR_TIME + 600)];
+    followUp.push(mineHeader(sha256d(followUp[0]), ANCHOR_TIME + 1200));
+    followUp.push(mineHeader(genesisHash, ANCHOR_TIME + 1800));
+
+    const ix = await program.methods
+      .initializeWithHeaders(
+        [...genesisHeader],
+        ANCHOR_HEIGHT,
+        [...genesisHash],
+        relayConfig(),
+        followUp.map((h) => [...h])
+      )
+      .accountsPartial({
+        relayState,
+        fork: forkPda(MAIN_CHAIN_ID),
+        chain: chainPda(ANCHOR_HEIGHT),
+        header: headerPda(genesisHash),
+        user,
+      })
+      .remainingAccounts(
+        followUp.flatMap((h, i) => [writable(headerPda(sha256d(h))), writable(chainPda(ANCHOR_HEIGHT + 1 + i))])
+      )
+      .instruction();
+    const simulation = await simulate(ix);
+    assert.isTrue(simulation.logs.some((log) => log.includes("InvalidHeaderBatch")));
+    assert.equal(Buffer.from(simulation.returnData.data[0], "base64").readUInt32LE(0), 2);
+    assert.isNull(await provider.connection.getAccountInfo(relayState));
+  });
+
+  it("rejects a checkpoint whose coinbase commits to another height", async () => {
+    await expectError(initializeCheckpoint(buildCheckpoint(ANCHOR_HEIGHT + 1)), "InvalidGenesisHeight");
+  });
+
+  it("rejects a 64-byte coinbase, which could be an inner merkle node", async () => {
+    const checkpoint = buildCheckpoint(ANCHOR_HEIGHT);
+    // the two children of the root hash to the root with an empty branch
+    const node = Buffer.concat([sha256d(checkpoint.coinbase), checkpoint.proof]);
+    await expectError(initializeCheckpoint({ ...checkpoint, coinbase: node, proof: Buffer.alloc(0) }), "InvalidCoinbase");
+  });
+
+  it("takes headers as fixed 80-byte arrays", () => {
+    const argType = (instruction: string, arg: string) =>
+      program.rawIdl.instructions.find((ix) => ix.name === instruction).args.find((a) => a.name === arg).type;
+    assert.deepEqual(argType("initialize", "genesis_header"), { array: ["u8", 80] });
+    assert.deepEqual(argType("initialize_checkpoint", "genesis_header"), { array: ["u8", 80] });
+    assert.deepEqual(argType("submit_block_header", "header"), { array: ["u8", 80] });
+  });
 
   it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.initialize().rpc();
-    console.log("Your transaction signature", tx);
+    const checkpoint = buildCheckpoint(ANCHOR_HEIGHT);
+    const { header, hash } = checkpoint;
+    await initializeCheckpoint(checkpoint);
+
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), hash);
+    assert.equal(state.bestHeight, ANCHOR_HEIGHT);
+    const stored = await program.account.header.fetch(headerPda(hash));
+    genesis = { header, hash, height: ANCHOR_HEIGHT, chainId: stored.chainId };
+    tip = genesis;
+  });
+
+  it("initializes independent relay instances under different epochs", async () => {
+    const genesisHeader = mineHeader(Buffer.alloc(32, 0x44), ANCHOR_TIME);
+    const genesisHash = sha256d(genesisHeader);
+    for (const epoch of [1, 2]) {
+      await program.methods
+        .initialize([...genesisHeader], ANCHOR_HEIGHT, [...genesisHash], relayConfig(epoch))
+        .accountsPartial({
+          relayState: relayStatePda(epoch),
+          fork: forkPda(MAIN_CHAIN_ID, epoch),
+          chain: chainPda(ANCHOR_HEIGHT, epoch),
+          header: headerPda(genesisHash, epoch),
+          user,
+        })
+        .rpc();
+    }
+
+    // extend epoch 1 only
+    const header = mineHeader(genesisHash, ANCHOR_TIME + 600);
+    const hash = sha256d(header);
+    await program.methods
+      .submitBlockHeader([...header], [...hash], ANCHOR_HEIGHT + 1, MAIN_CHAIN_ID + 1)
+      .accountsPartial({
+        relayState: relayStatePda(1),
+        prevHeader: headerPda(genesisHash, 1),
+        prevFork: forkPda(MAIN_CHAIN_ID, 1),
+        fork: forkPda(MAIN_CHAIN_ID + 1, 1),
+        chain: chainPda(ANCHOR_HEIGHT + 1, 1),
+        header: headerPda(hash, 1),
+        oracleConfig: null,
+        user,
+      })
+      .rpc();
+
+    const first = await program.account.relayState.fetch(relayStatePda(1));
+    const second = await program.account.relayState.fetch(relayStatePda(2));
+    assert.equal(first.epoch.toNumber(), 1);
+    assert.equal(second.epoch.toNumber(), 2);
+    assert.equal(first.bestHeight, ANCHOR_HEIGHT + 1);
+    assert.equal(second.bestHeight, ANCHOR_HEIGHT);
+    assert.isNull(await provider.connection.getAccountInfo(headerPda(hash, 2)));
+    // the instance under test is untouched
+    assert.deepEqual(Buffer.from((await program.account.relayState.fetch(relayState)).bestBlock), genesis.hash);
+  });
+
+  it("rejects a fork beyond MAX_FORKS active ones", async () => {
+    // a fresh instance, so filling it with forks leaves the other tests alone
+    const epoch = 4;
+    const MAX_FORKS = 100;
+    const header = mineHeader(Buffer.alloc(32, 0x66), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], relayConfig(epoch))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const root: Block = { header, hash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    await submit(mineHeader(hash, ANCHOR_TIME + 600), root, { epoch });
+
+    // every other sibling of the main block branches into its own fork
+    for (let i = 1; i <= MAX_FORKS; i++) {
+      await submit(mineHeader(hash, ANCHOR_TIME + 600 + i), root, { epoch });
+    }
+    const state = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.equal(state.activeForkIds.length, MAX_FORKS);
+
+    await expectError(submit(mineHeader(hash, ANCHOR_TIME + 600 + MAX_FORKS + 1), root, { epoch }), "TooManyForks");
+  });
+
+  it("detects a storm of fork creations", async () => {
+    const epoch = 5;
+    const FORK_STORM_THRESHOLD = 4;
+    const header = mineHeader(Buffer.alloc(32, 0x77), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], relayConfig(epoch))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const root: Block = { header, hash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    await submit(mineHeader(hash, ANCHOR_TIME + 600), root, { epoch });
+
+    for (let i = 1; i <= FORK_STORM_THRESHOLD; i++) {
+      const fork = await submit(mineHeader(hash, ANCHOR_TIME + 600 + i), root, { epoch });
+      assert.isEmpty(await findEvents(fork.signature, "forkStormDetected"));
+    }
+    const fork = await submit(mineHeader(hash, ANCHOR_TIME + 700), root, { epoch });
+    const storm = await findEvent(fork.signature, "forkStormDetected");
+    assert.equal(storm.recentForkCreations, FORK_STORM_THRESHOLD + 1);
+    assert.equal(storm.chainId, fork.chainId);
+
+    const health = await program.methods.getHealth().accountsPartial({ relayState: relayStatePda(epoch) }).view();
+    assert.equal(health.recentForkCreations, FORK_STORM_THRESHOLD + 1);
+  });
+
+  it("clones the tip of a relay into a fresh instance", async () => {
+    const epoch = 6;
+    const source = await program.account.relayState.fetch(relayState);
+    const toLe = (decimal: string) => (decimal ? [...new anchor.BN(decimal).toArrayLike(Buffer, "le", 32)] : Array(32).fill(0));
+    const anchors = {
+      startTarget: toLe(source.epochStartTarget),
+      startTime: source.epochStartTime,
+      endTarget: toLe(source.epochEndTarget),
+      endTime: source.epochEndTime,
+      anchored: source.epochAnchored,
+    };
+    await program.methods
+      .cloneTipTo([...tip.header], tip.height, [...tip.hash], relayConfig(epoch), anchors)
+      .accountsPartial({
+        sourceRelayState: relayState,
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(tip.height, epoch),
+        header: headerPda(tip.hash, epoch),
+        authority: user,
+      })
+      .rpc();
+
+    const root: Block = { ...tip, chainId: MAIN_CHAIN_ID };
+    const next = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600), root, { epoch });
+    const clone = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.equal(clone.bestHeight, tip.height + 1);
+    assert.deepEqual(Buffer.from(clone.bestBlock), next.hash);
+    assert.equal(clone.epochStartTarget, source.epochStartTarget);
+    // the source relay is untouched
+    assert.equal((await program.account.relayState.fetch(relayState)).bestHeight, tip.height);
+  });
+
+  it("reorganizes a fork branching at genesis back to the genesis block", async () => {
+    // a fresh instance, so its genesis is still in reach of a fork
+    const epoch = 3;
+    const header = mineHeader(Buffer.alloc(32, 0x55), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], relayConfig(epoch))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const root: Block = { header, hash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    // the main fork is rooted at the genesis block, not at a zero hash
+    const mainFork = await program.account.fork.fetch(forkPda(MAIN_CHAIN_ID, epoch));
+    assert.deepEqual(Buffer.from(mainFork.ancestor), hash);
+
+    const replaced = await submit(mineHeader(hash, ANCHOR_TIME + 600), root, { epoch });
+    // one block more than the main chain and CONFIRMATIONS on top overtake it
+    const forkBlocks: Block[] = [];
+    let prev = root;
+    for (let i = 1; i <= CONFIRMATIONS; i++) {
+      prev = await submit(mineHeader(prev.hash, ANCHOR_TIME + 600 * i + 1), prev, { epoch });
+      forkBlocks.push(prev);
+    }
+    const forkTip = await submit(mineHeader(prev.hash, ANCHOR_TIME + 600 * (CONFIRMATIONS + 1) + 1), prev, {
+      epoch,
+      remainingAccounts: reorgAccounts(root, forkBlocks, [replaced], epoch),
+    });
+
+    const state = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.deepEqual(Buffer.from(state.bestBlock), forkTip.hash);
+    assert.equal(state.bestHeight, ANCHOR_HEIGHT + CONFIRMATIONS + 1);
+    // the old main chain now branches at genesis too
+    const demoted = await program.account.fork.fetch(forkPda(state.chainCounter, epoch));
+    assert.deepEqual(Buffer.from(demoted.ancestor), hash);
+    assert.deepEqual(demoted.descendants.map((d) => Buffer.from(d)), [replaced.hash]);
+  });
+
+  it("derives the parent and its chain from the header's prevhash", async () => {
+    const header = mineHeader(genesis.hash, ANCHOR_TIME + 600);
+    const hash = sha256d(header);
+    const { chainCounter } = await program.account.relayState.fetch(relayState);
+    const submitWith = (prevFork: anchor.web3.PublicKey) =>
+      program.methods
+        .submitBlockHeader([...header], [...hash], genesis.height + 1, chainCounter + 1)
+        .accountsPartial({
+          relayState,
+          prevHeader: headerPda(genesis.hash),
+          prevFork,
+          fork: forkPda(chainCounter + 1),
+          chain: chainPda(genesis.height + 1),
+          header: headerPda(hash),
+          oracleConfig: null,
+          user,
+        })
+        .rpc();
+
+    // a chain other than the one the parent is stored on
+    await expectError(submitWith(forkPda(999)), "ConstraintSeeds");
+
+    // the derived parent and chain are the ones the client tracked
+    tip = await submit(header, genesis);
+    assert.equal(tip.chainId, genesis.chainId);
+    const stored = await program.account.header.fetch(headerPda(tip.hash));
+    assert.equal(stored.height, genesis.height + 1);
+  });
+
+  it("accumulates main chain work and reports sync status", async () => {
+    const totalWork = async () =>
+      new anchor.BN(
+        await program.methods.getTotalWork().accountsPartial({ relayState }).view(),
+        "le"
+      );
+    const isSynced = (minWork: anchor.BN) =>
+      program.methods
+        .isSynced(minWork.toArray("le", 32))
+        .accountsPartial({ relayState })
+        .view();
+
+    const before = await totalWork();
+    await extendMain(3);
+    const after = await totalWork();
+    assert.isTrue(after.eq(before.add(blockWork(EASY_BITS).muln(3))));
+
+    assert.isTrue(await isSynced(before));
+    assert.isTrue(await isSynced(after));
+    assert.isFalse(await isSynced(after.addn(1)));
+  });
+
+  it("reports the target and difficulty of the current period", async () => {
+    const target = await program.methods.getCurrentTarget().accountsPartial({ relayState }).view();
+    assert.isTrue(new anchor.BN(target, "le").eq(bitsToTarget(EASY_BITS)));
+
+    // the regtest target lies above the difficulty-1 target
+    const difficulty = await program.methods.getDifficulty().accountsPartial({ relayState }).view();
+    assert.isTrue(new anchor.BN(difficulty, "le").isZero());
+  });
+
+  it("reports the progress through the current difficulty period", async () => {
+    const progress = () => program.methods.getEpochProgress().accountsPartial({ relayState }).view();
+
+    const before = await progress();
+    assert.equal(before.periodLength, 2016);
+    assert.equal(before.blocksIntoPeriod, tip.height % 2016);
+    await extendMain(2);
+    const after = await progress();
+    assert.equal(after.blocksIntoPeriod, tip.height % 2016);
+    assert.equal(after.blocksIntoPeriod, before.blocksIntoPeriod + 2);
+    assert.equal(after.epochStartTime, before.epochStartTime);
+  });
+
+  it("computes the median time past over the last 11 headers", async () => {
+    const base = tip.header.readUInt32LE(68);
+    const offsets = [600, 300, 1500, 900, 2400, 1800, 1200, 3000, 2700, 2100, 3600];
+    const blocks: Block[] = [];
+    for (const offset of offsets) {
+      tip = await submit(mineHeader(tip.hash, base + offset), tip);
+      blocks.push(tip);
+    }
+    const headers = blocks.reverse().map((b) => readonly(headerPda(b.hash)));
+    const sorted = offsets.map((o) => base + o).sort((a, b) => a - b);
+
+    const mtp = await program.methods
+      .getMedianTimePast(tip.height, MAIN_CHAIN_ID)
+      .accountsPartial({ relayState })
+      .remainingAccounts(headers)
+      .view();
+    assert.equal(mtp, sorted[5]);
+
+    await expectError(
+      program.methods
+        .getMedianTimePast(tip.height, MAIN_CHAIN_ID)
+        .accountsPartial({ relayState })
+        .remainingAccounts(headers.slice(0, 10))
+        .rpc(),
+      "BlockNotFound"
+    );
+  });
+
+  it("computes the median time past along a fork branch", async () => {
+    const time = (b: Block) => b.header.readUInt32LE(68);
+    const below = await extendMain(12);
+    const base = below[below.length - 1];
+    const above = await extendMain(4);
+
+    // the fork dates its blocks before the main chain's at the same heights
+    let forkTip = base;
+    const forkBlocks: Block[] = [];
+    for (const offset of [3900, 3300, 2700]) {
+      forkTip = await submit(mineHeader(forkTip.hash, time(base) - offset), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+
+    const mtp = (chainId: number, blocks: Block[]) =>
+      program.methods
+        .getMedianTimePast(forkTip.height, chainId)
+        .accountsPartial({ relayState })
+        .remainingAccounts([
+          ...(chainId === MAIN_CHAIN_ID ? [] : [readonly(forkPda(chainId))]),
+          ...blocks.map((b) => readonly(headerPda(b.hash))),
+        ]);
+    const median = (blocks: Block[]) => blocks.map(time).sort((a, b) => a - b)[5];
+    const forkBranch = [...below, ...forkBlocks].slice(-11).reverse();
+    const mainBranch = [...below, ...above.slice(0, 3)].slice(-11).reverse();
+
+    const forkMtp = await mtp(forkTip.chainId, forkBranch).view();
+    assert.equal(forkMtp, median(forkBranch));
+    assert.notEqual(forkMtp, await mtp(MAIN_CHAIN_ID, mainBranch).view());
+    // the main chain's blocks do not belong to the fork's ancestry
+    await expectError(mtp(forkTip.chainId, mainBranch).rpc(), "BlockNotFound");
+  });
+
+  it("signals the rewound heights when a fork overtakes the main chain", async () => {
+    const forkPoint = tip;
+    const [verified] = await extendMain(1);
+
+    let forkTip = forkPoint;
+    const forkBlocks: Block[] = [];
+    while (forkTip.height < verified.height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    assert.notEqual(forkBlocks[0].chainId, MAIN_CHAIN_ID);
+
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip, {
+      remainingAccounts: reorgAccounts(forkPoint, forkBlocks, [verified]),
+    });
+
+    const reorg = await findEvent(tip.signature, "chainReorg");
+    assert.equal(reorg.rewindFrom, forkPoint.height + 1);
+    assert.isAtMost(reorg.rewindFrom, verified.height);
+
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), tip.hash);
+    assert.equal(tip.chainId, MAIN_CHAIN_ID);
+    const chain = await program.account.blockHash.fetch(chainPda(verified.height));
+    assert.deepEqual(Buffer.from(chain.blockHash), forkBlocks[0].hash);
+    const demoted = await program.account.header.fetch(headerPda(verified.hash));
+    assert.notEqual(demoted.chainId, MAIN_CHAIN_ID);
+  });
+
+  it("keeps the first-seen main chain until a tying fork leads by the margin", async () => {
+    const forkPoint = tip;
+    const [main] = await extendMain(1);
+    const bestBlock = async () => Buffer.from((await program.account.relayState.fetch(relayState)).bestBlock);
+
+    let forkTip = forkPoint;
+    const forkBlocks: Block[] = [];
+    while (forkTip.height < main.height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 599), forkTip);
+      forkBlocks.push(forkTip);
+      // from the tie at the main height up to a lead of CONFIRMATIONS - 1 blocks
+      assert.deepEqual(await bestBlock(), main.hash);
+      assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+    }
+
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 599), forkTip, {
+      remainingAccounts: reorgAccounts(forkPoint, forkBlocks, [main]),
+    });
+    assert.equal(tip.height, main.height + CONFIRMATIONS);
+    assert.deepEqual(await bestBlock(), tip.hash);
+  });
+
+  it("completes a reorg within its transaction, leaving submissions open", async () => {
+    const forkPoint = tip;
+    const [main] = await extendMain(1);
+    let forkTip = forkPoint;
+    const forkBlocks: Block[] = [];
+    while (forkTip.height < main.height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 598), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 598), forkTip, {
+      remainingAccounts: reorgAccounts(forkPoint, forkBlocks, [main]),
+    });
+
+    const reorgTip = tip;
+    const [next] = await extendMain(1);
+    assert.equal(next.height, reorgTip.height + 1);
+  });
+
+  it("emits the old and new hash of every height a reorg rewrites", async () => {
+    // two replaced blocks plus CONFIRMATIONS fill a fresh fork account of MAX_FORK_LENGTH
+    const forkPoint = tip;
+    const replaced = await extendMain(2);
+
+    let forkTip = forkPoint;
+    const forkBlocks: Block[] = [];
+    while (forkTip.height < replaced[1].height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip, {
+      remainingAccounts: reorgAccounts(forkPoint, forkBlocks, replaced),
+    });
+
+    const swaps = await findEvents(tip.signature, "heightReorged");
+    assert.equal(swaps.length, replaced.length);
+    for (const [i, swap] of swaps.entries()) {
+      assert.equal(swap.height, replaced[i].height);
+      assert.deepEqual(Buffer.from(swap.oldHash), replaced[i].hash);
+      assert.deepEqual(Buffer.from(swap.newHash), forkBlocks[i].hash);
+    }
+  });
+
+  it("simulates a submission without storing it", async () => {
+    const simulate = async (header: Buffer) => {
+      const hash = sha256d(header);
+      const { chainCounter } = await program.account.relayState.fetch(relayState);
+      return program.methods
+        .simulateSubmit([...header], [...hash], tip.height + 1, chainCounter + 1)
+        .accountsPartial({
+          relayState,
+          prevHeader: headerPda(tip.hash),
+          prevFork: forkPda(tip.chainId),
+          header: headerPda(hash),
+          oracleConfig: null,
+        })
+        .rpc();
+    };
+
+    const valid = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600);
+    await simulate(valid);
+    assert.isNull(await provider.connection.getAccountInfo(headerPda(sha256d(valid))));
+
+    // difficulty-1 bits without mining: the hash is practically never below the target
+    const weak = buildHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { bits: 0x1d00ffff });
+    await expectError(simulate(weak), "LowDifficulty");
+  });
+
+  it("fast-syncs a 100-header run validating difficulty at checkpoints", async () => {
+    const checkpointInterval = 3;
+    const chunkSize = 4;
+    const submitRun = (headers: Buffer[], height: number, remainingAccounts = headerChainAccounts(headers, tip.height)) =>
+      program.methods
+        .submitHeaderChain(headers.map((h) => [...h]), checkpointInterval)
+        .accountsPartial({
+          relayState,
+          mainFork: forkPda(MAIN_CHAIN_ID),
+          prevHeader: headerPda(tip.hash),
+          chain: chainPda(height),
+          header: headerPda(sha256d(headers[headers.length - 1])),
+          oracleConfig: null,
+          user,
+        })
+        .remainingAccounts(remainingAccounts)
+        .rpc();
+
+    const startHeight = tip.height;
+    const { totalHeaders } = await program.account.relayState.fetch(relayState);
+    for (let chunk = 0; chunk < 100 / chunkSize; chunk++) {
+      const headers: Buffer[] = [];
+      let prevHash = tip.hash;
+      let time = tip.header.readUInt32LE(68);
+      for (let i = 0; i < chunkSize; i++) {
+        const height = tip.height + i + 1;
+        time += 600;
+        // only checkpoints and the stored tip need proof-of-work
+        const checked = height % checkpointInterval === 0 || i === chunkSize - 1;
+        const header = checked ? mineHeader(prevHash, time) : buildHeader(prevHash, time);
+        headers.push(header);
+        prevHash = sha256d(header);
+      }
+
+      if (chunk === 0) {
+        // an unmined checkpoint is rejected
+        const broken = [...headers];
+        broken[chunkSize - 1] = buildHeader(sha256d(headers[chunkSize - 2]), time, { bits: 0x1d00ffff });
+        await expectError(submitRun(broken, tip.height + chunkSize), "LowDifficulty");
+        // every header is stored, not only the tip
+        await expectError(submitRun(headers, tip.height + chunkSize, []), "BlockNotFound");
+      }
+
+      await submitRun(headers, tip.height + chunkSize);
+      tip = { header: headers[chunkSize - 1], hash: prevHash, height: tip.height + chunkSize, chainId: MAIN_CHAIN_ID };
+    }
+
+    const state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.bestHeight, startHeight + 100);
+    assert.deepEqual(Buffer.from(state.bestBlock), tip.hash);
+    assert.equal(state.totalHeaders, totalHeaders + 100);
+    const stored = await program.account.header.fetch(headerPda(tip.hash));
+    assert.equal(stored.chainId, MAIN_CHAIN_ID);
+    // an unchecked header between checkpoints is indexed by height like any other
+    const { blockHash } = await program.account.blockHash.fetch(chainPda(startHeight + 1));
+    const intermediate = await program.account.header.fetch(headerPda(Buffer.from(blockHash)));
+    assert.equal(intermediate.height, startHeight + 1);
+    assert.equal(intermediate.chainId, MAIN_CHAIN_ID);
+  });
+
+  it("stores every header of a batch and reports the first one out of order", async () => {
+    const [first, second, third] = mineRun(3);
+
+    // the second and third headers swapped: the third does not link to the first
+    const simulation = await simulate(await batchSubmission([first, third, second]).instruction());
+    assert.isNotNull(simulation.err);
+    assert.isTrue(simulation.logs.some((log) => log.includes("InvalidHeaderBatch")));
+    assert.equal(Buffer.from(simulation.returnData.data[0], "base64").readUInt32LE(0), 1);
+
+    await batchSubmission([first, second, third]).rpc();
+    for (const [i, header] of [first, second, third].entries()) {
+      const stored = await program.account.header.fetch(headerPda(sha256d(header)));
+      assert.equal(stored.height, tip.height + 1 + i);
+      assert.equal(stored.chainId, MAIN_CHAIN_ID);
+      const chain = await program.account.blockHash.fetch(chainPda(tip.height + 1 + i));
+      assert.deepEqual(Buffer.from(chain.blockHash), sha256d(header));
+    }
+    tip = { header: third, hash: sha256d(third), height: tip.height + 3, chainId: MAIN_CHAIN_ID };
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), tip.hash);
+    assert.equal(state.bestHeight, tip.height);
+  });
+
+  it("stops a batch before it runs out of compute units", async () => {
+    const headers = mineRun(4);
+    const limit = anchor.web3.ComputeBudgetProgram.setComputeUnitLimit({ units: 100_000 });
+
+    const simulation = await simulate(limit, await batchSubmission(headers).instruction());
+    assert.isNull(simulation.err);
+    const stored = Buffer.from(simulation.returnData.data[0], "base64").readUInt32LE(0);
+    assert.isAtLeast(stored, 1);
+    assert.isBelow(stored, headers.length);
+    assert.isTrue(simulation.logs.some((log) => log.includes("Compute budget low")));
+
+    await batchSubmission(headers).preInstructions([limit]).rpc();
+    let state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.bestHeight, tip.height + stored);
+    assert.isNull(await provider.connection.getAccountInfo(headerPda(sha256d(headers[stored]))));
+    tip = { header: headers[stored - 1], hash: sha256d(headers[stored - 1]), height: tip.height + stored, chainId: MAIN_CHAIN_ID };
+
+    // the rest follows in the next batch
+    const rest = headers.slice(stored);
+    await batchSubmission(rest).rpc();
+    tip = { header: rest[rest.length - 1], hash: sha256d(rest[rest.length - 1]), height: tip.height + rest.length, chainId: MAIN_CHAIN_ID };
+    state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), tip.hash);
+  });
+
+  it("reports whether a header is already stored", async () => {
+    const headerExists = (hash: Buffer) =>
+      program.methods.headerExists([...hash]).accountsPartial({ relayState, header: headerPda(hash) }).view();
+
+    const parent = tip;
+    await extendMain(1);
+    const forkBlock = await submit(mineHeader(parent.hash, parent.header.readUInt32LE(68) + 601), parent);
+    assert.notEqual(forkBlock.chainId, MAIN_CHAIN_ID);
+
+    assert.isTrue(await headerExists(tip.hash));
+    assert.isTrue(await headerExists(forkBlock.hash));
+    assert.isFalse(await headerExists(Buffer.alloc(32, 0x42)));
+  });
+
+  it("records the signer that submitted each header", async () => {
+    await extendMain(1);
+    const stored = await program.methods
+      .getBlockHeader([...tip.hash])
+      .accountsPartial({ relayState, header: headerPda(tip.hash) })
+      .view();
+    assert.isTrue(stored.submitter.equals(user));
+    assert.equal(stored.chainId, MAIN_CHAIN_ID);
+    await expectError(
+      program.methods
+        .getBlockHeader(Array(32).fill(0x42))
+        .accountsPartial({ relayState, header: headerPda(Buffer.alloc(32, 0x42)) })
+        .view(),
+      "BlockNotFound"
+    );
+  });
+
+  it("extends the main chain deriving height and linkage from the header", async () => {
+    const startHeight = tip.height;
+    for (let i = 0; i < 3; i++) {
+      const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600);
+      const hash = sha256d(header);
+      await program.methods
+        .extendChain([...header])
+        .accountsPartial({
+          relayState,
+          mainFork: forkPda(MAIN_CHAIN_ID),
+          prevHeader: headerPda(tip.hash),
+          chain: chainPda(tip.height + 1),
+          header: headerPda(hash),
+          oracleConfig: null,
+          user,
+        })
+        .rpc();
+      tip = { header, hash, height: tip.height + 1, chainId: MAIN_CHAIN_ID };
+    }
+
+    const state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.bestHeight, startHeight + 3);
+    assert.deepEqual(Buffer.from(state.bestBlock), tip.hash);
+    const chain = await program.account.blockHash.fetch(chainPda(tip.height));
+    assert.deepEqual(Buffer.from(chain.blockHash), tip.hash);
+  });
+
+  it("counts BIP9 version bit signalling over a window", async () => {
+    // bit 1 is only signalled by the first and last header: the third lacks the 001 prefix
+    const versions = [0x20000002, 0x20000000, 0x00000002, 0x20000004, 0x20000003];
+    const blocks: Block[] = [];
+    for (const version of versions) {
+      tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { version }), tip);
+      blocks.push(tip);
+    }
+    const countBit = (bit: number) =>
+      program.methods
+        .countVersionBits(blocks[0].height, blocks.length, bit)
+        .accountsPartial({ relayState })
+        .remainingAccounts(blocks.map((b) => readonly(headerPda(b.hash))))
+        .view();
+
+    assert.equal(await countBit(1), 2);
+    assert.equal(await countBit(2), 1);
+    assert.equal(await countBit(0), 1);
+    await expectError(
+      program.methods
+        .countVersionBits(blocks[0].height, blocks.length, 29)
+        .accountsPartial({ relayState })
+        .remainingAccounts(blocks.map((b) => readonly(headerPda(b.hash))))
+        .rpc(),
+      "InvalidVersionBit"
+    );
+  });
+
+  it("extends an existing fork in its own PDA and rejects nested forks", async () => {
+    const base = tip;
+    await extendMain(5);
+
+    const first = await submit(mineHeader(base.hash, base.header.readUInt32LE(68) + 601), base);
+    const { chainCounter } = await program.account.relayState.fetch(relayState);
+    assert.equal(first.chainId, chainCounter);
+
+    const second = await submit(mineHeader(first.hash, first.header.readUInt32LE(68) + 601), first);
+    const third = await submit(mineHeader(second.hash, second.header.readUInt32LE(68) + 601), second);
+    assert.equal(second.chainId, first.chainId);
+    assert.equal(third.chainId, first.chainId);
+    assert.equal((await program.account.relayState.fetch(relayState)).chainCounter, chainCounter);
+
+    const fork = await program.account.fork.fetch(forkPda(first.chainId));
+    assert.equal(fork.height, third.height);
+    assert.deepEqual(fork.ancestor, [...base.hash]);
+    assert.deepEqual(
+      fork.descendants.map((d) => Buffer.from(d)),
+      [first.hash, second.hash, third.hash]
+    );
+
+    // branching off the middle of the fork would need a second hop back to the main chain
+    await expectError(
+      submit(mineHeader(first.hash, first.header.readUInt32LE(68) + 602), first),
+      "NestedFork"
+    );
+  });
+
+  it("accepts forks unless initialized in strict mode", async () => {
+    const state = await program.account.relayState.fetch(relayState);
+    assert.isFalse(state.strictNoFork);
+
+    const base = tip;
+    await extendMain(1);
+    const forkBlock = await submit(mineHeader(base.hash, base.header.readUInt32LE(68) + 601), base);
+    assert.notEqual(forkBlock.chainId, MAIN_CHAIN_ID);
+  });
+
+  it("grows a fork account past MAX_FORK_LENGTH without overtaking", async () => {
+    const base = tip;
+    await extendMain(12);
+
+    let forkTip = base;
+    for (let i = 0; i < MAX_FORK_LENGTH; i++) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+    }
+    const allocated = (await provider.connection.getAccountInfo(forkPda(forkTip.chainId))).data.length;
+
+    // each descendant beyond the allocation grows the account by one hash, paid by the submitter
+    for (let i = 0; i < 2; i++) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+    }
+    const fork = await program.account.fork.fetch(forkPda(forkTip.chainId));
+    assert.equal(fork.descendants.length, MAX_FORK_LENGTH + 2);
+    assert.deepEqual(Buffer.from(fork.descendants[MAX_FORK_LENGTH + 1]), forkTip.hash);
+    const info = await provider.connection.getAccountInfo(forkPda(forkTip.chainId));
+    assert.isAbove(info.data.length, allocated);
+    assert.isAtLeast(info.lamports, await provider.connection.getMinimumBalanceForRentExemption(info.data.length));
+    assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+  });
+
+  it("verifies a transaction in the tip block with one confirmation", async () => {
+    const txids = txidsOf("tip", 3);
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, {
+      merkleRoot: merkleRoot(txids),
+    });
+    tip = await submit(header, tip);
+
+    assert.isTrue(await verifyTx(tip, txids, 1, 1).view());
+    await expectError(verifyTx(tip, txids, 1, 2).rpc(), "InsufficientConfirmations");
+  });
+
+  it("reports why a transaction fails to verify", async () => {
+    const txids = txidsOf("reason", 3);
+    const prev = tip;
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const verify = (confirmations: number, opts: { height?: number; header?: Buffer; txid?: Buffer } = {}) => {
+      const height = opts.height ?? tip.height;
+      return program.methods
+        .verifyTxWithReason(
+          height,
+          1,
+          [...(opts.txid ?? txids[1])],
+          [...(opts.header ?? tip.header)],
+          merkleProof(txids, 1),
+          new anchor.BN(confirmations)
+        )
+        .accountsPartial({ relayState, chain: chainPda(height) })
+        .view();
+    };
+
+    assert.deepEqual(await verify(1), { ok: true, reason: 0 });
+    assert.deepEqual(await verify(1, { txid: txids[2] }), { ok: false, reason: 1 });
+    assert.deepEqual(await verify(2), { ok: false, reason: 2 });
+    assert.deepEqual(await verify(1, { height: tip.height + 1 }), { ok: false, reason: 3 });
+    assert.deepEqual(await verify(1, { header: prev.header }), { ok: false, reason: 4 });
+  });
+
+  it("rejects an index beyond the leaves of the proven tree", async () => {
+    const txids = txidsOf("index bound", 3);
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const proof = merkleProof(txids, 0);
+    assert.equal(proof.length, 2 * 32);
+
+    assert.isTrue(await verifyTx(tip, txids, 0, 1, { proof }).view());
+    // index 4 folds like index 0 over two levels, but a tree two levels deep has 4 leaves
+    await expectError(verifyTx(tip, txids, 4, 1, { proof, txid: txids[0] }).rpc(), "IncorrectMerkleProof");
+  });
+
+  it("verifies a transaction from another program through CPI", async () => {
+    const consumer = anchor.workspace.RelayConsumer as Program<RelayConsumer>;
+    const txids = txidsOf("cpi", 4);
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const checkTx = (index: number, confirmations: number) =>
+      consumer.methods
+        .checkTx(
+          tip.height,
+          index,
+          [...txids[index]],
+          [...tip.header],
+          merkleProof(txids, index),
+          new anchor.BN(confirmations)
+        )
+        .accountsPartial({ relayState, chain: chainPda(tip.height), user, relayerProgram: program.programId });
+
+    // the consumer returns what it read from the relayer's return data
+    assert.isTrue(await checkTx(2, 1).view());
+    await expectError(checkTx(2, 2).rpc(), "InsufficientConfirmations");
+  });
+
+  it("verifies a transaction through CPI without a user signer", async () => {
+    const consumer = anchor.workspace.RelayConsumer as Program<RelayConsumer>;
+    const txids = txidsOf("unsigned cpi", 3);
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const checkTx = consumer.methods
+      .checkTxUnsigned(
+        tip.height,
+        0,
+        [...txids[0]],
+        [...tip.header],
+        merkleProof(txids, 0),
+        new anchor.BN(1)
+      )
+      .accountsPartial({ relayState, chain: chainPda(tip.height), relayerProgram: program.programId });
+
+    const ix = await checkTx.instruction();
+    assert.isFalse(ix.keys.some((key) => key.isSigner));
+    assert.isTrue(await checkTx.view());
+  });
+
+  it("calls a consumer back after verifying a transaction", async () => {
+    const consumer = anchor.workspace.RelayConsumer as Program<RelayConsumer>;
+    const txids = txidsOf("callback", 2);
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const record = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("record"), u64le(42)], consumer.programId)[0];
+    // the consumer's `record_callback(tag)`, the relay appends `verified`
+    const discriminator = consumer.rawIdl.instructions.find((ix) => ix.name === "record_callback").discriminator;
+    const callbackData = Buffer.concat([Buffer.from(discriminator), new anchor.BN(42).toArrayLike(Buffer, "le", 8)]);
+
+    await program.methods
+      .verifyAndInvoke(
+        tip.height,
+        1,
+        [...txids[1]],
+        [...tip.header],
+        merkleProof(txids, 1),
+        new anchor.BN(1),
+        consumer.programId,
+        callbackData
+      )
+      .accountsPartial({ relayState, chain: chainPda(tip.height), consumerProgram: consumer.programId })
+      .remainingAccounts([
+        writable(record),
+        { pubkey: user, isWritable: true, isSigner: true },
+        readonly(anchor.web3.SystemProgram.programId),
+      ])
+      .rpc();
+
+    const recorded = await consumer.account.callbackRecord.fetch(record);
+    assert.equal(recorded.tag.toNumber(), 42);
+    assert.isTrue(recorded.verified);
+  });
+
+  it("rejects callbacks verified against another relay instance", async () => {
+    const consumer = anchor.workspace.RelayConsumer as Program<RelayConsumer>;
+    // anyone can start an instance whose genesis commits to a transaction Bitcoin never had
+    const epoch = 8;
+    const txids = txidsOf("forged", 2);
+    const header = mineHeader(Buffer.alloc(32, 0x88), ANCHOR_TIME, { merkleRoot: merkleRoot(txids) });
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], relayConfig(epoch))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const record = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("record"), u64le(43)], consumer.programId)[0];
+    const discriminator = consumer.rawIdl.instructions.find((ix) => ix.name === "record_callback").discriminator;
+    const callbackData = Buffer.concat([Buffer.from(discriminator), new anchor.BN(43).toArrayLike(Buffer, "le", 8)]);
+
+    // the relay verifies and signs with that instance's callback PDA, the consumer refuses it
+    const invoke = program.methods
+      .verifyAndInvoke(
+        ANCHOR_HEIGHT,
+        1,
+        [...txids[1]],
+        [...header],
+        merkleProof(txids, 1),
+        new anchor.BN(1),
+        consumer.programId,
+        callbackData
+      )
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        consumerProgram: consumer.programId,
+      })
+      .remainingAccounts([
+        writable(record),
+        { pubkey: user, isWritable: true, isSigner: true },
+        readonly(anchor.web3.SystemProgram.programId),
+      ]);
+    await expectError(invoke.rpc(), "ConstraintSeeds");
+    assert.isNull(await provider.connection.getAccountInfo(record));
+  });
+
+  it("keeps the consumer on the relay instance it trusts", async () => {
+    const consumer = anchor.workspace.RelayConsumer as Program<RelayConsumer>;
+    // the instance forged in the previous test, its genesis commits to `txids`
+    const epoch = 8;
+    const txids = txidsOf("forged", 2);
+    const header = mineHeader(Buffer.alloc(32, 0x88), ANCHOR_TIME, { merkleRoot: merkleRoot(txids) });
+    const checkTx = consumer.methods
+      .checkTxUnsigned(ANCHOR_HEIGHT, 0, [...txids[0]], [...header], merkleProof(txids, 0), new anchor.BN(1))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        relayerProgram: program.programId,
+      });
+    await expectError(checkTx.rpc(), "ConstraintSeeds");
+  });
+
+  it("verifies a proof streamed across transactions", async () => {
+    const txids = txidsOf("streamed", 8);
+    tip = await submit(mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) }), tip);
+    const partial = pda(Buffer.from("partial"), u64le(0), user.toBuffer());
+    const proof = merkleProof(txids, 5);
+    assert.equal(proof.length, 3 * 32);
+    const step = (chunk: Buffer, caller?: anchor.web3.Keypair) =>
+      program.methods
+        .verifyTxStep(chunk)
+        .accountsPartial({ relayState, partial, caller: caller?.publicKey ?? user })
+        .signers(caller ? [caller] : [])
+        .rpc();
+
+    await program.methods
+      .verifyTxBegin(tip.height, 5, [...txids[5]], [...tip.header], new anchor.BN(1))
+      .accountsPartial({ relayState, chain: chainPda(tip.height), partial, caller: user })
+      .rpc();
+    await step(proof.subarray(0, 32));
+    // only the caller that began the verification can continue it
+    await expectError(step(proof.subarray(32), anchor.web3.Keypair.generate()), "ConstraintSeeds");
+    await step(proof.subarray(32));
+
+    const finalize = program.methods.verifyTxFinalize().accountsPartial({
+      relayState,
+      chain: chainPda(tip.height),
+      partial,
+      caller: user,
+    });
+    assert.isTrue(await finalize.view());
+    await finalize.rpc();
+    assert.isNull(await provider.connection.getAccountInfo(partial));
+  });
+
+  it("audits that the best block is the stored main-chain tip", async () => {
+    const auditTip = async () => {
+      const { bestBlock } = await program.account.relayState.fetch(relayState);
+      return program.methods
+        .auditTip()
+        .accountsPartial({ relayState, header: headerPda(Buffer.from(bestBlock)) })
+        .view();
+    };
+
+    await extendMain(1);
+    assert.isTrue(await auditTip());
+    const stored = await program.account.header.fetch(headerPda(tip.hash));
+    assert.equal(stored.height, tip.height);
+  });
+
+  it("audits that the height index and headers agree", async () => {
+    const verifyConsistency = (height: number, hash: Buffer) =>
+      program.methods
+        .verifyConsistency(height)
+        .accountsPartial({ relayState, chain: chainPda(height), header: headerPda(hash) })
+        .view();
+
+    const [below, top] = await extendMain(2);
+    assert.isTrue(await verifyConsistency(top.height, top.hash));
+    assert.isTrue(await verifyConsistency(below.height, below.hash));
+    assert.isFalse(await verifyConsistency(top.height, below.hash));
+  });
+
+  it("returns the block stored at a main-chain height", async () => {
+    const getBlockAtHeight = (height: number, hash: Buffer) =>
+      program.methods
+        .getBlockAtHeight(height)
+        .accountsPartial({ relayState, chain: chainPda(height), header: headerPda(hash) });
+
+    const [block] = await extendMain(1);
+    const view = await getBlockAtHeight(block.height, block.hash).view();
+    assert.deepEqual(Buffer.from(view.blockHash), block.hash);
+    assert.equal(view.chainId, MAIN_CHAIN_ID);
+    assert.equal(view.timestamp, block.header.readUInt32LE(68));
+    assert.equal(view.bits, EASY_BITS);
+
+    await expectError(getBlockAtHeight(block.height + 1, block.hash).rpc(), "BlockNotFound");
+  });
+
+  it("resolves the height index in both directions", async () => {
+    const [block] = await extendMain(1);
+
+    // height -> BlockHash PDA -> Header PDA
+    const { blockHash } = await program.account.blockHash.fetch(chainPda(block.height));
+    const byHeight = await program.methods
+      .getBlockAtHeight(block.height)
+      .accountsPartial({ relayState, chain: chainPda(block.height), header: headerPda(Buffer.from(blockHash)) })
+      .view();
+    assert.deepEqual(Buffer.from(byHeight.blockHash), block.hash);
+
+    // hash -> Header PDA -> BlockHash PDA at its height
+    const byHash = await program.methods
+      .getBlockHeader([...block.hash])
+      .accountsPartial({ relayState, header: headerPda(block.hash) })
+      .view();
+    const indexed = await program.account.blockHash.fetch(chainPda(byHash.height));
+    assert.deepEqual(Buffer.from(indexed.blockHash), block.hash);
+  });
+
+  it("reports which branch occupies a height", async () => {
+    const chainIdAtHeight = (height: number, hash: Buffer) =>
+      program.methods
+        .chainIdAtHeight(height)
+        .accountsPartial({ relayState, chain: chainPda(height), header: headerPda(hash) })
+        .view();
+
+    const base = tip;
+    const [block] = await extendMain(1);
+    assert.equal(await chainIdAtHeight(block.height, block.hash), MAIN_CHAIN_ID);
+
+    // a fork reaching past the main tip without overtaking it
+    const forkBlock = await submit(mineHeader(base.hash, base.header.readUInt32LE(68) + 601), base);
+    const forkTip = await submit(mineHeader(forkBlock.hash, forkBlock.header.readUInt32LE(68) + 601), forkBlock);
+    assert.equal(forkTip.height, tip.height + 1);
+    assert.equal(await chainIdAtHeight(forkTip.height, forkTip.hash), forkTip.chainId);
+    assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+
+    // the main chain keeps the heights it indexes
+    await expectError(chainIdAtHeight(forkBlock.height, forkBlock.hash), "BlockNotFound");
+    assert.equal(await chainIdAtHeight(forkTip.height + 1, Buffer.alloc(32, 0x42)), 0);
+  });
+
+  it("derives the same PDAs as the client", async () => {
+    const pdas = await program.methods
+      .derivePdas([...tip.hash], tip.height, tip.chainId, new anchor.BN(0))
+      .accounts({})
+      .view();
+    assert.isTrue(pdas.header.equals(headerPda(tip.hash)));
+    assert.isTrue(pdas.chain.equals(chainPda(tip.height)));
+    assert.isTrue(pdas.fork.equals(forkPda(tip.chainId)));
+  });
+
+  it("validates a header segment without storing it", async () => {
+    const segment: Buffer[] = [];
+    let prevHash = Buffer.alloc(32, 0x11);
+    for (let i = 0; i < 10; i++) {
+      const header = mineHeader(prevHash, ANCHOR_TIME + i * 600);
+      segment.push(header);
+      prevHash = sha256d(header);
+    }
+    const startTarget = [...bitsToTarget(EASY_BITS).toArrayLike(Buffer, "le", 32)];
+    const validateSegment = (headers: Buffer[]) =>
+      program.methods
+        .validateSegment(headers.map((h) => [...h]), 1000, startTarget, ANCHOR_TIME)
+        .accounts({})
+        .view();
+
+    assert.isTrue(await validateSegment(segment));
+
+    const broken = [...segment];
+    broken[5] = mineHeader(Buffer.alloc(32, 0x22), ANCHOR_TIME + 5 * 600);
+    assert.isFalse(await validateSegment(broken));
+    await expectError(validateSegment([]), "InvalidHeaderBatch");
+  });
+
+  it("verifies a merkle proof against a supplied root", async () => {
+    const txids = [...Array(5)].map((_, i) => sha256d(Buffer.from(`tx ${i}`)));
+    const root = merkleRoot(txids);
+    const verifyMerkle = (txid: Buffer, index: number, proof: Buffer, expectedRoot = root) =>
+      program.methods
+        .verifyMerkle([...txid], index, proof, [...expectedRoot])
+        .accounts({})
+        .view();
+
+    const proof = merkleProof(txids, 4);
+    assert.isTrue(await verifyMerkle(txids[4], 4, proof));
+
+    const tampered = Buffer.from(proof);
+    tampered[40] ^= 1;
+    assert.isFalse(await verifyMerkle(txids[4], 4, tampered));
+    assert.isFalse(await verifyMerkle(txids[3], 4, proof));
+    assert.isFalse(await verifyMerkle(txids[4], 4, proof, sha256d(Buffer.from("other root"))));
+  });
+
+  it("checks the witness commitment of a segwit coinbase", async () => {
+    const wtxids = [Buffer.alloc(32), ...[1, 2].map((i) => sha256d(Buffer.from(`witness tx ${i}`)))];
+    const witnessRoot = merkleRoot(wtxids);
+    const reservedValue = Buffer.alloc(32);
+    const commitment = sha256d(Buffer.concat([witnessRoot, reservedValue]));
+    // BIP141 layout: marker and flag after the version, the witness before the locktime
+    const coinbase = Buffer.concat([
+      u32le(2),
+      Buffer.from([0x00, 0x01, 1]),
+      Buffer.alloc(32),
+      Buffer.alloc(4, 0xff),
+      Buffer.concat([Buffer.from([bip34Push(800_000).length]), bip34Push(800_000)]),
+      Buffer.alloc(4, 0xff),
+      Buffer.from([2]),
+      Buffer.from("00f2052a01000000", "hex"),
+      Buffer.from([1, 0x51]),
+      Buffer.alloc(8),
+      Buffer.from([38]),
+      Buffer.from("6a24aa21a9ed", "hex"),
+      commitment,
+      Buffer.from([1, 32]),
+      reservedValue,
+      u32le(0),
+    ]);
+    const verifyCommitment = (tx: Buffer, root: Buffer) =>
+      program.methods.verifyWitnessCommitment(tx, [...root]).accounts({});
+
+    assert.isTrue(await verifyCommitment(coinbase, witnessRoot).view());
+    assert.isFalse(await verifyCommitment(coinbase, merkleRoot(wtxids.slice(0, 2))).view());
+    await expectError(verifyCommitment(coinbase.subarray(0, coinbase.length - 1), witnessRoot).rpc(), "InvalidCoinbase");
+  });
+
+  it("stores a BIP158 filter-header chain keyed by height", async () => {
+    const filterHeaderPda = (height: number) => pda(Buffer.from("filter_header"), u64le(0), u32le(height));
+    const submitFilterHeader = (height: number, filterHeader: Buffer, linked = true) =>
+      program.methods
+        .submitFilterHeader(height, [...filterHeader])
+        .accountsPartial({
+          relayState,
+          prevFilterHeader: linked ? filterHeaderPda(height - 1) : null,
+          filterHeader: filterHeaderPda(height),
+          authority: user,
+        })
+        .rpc();
+
+    // filter header = hash256(filter hash || previous filter header), starting from zero
+    const filterHeaders: Buffer[] = [];
+    let prevFilterHeader = Buffer.alloc(32);
+    for (let i = 0; i < 3; i++) {
+      prevFilterHeader = sha256d(Buffer.concat([sha256d(Buffer.from(`filter ${i}`)), prevFilterHeader]));
+      filterHeaders.push(prevFilterHeader);
+    }
+    await submitFilterHeader(genesis.height, filterHeaders[0], false);
+    await submitFilterHeader(genesis.height + 1, filterHeaders[1]);
+    await submitFilterHeader(genesis.height + 2, filterHeaders[2]);
+
+    for (const [i, filterHeader] of filterHeaders.entries()) {
+      const stored = await program.methods
+        .getFilterHeader(genesis.height + i)
+        .accountsPartial({ relayState, filterHeader: filterHeaderPda(genesis.height + i) })
+        .view();
+      assert.deepEqual(Buffer.from(stored), filterHeader);
+    }
+
+    await expectError(submitFilterHeader(genesis.height + 4, filterHeaders[2], false), "FilterHeaderGap");
+    await expectError(submitFilterHeader(tip.height + 1, filterHeaders[2], false), "BlockNotFound");
+  });
+
+  it("enforces the configured header version range", async () => {
+    const setVersionRange = (min: number, max: number) =>
+      program.methods.setVersionRange(min, max).accountsPartial({ relayState, authority: user }).rpc();
+
+    await setVersionRange(0x20000000, 0x3fffffff);
+    await extendMain(1);
+    const legacy = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { version: 1 });
+    await expectError(submit(legacy, tip), "InvalidVersion");
+    await expectError(setVersionRange(2, 1), "InvalidVersion");
+
+    await setVersionRange(0, 0xffffffff);
+    tip = await submit(legacy, tip);
+  });
+
+  it("rejects a zero block hash at the first account it initializes", async () => {
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600);
+    const zero = Buffer.alloc(32);
+    const wrong = sha256d(Buffer.from("not the header"));
+    const { chainCounter } = await program.account.relayState.fetch(relayState);
+    const submission = (blockHash: Buffer) =>
+      program.methods
+        .submitBlockHeader([...header], [...blockHash], tip.height + 1, chainCounter + 1)
+        .accountsPartial({
+          relayState,
+          prevHeader: headerPda(tip.hash),
+          prevFork: forkPda(tip.chainId),
+          fork: forkPda(chainCounter + 1),
+          chain: chainPda(tip.height + 1),
+          header: headerPda(blockHash),
+          oracleConfig: null,
+          user,
+        });
+    await expectError(submission(zero).rpc(), "InvalidBlockHash");
+    assert.isNull(await provider.connection.getAccountInfo(headerPda(zero)));
+
+    // a wrong but non-zero hash is only caught by the handler, once every PDA is created
+    const early = await simulate(await submission(zero).instruction());
+    const late = await simulate(await submission(wrong).instruction());
+    for (const run of [early, late]) assert.isTrue(run.logs.some((log) => log.includes("InvalidBlockHash")));
+    assert.isBelow(early.unitsConsumed, late.unitsConsumed);
+
+    // the same for the genesis hash of a relay instance, simulated under an unused epoch
+    const genesisHeader = mineHeader(Buffer.alloc(32, 0xbb), ANCHOR_TIME);
+    const initialization = (blockHash: Buffer) =>
+      program.methods
+        .initialize([...genesisHeader], ANCHOR_HEIGHT, [...blockHash], relayConfig(11))
+        .accountsPartial({
+          relayState: relayStatePda(11),
+          fork: forkPda(MAIN_CHAIN_ID, 11),
+          chain: chainPda(ANCHOR_HEIGHT, 11),
+          header: headerPda(blockHash, 11),
+          user,
+        })
+        .instruction();
+    const earlyInit = await simulate(await initialization(zero));
+    const lateInit = await simulate(await initialization(wrong));
+    for (const run of [earlyInit, lateInit]) assert.isTrue(run.logs.some((log) => log.includes("InvalidBlockHash")));
+    assert.isBelow(earlyInit.unitsConsumed, lateInit.unitsConsumed);
+  });
+
+  it("rejects a parent other than the header's prevhash", async () => {
+    // the tip names its own parent, not itself, so it cannot be resubmitted on top of itself
+    await expectError(submit(tip.header, tip), "ConstraintSeeds");
+  });
+
+  it("rejects a mid-period block that changes the target", async () => {
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { bits: 0x207ffffe });
+    await expectError(submit(header, tip), "UnexpectedTargetChange");
+  });
+
+  it("connects an orphan once its parent is relayed", async () => {
+    const orphanPda = (parentHash: Buffer) => pda(Buffer.from("orphan"), u64le(0), parentHash);
+    const parent = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600);
+    const parentHash = sha256d(parent);
+    const child = mineHeader(parentHash, parent.readUInt32LE(68) + 600);
+    const childHash = sha256d(child);
+    const connect = () =>
+      program.methods
+        .connectOrphans([...parentHash])
+        .accountsPartial({
+          relayState,
+          mainFork: forkPda(MAIN_CHAIN_ID),
+          prevHeader: headerPda(parentHash),
+          orphan: orphanPda(parentHash),
+          submitter: user,
+          chain: chainPda(tip.height + 2),
+          header: headerPda(childHash),
+          oracleConfig: null,
+          user,
+        })
+        .rpc();
+
+    await program.methods
+      .submitOrphan([...child], [...childHash])
+      .accountsPartial({ relayState, orphan: orphanPda(parentHash), user })
+      .rpc();
+    await expectError(connect(), "AccountNotInitialized");
+
+    const parentBlock = await submit(parent, tip);
+    await connect();
+    tip = { header: child, hash: childHash, height: parentBlock.height + 1, chainId: MAIN_CHAIN_ID };
+
+    const stored = await program.account.header.fetch(headerPda(childHash));
+    assert.equal(stored.height, tip.height);
+    assert.equal(stored.chainId, MAIN_CHAIN_ID);
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), childHash);
+    assert.isNull(await provider.connection.getAccountInfo(orphanPda(parentHash)));
+  });
+
+  it("reports the confirmations of a main-chain height", async () => {
+    const confirmationsAt = (height: number) =>
+      program.methods.confirmationsAt(height).accountsPartial({ relayState, chain: chainPda(height) }).view();
+
+    await extendMain(3);
+    assert.equal(await confirmationsAt(tip.height), 1);
+    assert.equal(await confirmationsAt(tip.height - 2), 3);
+    assert.equal(await confirmationsAt(tip.height + 1), 0);
+    assert.equal(await confirmationsAt(ANCHOR_HEIGHT - 1), 0);
+  });
+
+  it("asserts the stored hash of a checkpoint height", async () => {
+    const assertCheckpoint = (height: number, hash: Buffer) =>
+      program.methods.assertCheckpoint(height, [...hash]).accountsPartial({ relayState, chain: chainPda(height) }).rpc();
+
+    await assertCheckpoint(tip.height, tip.hash);
+    await expectError(assertCheckpoint(tip.height, sha256d(tip.hash)), "CheckpointMismatch");
+    await expectError(assertCheckpoint(tip.height + 1, tip.hash), "BlockNotFound");
+  });
+
+  it("reports the main-chain work accumulated after a block", async () => {
+    const workSince = async (block: Block) =>
+      new anchor.BN(
+        await program.methods
+          .workSinceHeight(block.height)
+          .accountsPartial({ relayState, chain: chainPda(block.height), header: headerPda(block.hash) })
+          .view(),
+        "le"
+      );
+
+    const [block] = await extendMain(3);
+    assert.isTrue((await workSince(block)).eq(blockWork(EASY_BITS).muln(2)));
+    assert.isTrue((await workSince(tip)).isZero());
+    await expectError(workSince({ ...block, height: block.height + 1 }), "BlockNotFound");
+  });
+
+  it("verifies a transaction against a full block's txids", async () => {
+    const txids = BLOCK_170_TXIDS;
+    const root = BLOCK_170_MERKLE_ROOT;
+    assert.deepEqual(merkleRoot(txids), root);
+
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: root });
+    const block = await submit(header, tip);
+    tip = block;
+    await extendMain(CONFIRMATIONS - 1);
+
+    const verifyFullBlock = (target: Buffer, index: number) =>
+      program.methods
+        .verifyTxFullBlock(block.height, [...block.header], txids.map((t) => [...t]), [...target], index)
+        .accountsPartial({ relayState, chain: chainPda(block.height), user });
+
+    assert.isTrue(await verifyFullBlock(txids[1], 1).view());
+    await expectError(verifyFullBlock(txids[1], 0).rpc(), "IncorrectMerkleProof");
+    await expectError(verifyFullBlock(txids[1], 2).rpc(), "IncorrectMerkleProof");
+  });
+
+  it("proves a transaction absent from a small block", async () => {
+    const coinbase = buildCoinbase(Buffer.concat([bip34Push(tip.height + 1), Buffer.from("/absence/")]));
+    const txids = [sha256d(coinbase), ...[1, 2].map((i) => sha256d(Buffer.from(`tx ${i}`)))];
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) });
+    const block = await submit(header, tip);
+    tip = block;
+    await extendMain(CONFIRMATIONS - 1);
+
+    const proveAbsence = (list: Buffer[], absent: Buffer) =>
+      program.methods
+        .proveAbsence(block.height, [...block.header], list.map((t) => [...t]), [...absent], coinbase)
+        .accountsPartial({ relayState, chain: chainPda(block.height), user });
+
+    assert.isTrue(await proveAbsence(txids, sha256d(Buffer.from("tx 3"))).view());
+    assert.isFalse(await proveAbsence(txids, txids[2]).view());
+    // dropping a transaction changes the root
+    await expectError(proveAbsence(txids.slice(0, 2), txids[2]).rpc(), "IncorrectMerkleProof");
+    // inner nodes rebuild the root too, but do not start with the coinbase
+    const innerNodes = merkleLevels(txids)[1];
+    await expectError(proveAbsence(innerNodes, txids[2]).rpc(), "InvalidCoinbase");
+  });
+
+  it("verifies the BIP34 height committed in a block's coinbase", async () => {
+    const mineWithCoinbase = async (committed: number) => {
+      const coinbase = buildCoinbase(Buffer.concat([bip34Push(committed), Buffer.from("/bip34/")]));
+      const txids = [sha256d(coinbase), sha256d(Buffer.from(`bip34 ${committed}`))];
+      const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) });
+      tip = await submit(header, tip);
+      return { block: tip, coinbase, proof: merkleProof(txids, 0) };
+    };
+    const verifyCoinbaseHeight = (block: Block, coinbase: Buffer, proof: Buffer) =>
+      program.methods
+        .verifyCoinbaseHeight(block.height, [...block.header], coinbase, proof)
+        .accountsPartial({ relayState, chain: chainPda(block.height), user });
+
+    const honest = await mineWithCoinbase(tip.height + 1);
+    assert.isTrue(await verifyCoinbaseHeight(honest.block, honest.coinbase, honest.proof).view());
+
+    const lying = await mineWithCoinbase(tip.height + 2);
+    await expectError(verifyCoinbaseHeight(lying.block, lying.coinbase, lying.proof).rpc(), "InvalidCoinbase");
+    await expectError(verifyCoinbaseHeight(lying.block, honest.coinbase, honest.proof).rpc(), "IncorrectMerkleProof");
+  });
+
+  it("verifies two transactions of the same block in one call", async () => {
+    const txids = BLOCK_170_TXIDS;
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: BLOCK_170_MERKLE_ROOT });
+    const block = await submit(header, tip);
+    tip = block;
+    await extendMain(CONFIRMATIONS - 1);
+
+    const verifyBoth = (proofB: Buffer) =>
+      program.methods
+        .verifyBothInBlock(
+          block.height,
+          [...block.header],
+          [...txids[0]],
+          0,
+          merkleProof(txids, 0),
+          [...txids[1]],
+          1,
+          proofB,
+          new anchor.BN(CONFIRMATIONS)
+        )
+        .accountsPartial({ relayState, chain: chainPda(block.height), user });
+
+    assert.isTrue(await verifyBoth(merkleProof(txids, 1)).view());
+    await expectError(verifyBoth(merkleProof(txids, 0)).rpc(), "IncorrectMerkleProof");
+  });
+
+  it("verifies a backlog of proofs across blocks in one call", async () => {
+    const blocks: { block: Block; txids: Buffer[] }[] = [];
+    for (let i = 0; i < 3; i++) {
+      const txids = txidsOf(`backlog ${i}`, 3 + i);
+      const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { merkleRoot: merkleRoot(txids) });
+      tip = await submit(header, tip);
+      blocks.push({ block: tip, txids });
+    }
+    await extendMain(CONFIRMATIONS - 1);
+
+    const entries = blocks.map(({ block, txids }, i) => ({
+      height: block.height,
+      header: [...block.header],
+      txid: [...txids[i]],
+      index: i,
+      // the last entry carries the proof of another index
+      proof: merkleProof(txids, i === 2 ? 0 : i),
+      confirmations: new anchor.BN(CONFIRMATIONS),
+    }));
+    const results = await program.methods
+      .verifyTxMultiBlock(entries)
+      .accountsPartial({ relayState })
+      .remainingAccounts(blocks.map(({ block }) => readonly(chainPda(block.height))))
+      .view();
+    assert.deepEqual(results, [true, true, false]);
+
+    await expectError(
+      program.methods
+        .verifyTxMultiBlock(entries)
+        .accountsPartial({ relayState })
+        .remainingAccounts(blocks.slice(1).map(({ block }) => readonly(chainPda(block.height))))
+        .rpc(),
+      "BlockNotFound"
+    );
+  });
+
+  it("rejects proofs against a header with an all-zero merkle root", async () => {
+    const header = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, {
+      merkleRoot: Buffer.alloc(32),
+    });
+    tip = await submit(header, tip);
+
+    const txids = txidsOf("zero root", 2);
+    await expectError(verifyTx(tip, txids, 0, 1).rpc(), "IncorrectMerkleProof");
+    await expectError(verifyTx(tip, txids.slice(0, 1), 0, 1, { proof: Buffer.alloc(0) }).rpc(), "IncorrectMerkleProof");
+  });
+
+  it("reports a health snapshot matching the relay state", async () => {
+    const getHealth = () => program.methods.getHealth().accountsPartial({ relayState }).view();
+    const setPaused = (paused: boolean) =>
+      program.methods.setPaused(paused).accountsPartial({ relayState, authority: user }).rpc();
+
+    const before = await getHealth();
+    await extendMain(2);
+    const health = await getHealth();
+    const state = await program.account.relayState.fetch(relayState);
+
+    assert.equal(health.bestHeight, tip.height);
+    assert.equal(health.bestTimestamp, tip.header.readUInt32LE(68));
+    assert.equal(health.chainCounter, state.chainCounter);
+    assert.equal(health.totalHeaders, before.totalHeaders + 2);
+    assert.equal(health.activeForkCount, state.activeForkCount);
+    assert.isFalse(health.paused);
+
+    await setPaused(true);
+    assert.isTrue((await getHealth()).paused);
+    await expectError(extendMain(1), "RelayPaused");
+    await setPaused(false);
+
+    const outsider = anchor.web3.Keypair.generate();
+    await expectError(
+      program.methods
+        .setPaused(true)
+        .accountsPartial({ relayState, authority: outsider.publicKey })
+        .signers([outsider])
+        .rpc(),
+      "Unauthorized"
+    );
+  });
+
+  it("counts main-chain timestamps not above the median time past", async () => {
+    const anomalies = async () =>
+      (await program.methods.getHealth().accountsPartial({ relayState }).view()).timestampAnomalyCount;
+
+    await extendMain(11);
+    const before = await anomalies();
+    // the median of the last 11 blocks is the timestamp 5 blocks below the tip
+    const median = tip.header.readUInt32LE(68) - 5 * 600;
+    tip = await submit(mineHeader(tip.hash, median), tip);
+    assert.equal(await anomalies(), before + 1);
+
+    tip = await submit(mineHeader(tip.hash, median + 6000), tip);
+    assert.equal(await anomalies(), before + 1);
+  });
+
+  it("bonds new forks, refunding absorbed ones and forfeiting abandoned ones", async () => {
+    const bond = 1_000_000;
+    const setForkBond = (lamports: number) =>
+      program.methods.setForkBond(new anchor.BN(lamports)).accountsPartial({ relayState, authority: user }).rpc();
+    const lamportsOf = async (key: anchor.web3.PublicKey) =>
+      (await provider.connection.getAccountInfo(key)).lamports;
+    const forfeit = (chainId: number) =>
+      program.methods
+        .forfeitForkBond(chainId)
+        .accountsPartial({ relayState, fork: forkPda(chainId), authority: user })
+        .rpc();
+
+    await setForkBond(bond);
+
+    // deposit on creation
+    const forkPoint = tip;
+    const [replaced] = await extendMain(1);
+    let forkTip = await submit(mineHeader(forkPoint.hash, forkPoint.header.readUInt32LE(68) + 601), forkPoint);
+    const forkBlocks = [forkTip];
+    const forkId = forkTip.chainId;
+    let fork = await program.account.fork.fetch(forkPda(forkId));
+    assert.equal(fork.bond.toNumber(), bond);
+    assert.isTrue(fork.bondOwner.equals(user));
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(
+      (await provider.connection.getAccountInfo(forkPda(forkId))).data.length
+    );
+    assert.equal(await lamportsOf(forkPda(forkId)), rent + bond);
+
+    // refund on absorption
+    while (forkTip.height < replaced.height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip, {
+      remainingAccounts: [...reorgAccounts(forkPoint, forkBlocks, [replaced]), writable(user)],
+    });
+    assert.equal(tip.chainId, MAIN_CHAIN_ID);
+    fork = await program.account.fork.fetch(forkPda(forkId));
+    assert.equal(fork.bond.toNumber(), 0);
+    assert.equal(await lamportsOf(forkPda(forkId)), rent);
+
+    // forfeit once abandoned
+    const deadPoint = tip;
+    await extendMain(1);
+    const dead = await submit(mineHeader(deadPoint.hash, deadPoint.header.readUInt32LE(68) + 601), deadPoint);
+    await expectError(forfeit(dead.chainId), "ForkBondLocked");
+
+    await syncMain(FORK_BOND_TIMEOUT);
+    const deposited = await lamportsOf(forkPda(dead.chainId));
+    await forfeit(dead.chainId);
+    assert.equal(await lamportsOf(forkPda(dead.chainId)), deposited - bond);
+    await expectError(forfeit(dead.chainId), "NoForkBond");
+
+    await setForkBond(0);
+  });
+
+  it("snapshots the whole relay state in one call", async () => {
+    await extendMain(1);
+    const snapshot = await program.methods.getStateSnapshot().accountsPartial({ relayState }).view();
+    const state = await program.account.relayState.fetch(relayState);
+
+    assert.deepEqual(Object.keys(snapshot).sort(), Object.keys(state).sort());
+    for (const [field, value] of Object.entries(state)) {
+      assert.equal(JSON.stringify(snapshot[field]), JSON.stringify(value), field);
+    }
+    assert.deepEqual(Buffer.from(snapshot.bestBlock), tip.hash);
+  });
+
+  it("emits an admin action event for every authority instruction", async () => {
+    const actions: [number, anchor.web3.TransactionInstruction][] = [
+      [0, await program.methods.setPaused(false).accountsPartial({ relayState, authority: user }).instruction()],
+      [1, await program.methods.setForkBond(new anchor.BN(0)).accountsPartial({ relayState, authority: user }).instruction()],
+      [2, await program.methods.setVersionRange(0, 0xffffffff).accountsPartial({ relayState, authority: user }).instruction()],
+    ];
+
+    for (const [action, ix] of actions) {
+      const signature = await provider.sendAndConfirm(new anchor.web3.Transaction().add(ix), [], {
+        commitment: "confirmed",
+      });
+      const event = await findEvent(signature, "adminAction");
+      assert.equal(event.action, action);
+      assert.isTrue(event.authority.equals(user));
+      assert.isAbove(event.slot.toNumber(), 0);
+    }
+  });
+
+  it("lets only the authority set the oracle and only the oracle publish targets", async () => {
+    const oracleConfig = oracleConfigPda();
+    const oracle = anchor.web3.Keypair.generate();
+    const target = Buffer.alloc(32);
+    target.writeUInt32BE(0x7fffff00, 28);
+    const publish = (signer: anchor.web3.Keypair) =>
+      program.methods
+        .publishTarget([...target])
+        .accountsPartial({ relayState, oracleConfig, oracle: signer.publicKey })
+        .signers([signer])
+        .rpc();
+
+    const signature = await program.methods
+      .setOracle(oracle.publicKey)
+      .accountsPartial({ relayState, oracleConfig, authority: user })
+      .rpc({ commitment: "confirmed" });
+    assert.equal((await findEvent(signature, "adminAction")).action, 3);
+
+    await publish(oracle);
+    const config = await program.account.oracleConfig.fetch(oracleConfig);
+    assert.isTrue(config.oracle.equals(oracle.publicKey));
+    assert.deepEqual(Buffer.from(config.target), target);
+    await expectError(publish(anchor.web3.Keypair.generate()), "Unauthorized");
+
+    // this relay was initialized without oracle mode and keeps validating retargets
+    const state = await program.account.relayState.fetch(relayState);
+    assert.isFalse(state.oracleMode);
+  });
+
+  it("keeps another epoch's authority away from this relay's oracle", async () => {
+    const epoch = 7;
+    const authority = anchor.web3.Keypair.generate();
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        anchor.web3.SystemProgram.transfer({
+          fromPubkey: user,
+          toPubkey: authority.publicKey,
+          lamports: anchor.web3.LAMPORTS_PER_SOL,
+        }),
+      ),
+    );
+    const header = mineHeader(Buffer.alloc(32, 0x77), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], relayConfig(epoch))
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user: authority.publicKey,
+      })
+      .signers([authority])
+      .rpc();
+
+    const before = await program.account.oracleConfig.fetch(oracleConfigPda());
+    const setOracle = (oracleConfig: anchor.web3.PublicKey) =>
+      program.methods
+        .setOracle(authority.publicKey)
+        .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+    await expectError(setOracle(oracleConfigPda()), "ConstraintSeeds");
+    await setOracle(oracleConfigPda(epoch));
+
+    const after = await program.account.oracleConfig.fetch(oracleConfigPda());
+    assert.isTrue(after.oracle.equals(before.oracle));
+    const own = await program.account.oracleConfig.fetch(oracleConfigPda(epoch));
+    assert.isTrue(own.oracle.equals(authority.publicKey));
+  });
+
+  it("replays the epoch anchors from the new chain on a reorg across a period start", async () => {
+    const periodStart = 2016;
+    assert.isBelow(tip.height, periodStart - 3);
+    await syncMain(periodStart - 3 - tip.height);
+
+    // the main chain ends the period, the fork also starts the next one
+    const forkPoint = tip;
+    const replaced = await extendMain(2);
+    const periodEnd = replaced[1];
+    assert.equal(periodEnd.height, periodStart - 1);
+    let state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.epochEndTime, periodEnd.header.readUInt32LE(68));
+
+    let forkTip = forkPoint;
+    const forkBlocks: Block[] = [];
+    while (forkTip.height < periodEnd.height + CONFIRMATIONS - 1) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    // fork blocks at period boundaries leave the main chain's anchors alone
+    state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.epochEndTime, periodEnd.header.readUInt32LE(68));
+    assert.isFalse(state.epochAnchored);
+
+    tip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip, {
+      remainingAccounts: reorgAccounts(forkPoint, forkBlocks, replaced),
+    });
+    assert.equal(tip.chainId, MAIN_CHAIN_ID);
+
+    const newStart = forkBlocks.find((b) => b.height === periodStart);
+    state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.epochStartTime, newStart.header.readUInt32LE(68));
+    assert.equal(state.epochEndTime, 0);
+    assert.isTrue(state.epochAnchored);
+  });
+
+  it("counts the forks competing at a height", async () => {
+    const forksAtHeight = async (height: number) => {
+      const { activeForkIds } = await program.account.relayState.fetch(relayState);
+      return program.methods
+        .forksAtHeight(height)
+        .accountsPartial({ relayState })
+        .remainingAccounts(activeForkIds.map((id) => readonly(forkPda(id))))
+        .view();
+    };
+
+    const base = tip;
+    await extendMain(2);
+    const first = await submit(mineHeader(base.hash, base.header.readUInt32LE(68) + 601), base);
+    const second = await submit(mineHeader(base.hash, base.header.readUInt32LE(68) + 602), base);
+    assert.notEqual(first.chainId, second.chainId);
+
+    assert.equal(await forksAtHeight(base.height + 1), 2);
+    assert.equal(await forksAtHeight(base.height + 2), 0);
+
+    const state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.activeForkIds.length, state.activeForkCount);
+  });
+
+  it("rejects a fork rewriting history below the finalized height", async () => {
+    let state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.finalizedHeight, tip.height - FINALITY_DEPTH);
+    assert.isAbove(state.finalizedHeight, genesis.height);
+
+    const header = mineHeader(genesis.hash, genesis.header.readUInt32LE(68) + 607);
+    await expectError(submit(header, genesis), "BelowFinality");
+
+    await extendMain(1);
+    state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.finalizedHeight, tip.height - FINALITY_DEPTH);
+  });
+
+  it("purges a fork left below the finalized height", async () => {
+    const purge = (chainId: number, headers: Block[]) =>
+      program.methods
+        .purgeFork(chainId)
+        .accountsPartial({ relayState, fork: forkPda(chainId), authority: user })
+        .remainingAccounts(headers.map((b) => writable(headerPda(b.hash))))
+        .rpc();
+
+    const forkPoint = tip;
+    await extendMain(2);
+    const forkBlocks: Block[] = [];
+    let forkTip = forkPoint;
+    for (let i = 0; i < 2; i++) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 601), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    const chainId = forkTip.chainId;
+    await expectError(purge(chainId, forkBlocks), "ForkNotFinal");
+
+    await syncMain(FINALITY_DEPTH);
+    await expectError(purge(chainId, forkBlocks.slice(1)), "BlockNotFound");
+    const rent = async (keys: anchor.web3.PublicKey[]) =>
+      (await Promise.all(keys.map((key) => provider.connection.getAccountInfo(key)))).reduce(
+        (sum, info) => sum + info.lamports,
+        0
+      );
+    const reclaimed = await rent([forkPda(chainId), ...forkBlocks.map((b) => headerPda(b.hash))]);
+    const before = await provider.connection.getBalance(user);
+    await purge(chainId, forkBlocks);
+
+    assert.isNull(await provider.connection.getAccountInfo(forkPda(chainId)));
+    for (const block of forkBlocks) {
+      assert.isNull(await provider.connection.getAccountInfo(headerPda(block.hash)));
+    }
+    // the authority pays the fee and gets the rent back
+    assert.isAbove(await provider.connection.getBalance(user), before + reclaimed - 10_000);
+    const state = await program.account.relayState.fetch(relayState);
+    assert.notInclude(state.activeForkIds, chainId);
+    assert.equal(state.activeForkIds.length, state.activeForkCount);
+  });
+
+  it("accepts a header dated before the genesis block", async () => {
+    const state = await program.account.relayState.fetch(relayState);
+    assert.equal(state.genesisHeight, genesis.height);
+
+    // consensus only bounds timestamps by the median time past, which the relay does not enforce
+    tip = await submit(mineHeader(tip.hash, ANCHOR_TIME - 1), tip);
+  });
+
+  it("lets the authority force a fork onto the main chain", async () => {
+    const forkPoint = tip;
+    const replaced = await extendMain(1);
+    const forkBlocks: Block[] = [];
+    let forkTip = forkPoint;
+    for (let i = 0; i < 2; i++) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 602), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    // two blocks are far from the reorg margin, the fork stays a fork
+    assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+    const { chainCounter } = await program.account.relayState.fetch(relayState);
+    const forceSetMain = (height: number) =>
+      program.methods
+        .forceSetMain(forkTip.chainId, [...forkTip.hash], height)
+        .accountsPartial({
+          relayState,
+          fork: forkPda(forkTip.chainId),
+          newFork: forkPda(chainCounter + 1),
+          header: headerPda(forkTip.hash),
+          chain: chainPda(height),
+          authority: user,
+        })
+        .remainingAccounts(reorgAccounts(forkPoint, forkBlocks.slice(0, -1), replaced));
+
+    await expectError(forceSetMain(forkPoint.height + 1).rpc(), "BlockNotFound");
+    const signature = await forceSetMain(forkTip.height).rpc({ commitment: "confirmed" });
+
+    const reorg = await findEvent(signature, "chainReorg");
+    assert.deepEqual(Buffer.from(reorg.from), replaced[0].hash);
+    assert.deepEqual(Buffer.from(reorg.to), forkTip.hash);
+    assert.equal(reorg.rewindFrom, forkPoint.height + 1);
+    assert.equal((await findEvent(signature, "adminAction")).action, 4);
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), forkTip.hash);
+    assert.equal(state.bestHeight, forkTip.height);
+    assert.include(state.activeForkIds, chainCounter + 1);
+    assert.notInclude(state.activeForkIds, forkTip.chainId);
+    for (const block of forkBlocks) {
+      assert.deepEqual(Buffer.from((await program.account.blockHash.fetch(chainPda(block.height))).blockHash), block.hash);
+    }
+
+    // new blocks build on the forced chain
+    tip = { ...forkTip, chainId: MAIN_CHAIN_ID };
+    await extendMain(1);
+    assert.equal(tip.chainId, MAIN_CHAIN_ID);
+    assert.equal((await program.account.relayState.fetch(relayState)).bestHeight, forkTip.height + 1);
+  });
+
+  it("demotes more than MAX_FORK_LENGTH main-chain blocks in one reorg", async () => {
+    const forkPoint = tip;
+    const replaced = await extendMain(MAX_FORK_LENGTH + 1);
+    const forkBlocks: Block[] = [];
+    let forkTip = forkPoint;
+    for (let i = 0; i < MAX_FORK_LENGTH + 2; i++) {
+      forkTip = await submit(mineHeader(forkTip.hash, forkTip.header.readUInt32LE(68) + 603), forkTip);
+      forkBlocks.push(forkTip);
+    }
+    assert.notEqual(forkTip.chainId, MAIN_CHAIN_ID);
+    const { chainCounter } = await program.account.relayState.fetch(relayState);
+    const remainingAccounts = reorgAccounts(forkPoint, forkBlocks.slice(0, -1), replaced);
+
+    // too many accounts for a legacy transaction, they are passed through a lookup table
+    const { connection } = provider;
+    const [create, lookupTable] = anchor.web3.AddressLookupTableProgram.createLookupTable({
+      authority: user,
+      payer: user,
+      recentSlot: await connection.getSlot("finalized"),
+    });
+    const extend = (addresses: anchor.web3.PublicKey[]) =>
+      anchor.web3.AddressLookupTableProgram.extendLookupTable({ lookupTable, authority: user, payer: user, addresses });
+    const addresses = remainingAccounts.map((account) => account.pubkey);
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(create, extend(addresses.slice(0, 20))));
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(extend(addresses.slice(20))));
+    // the extension is usable from the next slot on
+    const extendedAt = await connection.getSlot("confirmed");
+    while ((await connection.getSlot("confirmed")) <= extendedAt) {
+      await new Promise((resolve) => setTimeout(resolve, 100));
+    }
+
+    const instruction = await program.methods
+      .forceSetMain(forkTip.chainId, [...forkTip.hash], forkTip.height)
+      .accountsPartial({
+        relayState,
+        fork: forkPda(forkTip.chainId),
+        newFork: forkPda(chainCounter + 1),
+        header: headerPda(forkTip.hash),
+        chain: chainPda(forkTip.height),
+        authority: user,
+      })
+      .remainingAccounts(remainingAccounts)
+      .instruction();
+    const message = new anchor.web3.TransactionMessage({
+      payerKey: user,
+      recentBlockhash: (await connection.getLatestBlockhash()).blockhash,
+      instructions: [anchor.web3.ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }), instruction],
+    }).compileToV0Message([(await connection.getAddressLookupTable(lookupTable)).value]);
+    await provider.sendAndConfirm(new anchor.web3.VersionedTransaction(message), [], { commitment: "confirmed" });
+
+    // the old main chain outgrew a fresh fork account and lives on in full
+    const demoted = await program.account.fork.fetch(forkPda(chainCounter + 1));
+    assert.equal(demoted.descendants.length, MAX_FORK_LENGTH + 1);
+    assert.deepEqual(
+      demoted.descendants.map((hash) => Buffer.from(hash)),
+      replaced.map((block) => block.hash)
+    );
+    const info = await connection.getAccountInfo(forkPda(chainCounter + 1));
+    assert.isAtLeast(info.lamports, await connection.getMinimumBalanceForRentExemption(info.data.length));
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.bestBlock), forkTip.hash);
+    tip = { ...forkTip, chainId: MAIN_CHAIN_ID };
+  });
+
+  it("reorgs onto an equal-height fork only when it has more work", async () => {
+    // oracle mode skips the period's target check, so a fork can be mined at a higher difficulty
+    const epoch = 9;
+    const header = mineHeader(Buffer.alloc(32, 0x99), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], { ...relayConfig(epoch), oracleMode: true })
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const oracleConfig = oracleConfigPda(epoch);
+    await program.methods
+      .setOracle(user)
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, authority: user })
+      .rpc();
+    await program.methods
+      .publishTarget([...bitsToTarget(EASY_BITS).toArrayLike(Buffer, "le", 32)])
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, oracle: user })
+      .rpc();
+    const root: Block = { header, hash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    const mine = async (count: number, spacing: number, bits = EASY_BITS) => {
+      const blocks: Block[] = [];
+      let prev = root;
+      for (let i = 0; i < count; i++) {
+        prev = await submit(mineHeader(prev.hash, prev.header.readUInt32LE(68) + spacing, { bits }), prev, {
+          epoch,
+          oracleConfig,
+        });
+        blocks.push(prev);
+      }
+      return blocks;
+    };
+
+    const replaced = await mine(2, 600);
+    // the same height and work as the main chain
+    const [, tied] = await mine(2, 601);
+    assert.notEqual(tied.chainId, MAIN_CHAIN_ID);
+
+    // a quarter of the target is four times the work per block: two blocks lead by CONFIRMATIONS
+    const HARD_BITS = 0x201fffff;
+    assert.isTrue(blockWork(HARD_BITS).eq(blockWork(EASY_BITS).muln(4)));
+    const [first] = await mine(1, 602, HARD_BITS);
+    assert.notEqual(first.chainId, MAIN_CHAIN_ID);
+    const { chainCounter } = await program.account.relayState.fetch(relayStatePda(epoch));
+    const forkTip = await submit(mineHeader(first.hash, first.header.readUInt32LE(68) + 602, { bits: HARD_BITS }), first, {
+      epoch,
+      oracleConfig,
+      remainingAccounts: reorgAccounts(root, [first], replaced, epoch),
+    });
+
+    assert.equal(forkTip.chainId, MAIN_CHAIN_ID);
+    assert.equal(forkTip.height, replaced[1].height);
+    const state = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.deepEqual(Buffer.from(state.bestBlock), forkTip.hash);
+    const indexed = await program.account.blockHash.fetch(chainPda(forkTip.height, epoch));
+    assert.deepEqual(Buffer.from(indexed.blockHash), forkTip.hash);
+    // the old tip is demoted with the rest of the old main chain
+    const demoted = await program.account.fork.fetch(forkPda(chainCounter + 1, epoch));
+    assert.deepEqual(
+      demoted.descendants.map((h) => Buffer.from(h)),
+      replaced.map((block) => block.hash)
+    );
+    assert.equal((await program.account.header.fetch(headerPda(replaced[1].hash, epoch))).chainId, chainCounter + 1);
+  });
+
+  it("reorgs onto a fork branching off a block an earlier reorg demoted", async () => {
+    const epoch = 10;
+    const header = mineHeader(Buffer.alloc(32, 0xaa), ANCHOR_TIME);
+    const hash = sha256d(header);
+    await program.methods
+      .initialize([...header], ANCHOR_HEIGHT, [...hash], { ...relayConfig(epoch), oracleMode: true })
+      .accountsPartial({
+        relayState: relayStatePda(epoch),
+        fork: forkPda(MAIN_CHAIN_ID, epoch),
+        chain: chainPda(ANCHOR_HEIGHT, epoch),
+        header: headerPda(hash, epoch),
+        user,
+      })
+      .rpc();
+    const oracleConfig = oracleConfigPda(epoch);
+    await program.methods
+      .setOracle(user)
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, authority: user })
+      .rpc();
+    await program.methods
+      .publishTarget([...bitsToTarget(EASY_BITS).toArrayLike(Buffer, "le", 32)])
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, oracle: user })
+      .rpc();
+    const chainCounter = async () => (await program.account.relayState.fetch(relayStatePda(epoch))).chainCounter;
+    const root: Block = { header, hash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    const HARD_BITS = 0x201fffff;
+    const mine = async (prev: Block, count: number, spacing: number, bits = EASY_BITS) => {
+      const blocks: Block[] = [];
+      for (let i = 0; i < count; i++) {
+        prev = await submit(mineHeader(prev.hash, prev.header.readUInt32LE(68) + spacing, { bits }), prev, {
+          epoch,
+          oracleConfig,
+        });
+        blocks.push(prev);
+      }
+      return blocks;
+    };
+
+    const replaced = await mine(root, 2, 600);
+    // a fork off the first main-chain block, which the next reorg demotes
+    const [nested] = await mine(replaced[0], 1, 601);
+    assert.notEqual(nested.chainId, MAIN_CHAIN_ID);
+    const [first] = await mine(root, 1, 602, HARD_BITS);
+    const demotedId = (await chainCounter()) + 1;
+    const second = await submit(mineHeader(first.hash, first.header.readUInt32LE(68) + 602, { bits: HARD_BITS }), first, {
+      epoch,
+      oracleConfig,
+      remainingAccounts: reorgAccounts(root, [first], replaced, epoch),
+    });
+    assert.equal(second.chainId, MAIN_CHAIN_ID);
+    assert.equal((await program.account.header.fetch(headerPda(replaced[0].hash, epoch))).chainId, demotedId);
+    const promoted = [first, second].map((block) => ({ ...block, chainId: MAIN_CHAIN_ID }));
+
+    // the nested fork keeps growing and overtakes through the demoted block it branches off
+    const grown = await mine(nested, 2, 601, HARD_BITS);
+    assert.equal(grown[1].chainId, nested.chainId);
+    const tipCounter = await chainCounter();
+    const forkTip = await submit(
+      mineHeader(grown[1].hash, grown[1].header.readUInt32LE(68) + 601, { bits: HARD_BITS }),
+      grown[1],
+      {
+        epoch,
+        oracleConfig,
+        remainingAccounts: reorgAccounts(root, [replaced[0], nested, ...grown], promoted, epoch, {
+          ancestor: replaced[0],
+          chainId: demotedId,
+        }),
+      }
+    );
+
+    assert.equal(forkTip.chainId, MAIN_CHAIN_ID);
+    const state = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.deepEqual(Buffer.from(state.bestBlock), forkTip.hash);
+    for (const block of [replaced[0], nested, ...grown]) {
+      assert.equal((await program.account.header.fetch(headerPda(block.hash, epoch))).chainId, MAIN_CHAIN_ID);
+      const indexed = await program.account.blockHash.fetch(chainPda(block.height, epoch));
+      assert.deepEqual(Buffer.from(indexed.blockHash), block.hash);
+    }
+    // the earlier demoted fork keeps the block above, now branching off the main chain again
+    const parent = await program.account.fork.fetch(forkPda(demotedId, epoch));
+    assert.deepEqual(Buffer.from(parent.ancestor), replaced[0].hash);
+    assert.deepEqual(
+      parent.descendants.map((h) => Buffer.from(h)),
+      [replaced[1].hash]
+    );
+    const demoted = await program.account.fork.fetch(forkPda(tipCounter + 1, epoch));
+    assert.deepEqual(
+      demoted.descendants.map((h) => Buffer.from(h)),
+      promoted.map((block) => block.hash)
+    );
+  });
+
+  it("consults the oracle on every path extending the chain", async () => {
+    const epoch = 12;
+    const genesisHeader = mineHeader(Buffer.alloc(32, 0xcc), ANCHOR_TIME);
+    const genesisHash = sha256d(genesisHeader);
+    const config = { ...relayConfig(epoch), oracleMode: true };
+    const initAccounts = {
+      relayState: relayStatePda(epoch),
+      fork: forkPda(MAIN_CHAIN_ID, epoch),
+      chain: chainPda(ANCHOR_HEIGHT, epoch),
+      header: headerPda(genesisHash, epoch),
+      user,
+    };
+    // no oracle can have published a target before the relay exists
+    const followUp = mineHeader(genesisHash, ANCHOR_TIME + 600);
+    const withHeaders = await program.methods
+      .initializeWithHeaders([...genesisHeader], ANCHOR_HEIGHT, [...genesisHash], config, [[...followUp]])
+      .accountsPartial(initAccounts)
+      .remainingAccounts([writable(headerPda(sha256d(followUp), epoch)), writable(chainPda(ANCHOR_HEIGHT + 1, epoch))])
+      .instruction();
+    assert.isTrue((await simulate(withHeaders)).logs.some((log) => log.includes("OracleNotConfigured")));
+    await program.methods
+      .initialize([...genesisHeader], ANCHOR_HEIGHT, [...genesisHash], config)
+      .accountsPartial(initAccounts)
+      .rpc();
+
+    let prev: Block = { header: genesisHeader, hash: genesisHash, height: ANCHOR_HEIGHT, chainId: MAIN_CHAIN_ID };
+    const oracleConfig = oracleConfigPda(epoch);
+    // a mid-period target change, which only the oracle's target makes acceptable
+    const oddBits = EASY_BITS - 1;
+    const extend = (header: Buffer, oracle: anchor.web3.PublicKey | null) =>
+      program.methods
+        .extendChain([...header])
+        .accountsPartial({
+          relayState: relayStatePda(epoch),
+          mainFork: forkPda(MAIN_CHAIN_ID, epoch),
+          prevHeader: headerPda(prev.hash, epoch),
+          chain: chainPda(prev.height + 1, epoch),
+          header: headerPda(sha256d(header), epoch),
+          oracleConfig: oracle,
+          user,
+        })
+        .rpc();
+    const first = mineHeader(prev.hash, ANCHOR_TIME + 600, { bits: oddBits });
+    await expectError(extend(first, null), "OracleNotConfigured");
+
+    await program.methods
+      .setOracle(user)
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, authority: user })
+      .rpc();
+    await program.methods
+      .publishTarget([...bitsToTarget(EASY_BITS).toArrayLike(Buffer, "le", 32)])
+      .accountsPartial({ relayState: relayStatePda(epoch), oracleConfig, oracle: user })
+      .rpc();
+    await extend(first, oracleConfig);
+    prev = { header: first, hash: sha256d(first), height: prev.height + 1, chainId: MAIN_CHAIN_ID };
+
+    // a header chain checks every header against the oracle, whatever the checkpoint interval
+    const headerChain = (headers: Buffer[]) =>
+      program.methods
+        .submitHeaderChain(headers.map((h) => [...h]), 1000)
+        .accountsPartial({
+          relayState: relayStatePda(epoch),
+          mainFork: forkPda(MAIN_CHAIN_ID, epoch),
+          prevHeader: headerPda(prev.hash, epoch),
+          chain: chainPda(prev.height + headers.length, epoch),
+          header: headerPda(sha256d(headers[headers.length - 1]), epoch),
+          oracleConfig,
+          user,
+        })
+        .remainingAccounts(
+          headers
+            .slice(0, -1)
+            .flatMap((h, i) => [writable(headerPda(sha256d(h), epoch)), writable(chainPda(prev.height + 1 + i, epoch))])
+        )
+        .rpc();
+    const unmined = buildHeader(prev.hash, ANCHOR_TIME + 1200, { bits: 0x1d00ffff });
+    const last = mineHeader(sha256d(unmined), ANCHOR_TIME + 1800, { bits: oddBits });
+    await expectError(headerChain([unmined, last]), "LowDifficulty");
+    const run = [mineHeader(prev.hash, ANCHOR_TIME + 1200, { bits: oddBits })];
+    run.push(mineHeader(sha256d(run[0]), ANCHOR_TIME + 1800, { bits: oddBits }));
+    await headerChain(run);
+
+    const state = await program.account.relayState.fetch(relayStatePda(epoch));
+    assert.equal(state.bestHeight, ANCHOR_HEIGHT + 3);
+    assert.deepEqual(Buffer.from(state.bestBlock), sha256d(run[1]));
+  });
+
+  it("trusts the targets of blocks up to the assumevalid height", async () => {
+    const setAssumevalid = (hash: Buffer, height: number) =>
+      program.methods.setAssumevalid([...hash], height).accountsPartial({ relayState, authority: user }).rpc();
+    // a mid-period target change, only acceptable where the authority vouches for it
+    const oddBits = EASY_BITS - 1;
+    const trusted = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { bits: oddBits });
+    await expectError(submit(trusted, tip), "UnexpectedTargetChange");
+
+    await setAssumevalid(sha256d(trusted), tip.height + 1);
+    const state = await program.account.relayState.fetch(relayState);
+    assert.deepEqual(Buffer.from(state.assumevalid), sha256d(trusted));
+    // any other block at the assumevalid height is rejected rather than trusted
+    const impostor = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 601, { bits: oddBits });
+    await expectError(submit(impostor, tip), "NotAssumevalidBlock");
+    tip = await submit(trusted, tip);
+    assert.equal(tip.chainId, MAIN_CHAIN_ID);
+
+    const above = mineHeader(tip.hash, tip.header.readUInt32LE(68) + 600, { bits: oddBits });
+    await expectError(submit(above, tip), "UnexpectedTargetChange");
+    await extendMain(1);
+    await setAssumevalid(Buffer.alloc(32), 0);
+  });
+
+  it("counts the blocks until a block is final", async () => {
+    const blocksUntilFinal = (height: number) =>
+      program.methods.blocksUntilFinal(height).accountsPartial({ relayState });
+
+    await extendMain(1);
+    assert.equal(await blocksUntilFinal(tip.height).view(), FINALITY_DEPTH);
+    assert.equal(await blocksUntilFinal(tip.height - 3).view(), FINALITY_DEPTH - 3);
+    const { finalizedHeight } = await program.account.relayState.fetch(relayState);
+    assert.equal(await blocksUntilFinal(finalizedHeight + 1).view(), 1);
+    assert.equal(await blocksUntilFinal(finalizedHeight).view(), 0);
+    assert.equal(await blocksUntilFinal(genesis.height).view(), 0);
+    await expectError(blocksUntilFinal(tip.height + 1).rpc(), "BlockNotFound");
   });
 });